@@ -0,0 +1,411 @@
+//! `cargo ispc`: build, inspect and verify the ISPC libraries declared in the
+//! `[package.metadata.ispc]` table of a crate's `Cargo.toml`, see
+//! `ispc_compile::build_from_manifest` for the format of the table.
+//!
+//! The libraries are compiled with the same options as the crate's build
+//! script would use, so `build` can produce the packaged libraries linked by
+//! `ispc_rt::PackagedModule` for several target triples from one machine,
+//! instead of building the crate on each of them and copying the outputs.
+
+use std::env;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+use ispc_compile::Config;
+use ispc_rt::{PackageManifest, PackagedModule};
+use serde_json::Value;
+
+const USAGE: &str = "\
+Build, inspect and verify the ISPC libraries declared in [package.metadata.ispc]
+
+Usage: cargo ispc <COMMAND> [OPTIONS]
+
+Commands:
+    build       Compile the libraries and write the packages for each target
+    targets     List the target ISAs supported by the installed ISPC compiler
+    commands    Show the ISPC command lines used to compile the libraries
+    verify      Check the packaged libraries and bindings match their manifests
+    clean       Remove the intermediate files and packages written to target/ispc
+
+Options:
+    --manifest-path <PATH>  The Cargo.toml of the crate, defaults to the current crate
+    --target <TRIPLE>       A target triple to build or verify, may be repeated.
+                            Defaults to the host for build and commands, and to
+                            each packaged target for verify
+    --out-dir <DIR>         Write or verify the packages in DIR instead of the
+                            out-dir set in the metadata (default target/ispc/package)
+    --ispc <PATH>           The ISPC compiler to use
+    --debug                 Compile without optimizations and with debug info
+    -h, --help              Print this help
+";
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// The options given on the command line after the command.
+#[derive(Default)]
+struct Options {
+    manifest_path: Option<PathBuf>,
+    targets: Vec<String>,
+    out_dir: Option<PathBuf>,
+    ispc: Option<PathBuf>,
+    debug: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options> {
+        let mut opts = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((f, v)) if f.starts_with("--") => (f, Some(v.to_owned())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next().cloned())
+                    .ok_or_else(|| format!("{flag} requires a value"))
+            };
+            match flag {
+                "--manifest-path" => opts.manifest_path = Some(PathBuf::from(value()?)),
+                "--target" => opts.targets.push(value()?),
+                "--out-dir" => opts.out_dir = Some(PathBuf::from(value()?)),
+                "--ispc" => opts.ispc = Some(PathBuf::from(value()?)),
+                "--debug" => opts.debug = true,
+                _ => return Err(format!("unknown option `{arg}`, see cargo ispc --help").into()),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// The crate whose libraries are being built, and where to write the outputs.
+struct Crate {
+    manifest: PathBuf,
+    /// The directory the intermediate files and default packages are written
+    /// to, `ispc` in the crate's target directory.
+    ispc_dir: PathBuf,
+    host: String,
+    opts: Options,
+}
+
+impl Crate {
+    fn new(opts: Options) -> Result<Crate> {
+        let manifest = match opts.manifest_path {
+            Some(ref p) => p.clone(),
+            None => PathBuf::from(run(cargo().args([
+                "locate-project",
+                "--message-format",
+                "plain",
+            ]))?),
+        };
+        let metadata: Value = serde_json::from_str(&run(cargo()
+            .args(["metadata", "--no-deps", "--format-version", "1"])
+            .arg("--manifest-path")
+            .arg(&manifest))?)?;
+        let target_dir = metadata["target_directory"]
+            .as_str()
+            .ok_or("cargo metadata didn't list the target directory")?;
+        let host = run(Command::new(env::var_os("RUSTC").unwrap_or("rustc".into())).arg("-vV"))?
+            .lines()
+            .find_map(|l| l.strip_prefix("host: "))
+            .map(str::to_owned)
+            .ok_or("rustc didn't report the host target")?;
+        Ok(Crate {
+            manifest,
+            ispc_dir: Path::new(target_dir).join("ispc"),
+            host,
+            opts,
+        })
+    }
+    /// The target triples given on the command line, or the host.
+    fn targets(&self) -> Vec<String> {
+        if self.opts.targets.is_empty() {
+            vec![self.host.clone()]
+        } else {
+            self.opts.targets.clone()
+        }
+    }
+    /// A config with the options given on the command line for `target`.
+    fn config(&self, target: &str) -> Config {
+        let mut cfg = Config::new();
+        cfg.target(target)
+            .cargo_metadata(false)
+            .debug(self.opts.debug)
+            .opt_level(if self.opts.debug { 0 } else { 3 })
+            .build_dir(self.ispc_dir.join("build").join(target));
+        if let Some(ref ispc) = self.opts.ispc {
+            cfg.ispc_path(ispc);
+        }
+        cfg
+    }
+    /// Set the variables `Config` reads from the build script environment.
+    /// Packages are written to target/ispc/package unless the metadata or the
+    /// command line gives another directory.
+    fn set_build_env(&self) {
+        env::set_var("OUT_DIR", self.ispc_dir.join("package"));
+        if env::var_os("HOST").is_none() {
+            env::set_var("HOST", &self.host);
+        }
+    }
+    /// The libraries declared in the crate's metadata, configured for `target`.
+    fn libraries(&self, target: &str) -> Result<Vec<(String, Config)>> {
+        let mut libs = self.config(target).manifest_libraries(&self.manifest)?;
+        if let Some(ref dir) = self.opts.out_dir {
+            for (_, cfg) in &mut libs {
+                cfg.out_dir(dir);
+            }
+        }
+        Ok(libs)
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // Cargo passes the name of the subcommand when run as `cargo ispc`
+    if args.first().map(String::as_str) == Some("ispc") {
+        args.remove(0);
+    }
+    let command = args.first().cloned().unwrap_or_default();
+    if args.is_empty() || args.iter().any(|a| a == "-h" || a == "--help") || command == "help" {
+        print!("{USAGE}");
+        return;
+    }
+    let result = Options::parse(&args[1..]).and_then(|opts| match command.as_str() {
+        "targets" => targets(&opts),
+        _ => {
+            let krate = Crate::new(opts)?;
+            krate.set_build_env();
+            match command.as_str() {
+                "build" => build(&krate),
+                "commands" => commands(&krate),
+                "verify" => verify(&krate),
+                "clean" => clean(&krate),
+                _ => Err(format!("unknown command `{command}`, see cargo ispc --help").into()),
+            }
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        process::exit(1);
+    }
+}
+
+fn build(krate: &Crate) -> Result<()> {
+    for target in krate.targets() {
+        for (lib, cfg) in krate.libraries(&target)? {
+            eprintln!("   Compiling {lib} for {target}");
+            let output = cfg.try_compile(&lib)?;
+            for d in &output.diagnostics {
+                eprintln!("warning: {d}");
+            }
+            eprintln!("    Packaged {}", output.library.display());
+        }
+    }
+    Ok(())
+}
+
+fn targets(opts: &Options) -> Result<()> {
+    let mut cfg = Config::new();
+    cfg.cargo_metadata(false);
+    if let Some(ref ispc) = opts.ispc {
+        cfg.ispc_path(ispc);
+    }
+    let version = cfg.try_ispc_version()?.clone();
+    let supported = cfg.supported_targets()?;
+    println!("ISPC {version} ({})", cfg.ispc_exe().display());
+    for isa in &supported.isas {
+        println!("{isa}");
+    }
+    Ok(())
+}
+
+fn commands(krate: &Crate) -> Result<()> {
+    for target in krate.targets() {
+        for (lib, cfg) in krate.libraries(&target)? {
+            println!("# {lib} for {target}");
+            for cmd in cfg.ispc_commands(&lib)? {
+                let args: Vec<_> = std::iter::once(cmd.get_program())
+                    .chain(cmd.get_args())
+                    .map(shell_quote)
+                    .collect();
+                println!("{}", args.join(" "));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn verify(krate: &Crate) -> Result<()> {
+    let mut failed = 0;
+    for (lib, cfg) in krate.libraries(&krate.host)? {
+        let dir = cfg.get_out_dir()?;
+        let targets = if krate.opts.targets.is_empty() {
+            match PackageManifest::read(&dir, &lib)? {
+                Some(manifest) => manifest.targets.into_keys().collect(),
+                None => {
+                    return Err(format!(
+                    "{lib} has no package manifest in {}, pass the targets to verify with --target",
+                    dir.display()
+                )
+                    .into())
+                }
+            }
+        } else {
+            krate.opts.targets.clone()
+        };
+        for target in targets {
+            match PackagedModule::new(&lib)
+                .lib_path(&dir)
+                .verify_target(&target)
+            {
+                Ok(()) => println!("ok: {lib} for {target}"),
+                Err(e) => {
+                    println!("FAILED: {lib} for {target}: {e}");
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(format!("{failed} packaged libraries failed verification").into());
+    }
+    Ok(())
+}
+
+fn clean(krate: &Crate) -> Result<()> {
+    if krate.ispc_dir.exists() {
+        fs::remove_dir_all(&krate.ispc_dir)?;
+        eprintln!("     Removed {}", krate.ispc_dir.display());
+    }
+    Ok(())
+}
+
+fn cargo() -> Command {
+    Command::new(env::var_os("CARGO").unwrap_or("cargo".into()))
+}
+
+/// Run the command, returning its trimmed output.
+fn run(cmd: &mut Command) -> Result<String> {
+    let output = cmd
+        .output()
+        .map_err(|e| format!("failed to run {}: {e}", cmd.get_program().to_string_lossy()))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr)
+            .trim()
+            .to_owned()
+            .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Quote an argument for display so the command can be pasted into a shell.
+fn shell_quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_=./,:+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.into_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+[package]
+name = "multi"
+version = "0.1.0"
+
+[package.metadata.ispc]
+target-isas = { x86_64 = ["sse2-i32x4", "avx2-i32x8"], aarch64 = ["neon-i32x4"] }
+
+[package.metadata.ispc.libraries.multi]
+files = ["src/multi.ispc"]
+"#;
+
+    const SOURCE: &str = "
+export uniform float sum(const uniform float vals[], uniform int count) {
+    float s = 0;
+    foreach (i = 0 ... count) {
+        s += vals[i];
+    }
+    return reduce_add(s);
+}
+";
+
+    #[test]
+    fn options() {
+        let args: Vec<_> = ["--target", "a", "--target=b", "--out-dir", "pkg", "--debug"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        let opts = Options::parse(&args).unwrap();
+        assert_eq!(opts.targets, ["a", "b"]);
+        assert_eq!(opts.out_dir, Some(PathBuf::from("pkg")));
+        assert!(opts.debug);
+        assert!(Options::parse(&[String::from("--target")]).is_err());
+        assert!(Options::parse(&[String::from("--release")]).is_err());
+    }
+
+    /// Build for two triples into the default package directory, which needs
+    /// ISPC to be installed.
+    #[test]
+    fn builds_several_targets() {
+        if Config::new().try_ispc_version().is_err() {
+            eprintln!("ISPC wasn't found, skipping");
+            return;
+        }
+        let dir = env::temp_dir().join(format!("cargo-ispc-build-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("Cargo.toml"), MANIFEST).unwrap();
+        fs::write(dir.join("src/multi.ispc"), SOURCE).unwrap();
+        let krate = Crate {
+            manifest: dir.join("Cargo.toml"),
+            ispc_dir: dir.join("target/ispc"),
+            host: String::from("x86_64-unknown-linux-gnu"),
+            opts: Options {
+                targets: vec![
+                    String::from("x86_64-unknown-linux-gnu"),
+                    String::from("aarch64-unknown-linux-gnu"),
+                ],
+                ..Options::default()
+            },
+        };
+        krate.set_build_env();
+        build(&krate).unwrap();
+
+        // The targets file lists the ISAs of both packaged triples, not just
+        // the last one built
+        let package = dir.join("target/ispc/package");
+        let targets = fs::read_to_string(package.join("multi.targets.rs")).unwrap();
+        assert!(
+            targets.contains(
+                "#[cfg(target_arch = \"x86_64\")]\n\
+                 pub const ISPC_TARGETS: &[&str] = &[\"sse2-i32x4\", \"avx2-i32x8\"];"
+            ),
+            "{targets}"
+        );
+        assert!(
+            targets.contains(
+                "#[cfg(target_arch = \"aarch64\")]\n\
+                 pub const ISPC_TARGETS: &[&str] = &[\"neon-i32x4\"];"
+            ),
+            "{targets}"
+        );
+        let manifest = PackageManifest::read(&package, "multi").unwrap().unwrap();
+        let triples: Vec<_> = manifest.targets.keys().collect();
+        assert_eq!(
+            triples,
+            ["aarch64-unknown-linux-gnu", "x86_64-unknown-linux-gnu"]
+        );
+        verify(&krate).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+//! The ABI hash embedded in both the library and the bindings, so a library
+//! linked with bindings generated from different ISPC code is detected when
+//! the program runs instead of corrupting memory.
+//!
+//! The hash is computed from the headers ISPC writes for each source file,
+//! which declare every exported function and the types they use. Comments are
+//! removed first, as the headers include their own path in a comment.
+
+use std::fs;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::IspcError;
+
+/// Compute the ABI hash of the headers generated for a library.
+pub(crate) fn hash_headers(headers: &[PathBuf]) -> Result<u64, IspcError> {
+    let mut hasher = Sha256::new();
+    for h in headers {
+        let text = fs::read_to_string(h).map_err(|e| IspcError::Io {
+            path: h.clone(),
+            source: e,
+        })?;
+        for line in strip_comments(&text).lines() {
+            let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !line.is_empty() {
+                hasher.update(line.as_bytes());
+                hasher.update(b"\n");
+            }
+        }
+    }
+    let digest = hasher.finalize();
+    Ok(u64::from_le_bytes(digest[..8].try_into().unwrap()))
+}
+
+/// The name of the function exported from the library returning its hash.
+pub(crate) fn symbol(lib: &str) -> String {
+    format!("ispc_rs_abi_hash_{lib}")
+}
+
+/// The ISPC source for the function returning the hash, which is compiled
+/// into the library.
+pub(crate) fn ispc_source(lib: &str, hash: u64) -> String {
+    // Built from two 32-bit halves to avoid relying on 64-bit literal suffixes
+    format!(
+        "// Generated by ispc-rs, returns the hash checked by ispc_rs_verify() in the bindings\n\
+         export uniform uint64 {}() {{\n    \
+         return ((uniform uint64)0x{:08x}u << 32) | (uniform uint64)0x{:08x}u;\n}}\n",
+        symbol(lib),
+        hash >> 32,
+        hash & 0xffff_ffff
+    )
+}
+
+/// The Rust code added to the bindings module to check the hash of the
+/// library linked matches the bindings. The check is made by `ispc_rt`, which
+/// the bindings reach through the `ispc_rs_verify_abi!` macro defined by
+/// `ispc_module!` and `ispc!`.
+pub(crate) fn bindings(lib: &str, hash: u64) -> String {
+    BINDINGS_TEMPLATE
+        .replace("{lib}", lib)
+        .replace("{symbol}", &symbol(lib))
+        .replace("{hash}", &format!("0x{hash:016x}"))
+}
+
+const BINDINGS_TEMPLATE: &str = r#"/// The hash of the ISPC headers the bindings were generated from
+pub const ISPC_RS_ABI_HASH: u64 = {hash};
+unsafe extern "C" {
+    /// Returns the hash of the ISPC headers the library was compiled from
+    pub fn {symbol}() -> u64;
+}
+/// Check the library linked was compiled from the same ISPC code as these
+/// bindings, panicking if it wasn't, see `ispc_rt::verify_abi`.
+pub fn ispc_rs_verify() {
+    ispc_rs_verify_abi!("{lib}", ISPC_RS_ABI_HASH, unsafe { {symbol}() });
+}
+"#;
+
+/// Remove the C and C++ style comments from a header.
+fn strip_comments(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            if c == '\\' {
+                out.extend(chars.next());
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                out.push(c);
+            }
+            ('/', Some('/')) => while chars.next_if(|c| *c != '\n').is_some() {},
+            ('/', Some('*')) => {
+                chars.next();
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    // Keep the line structure of multi-line comments
+                    if c == '\n' {
+                        out.push('\n');
+                    }
+                    prev = c;
+                }
+                out.push(' ');
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_comments() {
+        assert_eq!(
+            strip_comments("int a; // the count\nint b;"),
+            "int a; \nint b;"
+        );
+        // Multi-line comments keep their lines, so line numbers don't change
+        assert_eq!(
+            strip_comments("int a; /* the\ncount */ int b;\n"),
+            "int a; \n  int b;\n"
+        );
+        assert_eq!(strip_comments("/**/int a;/* * / */"), " int a; ");
+        // Comment markers in strings are kept
+        assert_eq!(
+            strip_comments(r#"const char *s = "// \" /* */"; // done"#),
+            r#"const char *s = "// \" /* */"; "#
+        );
+        // An unterminated comment runs to the end of the header
+        assert_eq!(strip_comments("int a; /* never closed"), "int a;  ");
+        assert_eq!(strip_comments("a / b"), "a / b");
+    }
+
+    #[test]
+    fn hash_ignores_comments_and_whitespace() {
+        let dir = crate::test_util::test_dir("abi-hash");
+        let write = |name: &str, text: &str| {
+            let path = dir.join(name);
+            fs::write(&path, text).unwrap();
+            path
+        };
+        let a = write(
+            "a.h",
+            "// Header generated from /tmp/a/simple.ispc\nextern void add(float *a,  int n);\n",
+        );
+        let b = write(
+            "b.h",
+            "/* Header generated from\n   /tmp/b/simple.ispc */\n\nextern void add(float *a, int n);",
+        );
+        let c = write("c.h", "extern void add(float *a, int64_t n);\n");
+        let hash = |h: &[&PathBuf]| {
+            hash_headers(&h.iter().map(|p| p.to_path_buf()).collect::<Vec<_>>()).unwrap()
+        };
+        assert_eq!(hash(&[&a]), hash(&[&b]));
+        assert_ne!(hash(&[&a]), hash(&[&c]));
+        assert_ne!(hash(&[&a, &c]), hash(&[&c, &a]));
+    }
+
+    #[test]
+    fn generates_check() {
+        let hash = 0x0123_4567_89ab_cdef;
+        let source = ispc_source("simple", hash);
+        assert!(source.contains("export uniform uint64 ispc_rs_abi_hash_simple()"));
+        assert!(source.contains("0x01234567u << 32) | (uniform uint64)0x89abcdefu"));
+
+        let rust = bindings("simple", hash);
+        assert!(rust.contains("pub const ISPC_RS_ABI_HASH: u64 = 0x0123456789abcdef;"));
+        assert!(rust.contains("pub fn ispc_rs_abi_hash_simple() -> u64;"));
+        assert!(rust.contains(
+            "ispc_rs_verify_abi!(\"simple\", ISPC_RS_ABI_HASH, unsafe { ispc_rs_abi_hash_simple() });"
+        ));
+    }
+}
@@ -0,0 +1,86 @@
+//! Locating the ISPC compiler executable to use for a build.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variables which can be used to select the ISPC compiler,
+/// in order of precedence. The target specific variables are checked with
+/// the target triple as-is and with dashes replaced by underscores, following
+/// the convention used by the `cc` crate for `CC_<target>`.
+pub(crate) fn env_vars(target: Option<&str>) -> Vec<String> {
+    let mut vars = Vec::new();
+    if let Some(t) = target {
+        vars.push(format!("ISPC_{t}"));
+        vars.push(format!("ISPC_{}", t.replace('-', "_")));
+    }
+    vars.push(String::from("ISPC"));
+    vars
+}
+
+/// Find the ISPC compiler, checking the environment variables from `env_vars`,
+/// then the `PATH` and finally some common install locations. If it can't be
+/// found we fall back to running `ispc` and let the caller report the error.
+pub(crate) fn find(target: Option<&str>) -> PathBuf {
+    if let Some(p) = env_vars(target)
+        .iter()
+        .find_map(|v| env::var_os(v).filter(|p| !p.is_empty()))
+    {
+        return PathBuf::from(p);
+    }
+    let exe = format!("ispc{}", env::consts::EXE_SUFFIX);
+    if let Some(paths) = env::var_os("PATH") {
+        if let Some(p) = env::split_paths(&paths)
+            .map(|dir| dir.join(&exe))
+            .find(|p| p.is_file())
+        {
+            return p;
+        }
+    }
+    install_locations()
+        .into_iter()
+        .map(|dir| dir.join(&exe))
+        .find(|p| p.is_file())
+        .unwrap_or_else(|| PathBuf::from(exe))
+}
+
+/// Common directories the ISPC release packages or package managers install
+/// the compiler into. Versioned release directories such as
+/// `/opt/ispc-v1.25.3-linux` are also searched.
+fn install_locations() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if cfg!(windows) {
+        if let Some(pf) = env::var_os("ProgramFiles") {
+            let base = PathBuf::from(pf).join("ISPC");
+            dirs.push(base.join("bin"));
+            dirs.extend(versioned_dirs(&base));
+        }
+    } else {
+        if let Some(home) = env::var_os("HOME") {
+            dirs.push(PathBuf::from(home).join(".local/bin"));
+        }
+        dirs.push(PathBuf::from("/usr/local/bin"));
+        dirs.push(PathBuf::from("/opt/homebrew/bin"));
+        dirs.push(PathBuf::from("/opt/ispc/bin"));
+        dirs.extend(versioned_dirs(Path::new("/opt")));
+        dirs.extend(versioned_dirs(Path::new("/usr/local")));
+    }
+    dirs
+}
+
+/// Find `<base>/ispc*/bin` directories, in reverse lexical order so later
+/// releases are usually preferred.
+fn versioned_dirs(base: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(base)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_name().to_string_lossy().starts_with("ispc"))
+                .map(|e| e.path().join("bin"))
+                .collect()
+        })
+        .unwrap_or_default();
+    dirs.sort();
+    dirs.reverse();
+    dirs
+}
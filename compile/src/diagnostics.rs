@@ -0,0 +1,240 @@
+//! Parsing of the warnings and errors printed by the ISPC compiler into
+//! structured diagnostics.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use regex::Regex;
+
+/// The severity of a diagnostic reported by ISPC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Severity {
+    /// Output from ISPC which isn't part of a warning or error.
+    Note,
+    /// A performance warning, these can be disabled with `Config::wno_perf`.
+    PerformanceWarning,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Severity::Note => write!(f, "Note"),
+            Severity::PerformanceWarning => write!(f, "Performance Warning"),
+            Severity::Warning => write!(f, "Warning"),
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+/// A warning or error reported by ISPC while compiling a file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    /// The file the diagnostic refers to, if ISPC reported a location.
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    pub severity: Severity,
+    pub message: String,
+    /// Any additional lines printed along with the diagnostic, such as the
+    /// source code excerpt ISPC prints for errors.
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Returns true if this diagnostic is a warning of either kind.
+    pub fn is_warning(&self) -> bool {
+        matches!(
+            self.severity,
+            Severity::Warning | Severity::PerformanceWarning
+        )
+    }
+    /// Returns true if this diagnostic is an error.
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    /// Formats the diagnostic in the same style ISPC prints it, without the notes.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            write!(f, "{}:", file.display())?;
+            if let Some(line) = self.line {
+                write!(f, "{line}:")?;
+                if let Some(col) = self.column {
+                    write!(f, "{col}:")?;
+                }
+            }
+            write!(f, " ")?;
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+/// Parse the output printed by ISPC to stderr into a list of diagnostics.
+/// Lines following a warning or error, such as the source excerpt, are
+/// attached to it as notes.
+pub fn parse(stderr: &str) -> Vec<Diagnostic> {
+    // ISPC may color its output if it thinks it's writing to a terminal
+    let ansi = Regex::new(r"\x1b\[[0-9;]*m").unwrap();
+    let re = Regex::new(
+        r"^(?:(?P<file>.+?):(?P<line>\d+):(?P<col>\d+):\s*)?(?P<sev>Fatal Error|Error|Performance Warning|Warning):\s*(?P<msg>.*)$",
+    )
+    .unwrap();
+
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    for l in stderr.lines() {
+        let l = ansi.replace_all(l, "");
+        if let Some(c) = re.captures(&l) {
+            let severity = match &c["sev"] {
+                "Performance Warning" => Severity::PerformanceWarning,
+                "Warning" => Severity::Warning,
+                _ => Severity::Error,
+            };
+            diagnostics.push(Diagnostic {
+                file: c.name("file").map(|f| PathBuf::from(f.as_str())),
+                line: c.name("line").and_then(|l| l.as_str().parse().ok()),
+                column: c.name("col").and_then(|l| l.as_str().parse().ok()),
+                severity,
+                message: c["msg"].trim().to_owned(),
+                notes: Vec::new(),
+            });
+        } else if l.trim().is_empty() {
+            continue;
+        } else if let Some(last) = diagnostics.last_mut() {
+            last.notes.push(l.trim_end().to_owned());
+        } else {
+            diagnostics.push(Diagnostic {
+                file: None,
+                line: None,
+                column: None,
+                severity: Severity::Note,
+                message: l.trim().to_owned(),
+                notes: Vec::new(),
+            });
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn located(
+        file: &str,
+        line: u32,
+        column: u32,
+        severity: Severity,
+        message: &str,
+    ) -> Diagnostic {
+        Diagnostic {
+            file: Some(PathBuf::from(file)),
+            line: Some(line),
+            column: Some(column),
+            severity,
+            message: message.to_owned(),
+            notes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn errors_and_warnings() {
+        let stderr = "\
+src/simple.ispc:4:10: Error: Undeclared symbol \"x\".
+src/simple.ispc:6:5: Warning: Unused variable \"y\".
+src/simple.ispc:8:9: Performance Warning: Gather required to load value.
+Fatal Error: Can't open file \"missing.ispc\".
+";
+        let fatal = Diagnostic {
+            file: None,
+            line: None,
+            column: None,
+            severity: Severity::Error,
+            message: String::from("Can't open file \"missing.ispc\"."),
+            notes: Vec::new(),
+        };
+        let diagnostics = parse(stderr);
+        assert_eq!(
+            diagnostics,
+            vec![
+                located(
+                    "src/simple.ispc",
+                    4,
+                    10,
+                    Severity::Error,
+                    "Undeclared symbol \"x\"."
+                ),
+                located(
+                    "src/simple.ispc",
+                    6,
+                    5,
+                    Severity::Warning,
+                    "Unused variable \"y\"."
+                ),
+                located(
+                    "src/simple.ispc",
+                    8,
+                    9,
+                    Severity::PerformanceWarning,
+                    "Gather required to load value."
+                ),
+                fatal,
+            ]
+        );
+        assert!(diagnostics[0].is_error() && !diagnostics[0].is_warning());
+        assert!(diagnostics[1].is_warning() && diagnostics[2].is_warning());
+        assert_eq!(
+            diagnostics[2].to_string(),
+            "src/simple.ispc:8:9: Performance Warning: Gather required to load value."
+        );
+        assert_eq!(
+            diagnostics[3].to_string(),
+            "Error: Can't open file \"missing.ispc\"."
+        );
+    }
+
+    #[test]
+    fn continuation_lines_are_notes() {
+        // ISPC prints the source line and a marker under the location, and
+        // colors the output when it thinks it's writing to a terminal
+        let stderr = "\
+\x1b[1msrc/simple.ispc:2:1: \x1b[31mError\x1b[0m: Undeclared symbol \"vals\".
+
+    vals[i] *= 2;
+    ^^^^
+src/simple.ispc:3:1: Warning: No --target specified.
+";
+        let diagnostics = parse(stderr);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].message, "Undeclared symbol \"vals\".");
+        assert_eq!(diagnostics[0].notes, vec!["    vals[i] *= 2;", "    ^^^^"]);
+        assert!(diagnostics[1].notes.is_empty());
+    }
+
+    #[test]
+    fn unmatched_leading_lines() {
+        let stderr = "\
+Target ISA doesn't support double precision.
+Output from the compiler
+src/simple.ispc:1:1: Warning: Something.
+";
+        let diagnostics = parse(stderr);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Note);
+        assert_eq!(
+            diagnostics[0].message,
+            "Target ISA doesn't support double precision."
+        );
+        assert_eq!(diagnostics[0].notes, vec!["Output from the compiler"]);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "Note: Target ISA doesn't support double precision."
+        );
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+        assert!(parse("").is_empty());
+    }
+}
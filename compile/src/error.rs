@@ -0,0 +1,243 @@
+//! Errors which can occur while compiling ISPC code into a library and
+//! generating bindings for it.
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::diagnostics::Diagnostic;
+use crate::opt::ParseOptionError;
+
+/// An error from one of the stages of building an ISPC library, returned by
+/// `Config::try_compile`.
+#[derive(Debug)]
+pub enum IspcError {
+    /// The ISPC compiler could not be run.
+    CompilerNotFound {
+        compiler: PathBuf,
+        source: io::Error,
+    },
+    /// The version reported by `ispc --version` could not be parsed.
+    VersionParse { output: String },
+    /// ISPC failed to compile a source file, `stderr` holds the compiler output
+    /// and `diagnostics` the warnings and errors parsed from it.
+    Compile {
+        file: PathBuf,
+        stderr: String,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// The objects could not be assembled into a library.
+    Archive { lib: String, message: String },
+    /// The objects could not be linked into a shared library, `stderr` holds
+    /// the linker output if it was run.
+    Link {
+        lib: String,
+        message: String,
+        stderr: String,
+    },
+    /// The dependency file written by ISPC for a source file could not be read.
+    DepFile { file: PathBuf, source: io::Error },
+    /// Generating the Rust bindings for the library failed.
+    Bindgen { lib: String, message: String },
+    /// The target ISA, CPU, architecture or OS options are invalid, either
+    /// because the compiler doesn't support them or they can't be combined.
+    InvalidConfig { problems: Vec<String> },
+    /// One of the `ISPC_RS_*` environment variables overriding an option
+    /// couldn't be parsed.
+    InvalidEnvVar {
+        var: String,
+        source: ParseOptionError,
+    },
+    /// The `[package.metadata.ispc]` table of the crate's manifest couldn't
+    /// be read, see `build_from_manifest`.
+    InvalidMetadata { manifest: PathBuf, message: String },
+    /// A safe wrapper couldn't be generated from the description given for
+    /// an exported function.
+    SafeWrapper { function: String, message: String },
+    /// An ISPC header couldn't be generated from a Rust source file.
+    IsphHeader {
+        source_file: PathBuf,
+        message: String,
+    },
+    /// The ISPC code written inline in a Rust source file couldn't be found.
+    InlineSource {
+        source_file: PathBuf,
+        message: String,
+    },
+    /// Two source files would write outputs with the same name to the build
+    /// directory, e.g. because the same file was added twice.
+    OutputCollision {
+        name: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    /// ISPC didn't write an output for each target ISA when compiling a file
+    /// for multiple targets.
+    MissingIsaOutputs {
+        file: PathBuf,
+        expected: Vec<String>,
+        found: Vec<PathBuf>,
+    },
+    /// An ISPC source file's path has no file name, or one which isn't valid
+    /// UTF-8, so the outputs can't be named after it.
+    InvalidSourceName { file: PathBuf },
+    /// An environment variable Cargo sets for build scripts isn't set, e.g.
+    /// because the function was called outside of a build script.
+    MissingEnvVar { var: String },
+    /// Some other I/O operation on a generated file failed.
+    Io { path: PathBuf, source: io::Error },
+}
+
+impl fmt::Display for IspcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IspcError::CompilerNotFound { compiler, source } => write!(
+                f,
+                "Failed to run ISPC compiler '{}', is it in your PATH? ({source})",
+                compiler.display()
+            ),
+            IspcError::VersionParse { output } => {
+                write!(f, "Failed to parse ISPC version from '{}'", output.trim())
+            }
+            IspcError::Compile {
+                file,
+                stderr,
+                diagnostics,
+            } => {
+                write!(f, "Failed to compile ISPC source file {}", file.display())?;
+                let errors: Vec<_> = diagnostics.iter().filter(|d| d.is_error()).collect();
+                if errors.is_empty() {
+                    if !stderr.trim().is_empty() {
+                        write!(f, ":\n{}", stderr.trim_end())?;
+                    }
+                    return Ok(());
+                }
+                write!(f, ":")?;
+                for e in errors {
+                    write!(f, "\n{e}")?;
+                    for n in &e.notes {
+                        write!(f, "\n{n}")?;
+                    }
+                }
+                Ok(())
+            }
+            IspcError::Archive { lib, message } => write!(
+                f,
+                "Failed to assemble ISPC objects into library {lib}: {message}"
+            ),
+            IspcError::Link {
+                lib,
+                message,
+                stderr,
+            } => {
+                write!(
+                    f,
+                    "Failed to link ISPC objects into shared library {lib}: {message}"
+                )?;
+                if !stderr.trim().is_empty() {
+                    write!(f, "\n{}", stderr.trim_end())?;
+                }
+                Ok(())
+            }
+            IspcError::DepFile { file, source } => write!(
+                f,
+                "Failed to open dependencies list for {}: {source}",
+                file.display()
+            ),
+            IspcError::Bindgen { lib, message } => {
+                write!(f, "Failed to generate Rust bindings to {lib}: {message}")
+            }
+            IspcError::IsphHeader {
+                source_file,
+                message,
+            } => write!(
+                f,
+                "Failed to generate an ISPC header from {}: {message}",
+                source_file.display()
+            ),
+            IspcError::InlineSource {
+                source_file,
+                message,
+            } => write!(
+                f,
+                "Failed to find the inline ISPC code in {}: {message}",
+                source_file.display()
+            ),
+            IspcError::MissingIsaOutputs {
+                file,
+                expected,
+                found,
+            } => {
+                write!(
+                    f,
+                    "ISPC did not write the outputs expected for each target ISA of {}",
+                    file.display()
+                )?;
+                write!(f, "\n  expected: {}", expected.join(", "))?;
+                let found: Vec<_> = found
+                    .iter()
+                    .filter_map(|p| p.file_name())
+                    .map(|p| p.to_string_lossy())
+                    .collect();
+                if found.is_empty() {
+                    write!(f, "\n  found: none")
+                } else {
+                    write!(f, "\n  found: {}", found.join(", "))
+                }
+            }
+            IspcError::InvalidConfig { problems } => {
+                write!(f, "Invalid ISPC configuration:")?;
+                for p in problems {
+                    write!(f, "\n  - {p}")?;
+                }
+                Ok(())
+            }
+            IspcError::InvalidEnvVar { var, source } => {
+                write!(f, "Invalid value for environment variable {var}: {source}")
+            }
+            IspcError::InvalidMetadata { manifest, message } => write!(
+                f,
+                "Invalid [package.metadata.ispc] in {}: {message}",
+                manifest.display()
+            ),
+            IspcError::SafeWrapper { function, message } => {
+                write!(
+                    f,
+                    "Failed to generate safe wrapper for {function}: {message}"
+                )
+            }
+            IspcError::OutputCollision {
+                name,
+                first,
+                second,
+            } => write!(
+                f,
+                "ISPC source files {} and {} would both write outputs named {name}",
+                first.display(),
+                second.display()
+            ),
+            IspcError::InvalidSourceName { file } => write!(
+                f,
+                "ISPC source file {} must have a file name which is valid UTF-8",
+                file.display()
+            ),
+            IspcError::MissingEnvVar { var } => write!(
+                f,
+                "Environment variable {var} is not set, is this being run from a build script?"
+            ),
+            IspcError::Io { path, source } => write!(f, "{}: {source}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for IspcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IspcError::CompilerNotFound { source, .. }
+            | IspcError::DepFile { source, .. }
+            | IspcError::Io { source, .. } => Some(source),
+            IspcError::InvalidEnvVar { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
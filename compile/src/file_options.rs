@@ -0,0 +1,85 @@
+//! Options overriding the library wide configuration for individual ISPC
+//! source files.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::opt::{Addressing, MathLib, OptimizationOpt, TargetISA, CPU};
+
+/// Options for a single ISPC file which are merged over the library defaults
+/// set on the `Config` when building that file, see `Config::file_with`.
+/// Defines, include paths and optimization options are added to those of the
+/// library, the other options replace the library's setting if set.
+///
+/// # Example
+/// ```no_run
+/// use ispc_compile::{Config, FileOptions, OptimizationOpt, TargetISA};
+///
+/// Config::new()
+///     .file("src/scene.ispc")
+///     .file_with(
+///         "src/hot_loop.ispc",
+///         FileOptions::new()
+///             .optimization_opt(OptimizationOpt::FastMath)
+///             .target_isas(vec![TargetISA::AVX2i32x8, TargetISA::AVX512SKXx16]),
+///     )
+///     .compile("kernels");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FileOptions {
+    pub(crate) defines: Vec<(String, Option<String>)>,
+    pub(crate) include_paths: Vec<PathBuf>,
+    pub(crate) math_lib: Option<MathLib>,
+    pub(crate) addressing: Option<Addressing>,
+    pub(crate) optimization_opts: BTreeSet<OptimizationOpt>,
+    pub(crate) cpu_target: Option<CPU>,
+    pub(crate) target_isa: Option<Vec<TargetISA>>,
+}
+
+impl FileOptions {
+    pub fn new() -> FileOptions {
+        FileOptions::default()
+    }
+    /// Add a define to be passed to ISPC when compiling this file.
+    pub fn add_define(mut self, define: &str, value: Option<&str>) -> FileOptions {
+        self.defines
+            .push((define.to_string(), value.map(|s| s.to_string())));
+        self
+    }
+    /// Add an extra include path for this file.
+    pub fn include_path<P: AsRef<Path>>(mut self, path: P) -> FileOptions {
+        self.include_paths.push(path.as_ref().to_path_buf());
+        self
+    }
+    /// Select the math library used by this file.
+    pub fn math_lib(mut self, math_lib: MathLib) -> FileOptions {
+        self.math_lib = Some(math_lib);
+        self
+    }
+    /// Select 32 or 64 bit addressing calculations for this file.
+    pub fn addressing(mut self, addressing: Addressing) -> FileOptions {
+        self.addressing = Some(addressing);
+        self
+    }
+    /// Add an optimization option for this file.
+    pub fn optimization_opt(mut self, opt: OptimizationOpt) -> FileOptions {
+        self.optimization_opts.insert(opt);
+        self
+    }
+    /// Select the target CPU for this file.
+    pub fn cpu(mut self, cpu: CPU) -> FileOptions {
+        self.cpu_target = Some(cpu);
+        self
+    }
+    /// Select the target ISA and vector width for this file.
+    pub fn target_isa(mut self, target: TargetISA) -> FileOptions {
+        self.target_isa = Some(vec![target]);
+        self
+    }
+    /// Select multiple target ISAs and vector widths for this file, the same
+    /// restrictions as for `Config::target_isas` apply.
+    pub fn target_isas(mut self, targets: Vec<TargetISA>) -> FileOptions {
+        self.target_isa = Some(targets);
+        self
+    }
+}
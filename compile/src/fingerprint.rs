@@ -0,0 +1,80 @@
+//! Fingerprints recording the inputs an ISPC source file was last compiled
+//! with, so that unchanged files don't need to be recompiled each time the
+//! build script runs.
+
+use std::fs;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// Compute the fingerprint for a source file from its contents, the contents
+/// of each dependency listed in the `.idep` file ISPC wrote for it and the
+/// compiler `key` (the ISPC version and arguments). Dependencies which aren't
+/// files, such as the pseudo-dependency ISPC lists for its standard library,
+/// are recorded by name only. Returns `None` if one of the files can't be
+/// read, in which case the source must be recompiled.
+pub(crate) fn compute(source: &Path, deps: &Path, key: &[String]) -> Option<String> {
+    let mut hasher = Sha256::new();
+    for k in key {
+        hash_entry(&mut hasher, k.as_bytes());
+    }
+    hash_entry(&mut hasher, &fs::read(source).ok()?);
+    let dep_list = fs::read_to_string(deps).ok()?;
+    for d in dep_list.lines() {
+        hash_entry(&mut hasher, d.as_bytes());
+        if Path::new(d).is_file() {
+            hash_entry(&mut hasher, &fs::read(d).ok()?);
+        }
+    }
+    Some(to_hex(&hasher.finalize()))
+}
+
+/// Check if the fingerprint stored at `path` matches `fingerprint`.
+pub(crate) fn matches(path: &Path, fingerprint: &str) -> bool {
+    fs::read_to_string(path)
+        .map(|f| f.trim() == fingerprint)
+        .unwrap_or(false)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hash a length prefixed entry so adjacent entries can't run together.
+fn hash_entry(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn dependencies_which_arent_files() {
+        let dir = test_dir("fingerprint");
+        let source = dir.join("simple.ispc");
+        let header = dir.join("common.isph");
+        let deps = dir.join("simple.idep");
+        fs::write(&source, "#include \"common.isph\"\n").unwrap();
+        fs::write(&header, "struct Foo { float x; };\n").unwrap();
+        fs::write(
+            &deps,
+            format!("{}\nstdlib.isph\n{}\n", source.display(), header.display()),
+        )
+        .unwrap();
+        let key = [String::from("1.25.3"), String::from("-O2")];
+
+        let first = compute(&source, &deps, &key).expect("the stdlib dependency is skipped");
+        assert_eq!(compute(&source, &deps, &key).as_ref(), Some(&first));
+
+        fs::write(&header, "struct Foo { double x; };\n").unwrap();
+        let changed = compute(&source, &deps, &key).unwrap();
+        assert_ne!(first, changed);
+
+        fs::remove_file(&header).unwrap();
+        assert_ne!(compute(&source, &deps, &key), Some(changed));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,1044 @@
+//! Generation of the Rust bindings by parsing the C headers written by ISPC,
+//! as an alternative to bindgen which doesn't require libclang.
+//!
+//! ISPC's headers are machine generated and only use a small subset of C:
+//! include guards and a few `#if` checks, structs (including the vector types
+//! ISPC exports), enums and `extern` function declarations. We run a minimal
+//! preprocessor over them as a C compiler would see them, then parse the
+//! declarations and emit bindings in the same style as bindgen.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+
+/// Generate the Rust bindings for the declarations in the ISPC `headers`.
+/// Types shared between headers are guarded by ISPC with include guards, so
+/// the headers are preprocessed in order with the same set of defines to
+/// emit them only once. Structs and enums named in `skip` are left out.
+pub(crate) fn generate_bindings(
+    headers: &[PathBuf],
+    skip: &HashSet<String>,
+) -> Result<String, String> {
+    let mut defines = HashSet::new();
+    let mut items = Vec::new();
+    for h in headers {
+        let text =
+            fs::read_to_string(h).map_err(|e| format!("failed to read {}: {e}", h.display()))?;
+        let source = preprocess(&text, &mut defines)
+            .map_err(|e| format!("failed to preprocess {}: {e}", h.display()))?;
+        let tokens =
+            tokenize(&source).map_err(|e| format!("failed to parse {}: {e}", h.display()))?;
+        Parser {
+            tokens: &tokens,
+            pos: 0,
+        }
+        .parse(&mut items)
+        .map_err(|e| format!("failed to parse {}: {e}", h.display()))?;
+    }
+    let mut out =
+        String::from("/* automatically generated by ispc_compile from the ISPC headers */\n\n");
+    for i in &items {
+        match i {
+            Item::Struct { name, .. } | Item::Enum { name, .. } if skip.contains(name) => {}
+            _ => i.emit(&mut out)?,
+        }
+    }
+    Ok(out)
+}
+
+/// Strip comments and evaluate the preprocessor directives in `text`,
+/// returning the lines a C compiler would see.
+fn preprocess(text: &str, defines: &mut HashSet<String>) -> Result<String, String> {
+    let text = strip_comments(&text.replace("\\\n", " "));
+    // Each open conditional tracks if it's active, if a branch has been taken
+    // and if the enclosing block is active
+    let mut conds: Vec<(bool, bool, bool)> = Vec::new();
+    let mut out = String::new();
+    for line in text.lines() {
+        let active = conds.last().map(|c| c.0).unwrap_or(true);
+        let line = line.trim();
+        let Some(directive) = line.strip_prefix('#') else {
+            if active {
+                out.push_str(line);
+                out.push('\n');
+            }
+            continue;
+        };
+        let directive = directive.trim_start();
+        let (name, rest) = directive
+            .split_once(|c: char| c.is_whitespace())
+            .map(|(n, r)| (n, r.trim()))
+            .unwrap_or((directive, ""));
+        match name {
+            "ifdef" | "ifndef" | "if" => {
+                let cond = match name {
+                    "ifdef" => defines.contains(rest),
+                    "ifndef" => !defines.contains(rest),
+                    _ => active && eval_condition(rest, defines)?,
+                };
+                conds.push((active && cond, cond, active));
+            }
+            "elif" => {
+                let c = conds.last_mut().ok_or("#elif without #if")?;
+                let cond = c.2 && !c.1 && eval_condition(rest, defines)?;
+                c.0 = cond;
+                c.1 |= cond;
+            }
+            "else" => {
+                let c = conds.last_mut().ok_or("#else without #if")?;
+                c.0 = c.2 && !c.1;
+                c.1 = true;
+            }
+            "endif" => {
+                conds.pop().ok_or("#endif without #if")?;
+            }
+            "define" if active => {
+                let macro_name: String = rest
+                    .chars()
+                    .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                    .collect();
+                defines.insert(macro_name);
+            }
+            "undef" if active => {
+                defines.remove(rest);
+            }
+            "error" if active => return Err(format!("#error {rest}")),
+            // Includes of system headers, pragmas and inactive directives
+            _ => {}
+        }
+    }
+    if !conds.is_empty() {
+        return Err(String::from("unterminated #if"));
+    }
+    Ok(out)
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    loop {
+        let line = rest.find("//");
+        let block = rest.find("/*");
+        match (line, block) {
+            (Some(l), b) if b.is_none_or(|b| l < b) => {
+                out.push_str(&rest[..l]);
+                rest = rest[l..].find('\n').map(|e| &rest[l + e..]).unwrap_or("");
+            }
+            (_, Some(b)) => {
+                out.push_str(&rest[..b]);
+                out.push(' ');
+                rest = rest[b + 2..]
+                    .find("*/")
+                    .map(|e| &rest[b + e + 4..])
+                    .unwrap_or("");
+            }
+            _ => {
+                out.push_str(rest);
+                return out;
+            }
+        }
+    }
+}
+
+/// Evaluate an `#if` condition. Only `defined`, `!`, `&&`, `||`, parentheses,
+/// integers and identifiers are supported, which is all ISPC uses. Identifiers
+/// evaluate to 1 if they're defined and 0 otherwise.
+fn eval_condition(cond: &str, defines: &HashSet<String>) -> Result<bool, String> {
+    let tokens = tokenize(cond)?;
+    let mut eval = CondEval {
+        tokens: &tokens,
+        pos: 0,
+        defines,
+    };
+    let v = eval.or()?;
+    if eval.pos != tokens.len() {
+        return Err(format!("unsupported #if condition '{cond}'"));
+    }
+    Ok(v)
+}
+
+struct CondEval<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    defines: &'a HashSet<String>,
+}
+
+impl CondEval<'_> {
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+    fn eat(&mut self, p: &str) -> bool {
+        if self.tokens.get(self.pos).is_some_and(|t| t.is_punct(p)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn or(&mut self) -> Result<bool, String> {
+        let mut v = self.and()?;
+        while self.eat("||") {
+            v |= self.and()?;
+        }
+        Ok(v)
+    }
+    fn and(&mut self) -> Result<bool, String> {
+        let mut v = self.unary()?;
+        while self.eat("&&") {
+            v &= self.unary()?;
+        }
+        Ok(v)
+    }
+    fn unary(&mut self) -> Result<bool, String> {
+        if self.eat("!") {
+            return Ok(!self.unary()?);
+        }
+        if self.eat("(") {
+            let v = self.or()?;
+            if !self.eat(")") {
+                return Err(String::from("expected ')' in #if condition"));
+            }
+            return Ok(v);
+        }
+        match self.next() {
+            Some(Token::Ident(i)) if i == "defined" => {
+                let paren = self.eat("(");
+                let v = match self.next() {
+                    Some(Token::Ident(m)) => self.defines.contains(&m),
+                    _ => return Err(String::from("expected macro name after defined")),
+                };
+                if paren && !self.eat(")") {
+                    return Err(String::from("expected ')' after defined"));
+                }
+                Ok(v)
+            }
+            Some(Token::Ident(i)) => Ok(self.defines.contains(&i)),
+            Some(Token::Number(n)) => Ok(parse_int(&n).is_some_and(|n| n != 0)),
+            t => Err(format!("unexpected {t:?} in #if condition")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(String),
+    Str(String),
+    Punct(String),
+}
+
+impl Token {
+    fn is_punct(&self, p: &str) -> bool {
+        matches!(self, Token::Punct(t) if t == p)
+    }
+    fn is_ident(&self, i: &str) -> bool {
+        matches!(self, Token::Ident(t) if t == i)
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let take_while = |chars: &mut std::iter::Peekable<std::str::CharIndices<'_>>,
+                          f: fn(char) -> bool| {
+            let mut end = i + c.len_utf8();
+            while let Some(&(j, n)) = chars.peek() {
+                if !f(n) {
+                    break;
+                }
+                end = j + n.len_utf8();
+                chars.next();
+            }
+            &text[i..end]
+        };
+        if c.is_ascii_alphabetic() || c == '_' {
+            let s = take_while(&mut chars, |n| n.is_ascii_alphanumeric() || n == '_');
+            tokens.push(Token::Ident(s.to_owned()));
+        } else if c.is_ascii_digit() {
+            let s = take_while(&mut chars, |n| n.is_ascii_alphanumeric() || n == '.');
+            tokens.push(Token::Number(s.to_owned()));
+        } else if c == '"' {
+            let mut s = String::new();
+            for (_, n) in chars.by_ref() {
+                if n == '"' {
+                    break;
+                }
+                s.push(n);
+            }
+            tokens.push(Token::Str(s));
+        } else if "&|".contains(c) && chars.peek().is_some_and(|&(_, n)| n == c) {
+            chars.next();
+            tokens.push(Token::Punct(format!("{c}{c}")));
+        } else if "{}()[];,*=-+!<>&|~.:".contains(c) {
+            tokens.push(Token::Punct(c.to_string()));
+        } else {
+            return Err(format!("unexpected character '{c}'"));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parse a C integer literal, ignoring any suffix.
+fn parse_int(s: &str) -> Option<i64> {
+    let s = s.trim_end_matches(['u', 'U', 'l', 'L']);
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// A C type used in a declaration.
+#[derive(Clone, Debug)]
+enum CType {
+    /// A builtin or named type, as the Rust type to use.
+    Named(String),
+    Void,
+    Pointer {
+        is_const: bool,
+        pointee: Box<CType>,
+    },
+    Array(Box<CType>, u64),
+    FnPointer {
+        ret: Box<CType>,
+        params: Vec<(String, CType)>,
+    },
+}
+
+impl CType {
+    fn rust(&self) -> String {
+        match self {
+            CType::Named(n) => n.clone(),
+            CType::Void => String::from("::std::os::raw::c_void"),
+            CType::Pointer { is_const, pointee } => {
+                let q = if *is_const { "const" } else { "mut" };
+                format!("*{q} {}", pointee.rust())
+            }
+            CType::Array(t, n) => format!("[{}; {n}usize]", t.rust()),
+            CType::FnPointer { ret, params } => format!(
+                "::std::option::Option<unsafe extern \"C\" fn({}){}>",
+                rust_params(params),
+                rust_ret(ret)
+            ),
+        }
+    }
+}
+
+/// A declaration from the header to emit bindings for.
+enum Item {
+    Struct {
+        name: String,
+        align: Option<u64>,
+        fields: Vec<(String, CType)>,
+    },
+    Enum {
+        name: String,
+        variants: Vec<(String, i64)>,
+    },
+    Function {
+        name: String,
+        ret: CType,
+        params: Vec<(String, CType)>,
+    },
+}
+
+impl Item {
+    /// Emit the bindings for the item in the same style as bindgen.
+    fn emit(&self, out: &mut String) -> Result<(), String> {
+        let fmt_err = |e: std::fmt::Error| e.to_string();
+        match self {
+            Item::Struct {
+                name,
+                align,
+                fields,
+            } => {
+                match align {
+                    Some(a) => writeln!(out, "#[repr(C, align({a}))]"),
+                    None => writeln!(out, "#[repr(C)]"),
+                }
+                .map_err(fmt_err)?;
+                writeln!(out, "#[derive(Debug, Copy, Clone)]\npub struct {name} {{")
+                    .map_err(fmt_err)?;
+                for (f, t) in fields {
+                    writeln!(out, "    pub {}: {},", rust_ident(f), t.rust()).map_err(fmt_err)?;
+                }
+                writeln!(out, "}}").map_err(fmt_err)?;
+            }
+            Item::Enum { name, variants } => {
+                for (v, value) in variants {
+                    writeln!(out, "pub const {name}_{v}: {name} = {value};").map_err(fmt_err)?;
+                }
+                let repr = if variants.iter().any(|(_, v)| *v < 0) {
+                    "c_int"
+                } else {
+                    "c_uint"
+                };
+                writeln!(out, "pub type {name} = ::std::os::raw::{repr};").map_err(fmt_err)?;
+            }
+            Item::Function { name, ret, params } => {
+                writeln!(
+                    out,
+                    "unsafe extern \"C\" {{\n    pub fn {name}({}){};\n}}",
+                    rust_params(params),
+                    rust_ret(ret)
+                )
+                .map_err(fmt_err)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn rust_params(params: &[(String, CType)]) -> String {
+    let params: Vec<_> = params
+        .iter()
+        .map(|(p, t)| format!("{}: {}", rust_ident(p), t.rust()))
+        .collect();
+    params.join(", ")
+}
+
+fn rust_ret(ret: &CType) -> String {
+    match ret {
+        CType::Void => String::new(),
+        r => format!(" -> {}", r.rust()),
+    }
+}
+
+/// Escape names which are Rust keywords the same way bindgen does.
+fn rust_ident(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else",
+        "enum", "extern", "false", "fn", "for", "gen", "if", "impl", "in", "let", "loop", "match",
+        "mod", "move", "mut", "priv", "pub", "ref", "return", "self", "Self", "static", "struct",
+        "super", "trait", "true", "try", "type", "unsafe", "use", "where", "while", "yield",
+    ];
+    if KEYWORDS.contains(&name) {
+        format!("{name}_")
+    } else {
+        name.to_owned()
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Result<Token, String> {
+        let t = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("unexpected end of header")?;
+        self.pos += 1;
+        Ok(t)
+    }
+    fn eat(&mut self, p: &str) -> bool {
+        if self.peek().is_some_and(|t| t.is_punct(p)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn eat_ident(&mut self, i: &str) -> bool {
+        if self.peek().is_some_and(|t| t.is_ident(i)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, p: &str) -> Result<(), String> {
+        match self.next()? {
+            t if t.is_punct(p) => Ok(()),
+            t => Err(format!("expected '{p}', found {t:?}")),
+        }
+    }
+    fn ident(&mut self) -> Result<String, String> {
+        match self.next()? {
+            Token::Ident(i) => Ok(i),
+            t => Err(format!("expected an identifier, found {t:?}")),
+        }
+    }
+    fn number(&mut self) -> Result<i64, String> {
+        let neg = self.eat("-");
+        match self.next()? {
+            Token::Number(n) => parse_int(&n)
+                .map(|v| if neg { -v } else { v })
+                .ok_or_else(|| format!("invalid integer {n}")),
+            t => Err(format!("expected an integer, found {t:?}")),
+        }
+    }
+
+    fn parse(&mut self, items: &mut Vec<Item>) -> Result<(), String> {
+        while self.peek().is_some() {
+            if self.eat(";") {
+                continue;
+            }
+            // The extern "C" block is only seen by C++ compilers, but handle it anyway
+            if self.peek().is_some_and(|t| t.is_ident("extern"))
+                && matches!(self.tokens.get(self.pos + 1), Some(Token::Str(_)))
+            {
+                self.pos += 2;
+                self.expect("{")?;
+                continue;
+            }
+            if self.eat("}") {
+                continue;
+            }
+            if self.eat_ident("extern") {
+                items.push(self.function()?);
+            } else if self.peek().is_some_and(|t| t.is_ident("struct"))
+                && matches!(self.tokens.get(self.pos + 2), Some(t) if t.is_punct("{"))
+            {
+                self.pos += 1;
+                items.push(self.structure(None)?);
+            } else if self.eat_ident("__ISPC_ALIGNED_STRUCT__") {
+                // Structs holding vector types, the macro expands to the struct
+                // keyword and the alignment attribute of the compiler
+                self.expect("(")?;
+                let align = self.number()? as u64;
+                self.expect(")")?;
+                items.push(self.structure(Some(align))?);
+            } else if self.eat_ident("__declspec") {
+                // MSVC puts the alignment of vector types before the struct
+                self.expect("(")?;
+                if self.ident()? != "align" {
+                    return Err(String::from("unsupported __declspec on struct"));
+                }
+                self.expect("(")?;
+                let align = self.number()? as u64;
+                self.expect(")")?;
+                self.expect(")")?;
+                if !self.eat_ident("struct") {
+                    return Err(String::from("expected a struct after __declspec"));
+                }
+                items.push(self.structure(Some(align))?);
+            } else if self.peek().is_some_and(|t| t.is_ident("enum"))
+                && matches!(self.tokens.get(self.pos + 2), Some(t) if t.is_punct("{"))
+            {
+                self.pos += 1;
+                items.push(self.enumeration()?);
+            } else {
+                return Err(format!(
+                    "unsupported declaration starting at {:?}",
+                    self.peek()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn structure(&mut self, mut align: Option<u64>) -> Result<Item, String> {
+        let name = self.ident()?;
+        self.expect("{")?;
+        let mut fields = Vec::new();
+        while !self.eat("}") {
+            let (field, ty) = self.declaration()?;
+            let field = field.ok_or_else(|| format!("unnamed field in struct {name}"))?;
+            self.expect(";")?;
+            fields.push((field, ty));
+        }
+        // Vector types are aligned with __ISPC_ALIGN__(n) or the attribute it expands to
+        if self.eat_ident("__ISPC_ALIGN__") {
+            self.expect("(")?;
+            align = Some(self.number()? as u64);
+            self.expect(")")?;
+        } else if self.eat_ident("__attribute__") {
+            self.expect("(")?;
+            self.expect("(")?;
+            if self.ident()? != "aligned" {
+                return Err(format!("unsupported attribute on struct {name}"));
+            }
+            self.expect("(")?;
+            align = Some(self.number()? as u64);
+            for _ in 0..3 {
+                self.expect(")")?;
+            }
+        }
+        self.expect(";")?;
+        Ok(Item::Struct {
+            name,
+            align,
+            fields,
+        })
+    }
+
+    fn enumeration(&mut self) -> Result<Item, String> {
+        let name = self.ident()?;
+        self.expect("{")?;
+        let mut variants = Vec::new();
+        let mut next = 0;
+        while !self.eat("}") {
+            let variant = self.ident()?;
+            if self.eat("=") {
+                next = self.number()?;
+            }
+            variants.push((variant, next));
+            next += 1;
+            if !self.eat(",") {
+                self.expect("}")?;
+                break;
+            }
+        }
+        self.expect(";")?;
+        Ok(Item::Enum { name, variants })
+    }
+
+    fn function(&mut self) -> Result<Item, String> {
+        let (name, ret) = self.declaration()?;
+        let name = name.ok_or("function declaration without a name")?;
+        let params = self.parameters()?;
+        self.expect(";")?;
+        Ok(Item::Function { name, ret, params })
+    }
+
+    /// Parse the parameter list of a function or function pointer, naming
+    /// unnamed parameters by their position as bindgen does.
+    fn parameters(&mut self) -> Result<Vec<(String, CType)>, String> {
+        self.expect("(")?;
+        let mut params = Vec::new();
+        if !self.eat(")") {
+            loop {
+                let (p, ty) = self.declaration()?;
+                // A lone void means the function takes no parameters
+                if p.is_none() && matches!(ty, CType::Void) {
+                    self.expect(")")?;
+                    break;
+                }
+                // Array parameters are passed as pointers
+                let ty = match ty {
+                    CType::Array(t, _) => CType::Pointer {
+                        is_const: false,
+                        pointee: t,
+                    },
+                    t => t,
+                };
+                params.push((p.unwrap_or_else(|| format!("arg{}", params.len() + 1)), ty));
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(params)
+    }
+
+    /// Parse a type followed by an optional name and array dimensions.
+    fn declaration(&mut self) -> Result<(Option<String>, CType), String> {
+        let mut is_const = false;
+        let mut words = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Ident(i)) if i == "const" => is_const = true,
+                Some(Token::Ident(i)) if i == "volatile" || i == "struct" || i == "enum" => {}
+                Some(Token::Ident(i))
+                    if [
+                        "unsigned", "signed", "char", "short", "int", "long", "float", "double",
+                    ]
+                    .contains(&i.as_str())
+                        || words.is_empty() =>
+                {
+                    words.push(i.clone())
+                }
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let mut ty = c_type(&words)?;
+        // References to uniform values are passed as pointers, as bindgen maps them
+        while self.eat("*") || self.eat("&") {
+            ty = CType::Pointer {
+                is_const,
+                pointee: Box::new(ty),
+            };
+            // A const after the * applies to the pointer itself
+            is_const = self.eat_ident("const");
+        }
+        // A function pointer, e.g. `float (*f)(float, int32_t)`
+        if self.peek().is_some_and(|t| t.is_punct("("))
+            && self
+                .tokens
+                .get(self.pos + 1)
+                .is_some_and(|t| t.is_punct("*"))
+        {
+            self.pos += 2;
+            let name = match self.peek() {
+                Some(Token::Ident(_)) => Some(self.ident()?),
+                _ => None,
+            };
+            self.expect(")")?;
+            let params = self.parameters()?;
+            let ty = CType::FnPointer {
+                ret: Box::new(ty),
+                params,
+            };
+            return Ok((name, ty));
+        }
+        let name = match self.peek() {
+            Some(Token::Ident(_)) => Some(self.ident()?),
+            _ => None,
+        };
+        let mut dims = Vec::new();
+        while self.eat("[") {
+            dims.push(self.number()? as u64);
+            self.expect("]")?;
+        }
+        for d in dims.into_iter().rev() {
+            ty = CType::Array(Box::new(ty), d);
+        }
+        Ok((name, ty))
+    }
+}
+
+/// Map the words naming a C type to the Rust type bindgen would use.
+fn c_type(words: &[String]) -> Result<CType, String> {
+    let name = words.join(" ");
+    let raw = |t: &str| Ok(CType::Named(format!("::std::os::raw::{t}")));
+    let named = |t: &str| Ok(CType::Named(t.to_owned()));
+    match name.as_str() {
+        "" => Err(String::from("expected a type")),
+        "void" => Ok(CType::Void),
+        "bool" | "_Bool" => named("bool"),
+        "int8_t" => named("i8"),
+        "int16_t" => named("i16"),
+        "int32_t" => named("i32"),
+        "int64_t" => named("i64"),
+        "uint8_t" => named("u8"),
+        "uint16_t" => named("u16"),
+        "uint32_t" => named("u32"),
+        "uint64_t" => named("u64"),
+        "size_t" => named("usize"),
+        "float" => named("f32"),
+        "double" => named("f64"),
+        "char" => raw("c_char"),
+        "signed char" => raw("c_schar"),
+        "unsigned char" => raw("c_uchar"),
+        "short" | "short int" => raw("c_short"),
+        "unsigned short" | "unsigned short int" => raw("c_ushort"),
+        "int" | "signed" | "signed int" => raw("c_int"),
+        "unsigned" | "unsigned int" => raw("c_uint"),
+        "long" | "long int" => raw("c_long"),
+        "unsigned long" | "unsigned long int" => raw("c_ulong"),
+        "long long" | "long long int" => raw("c_longlong"),
+        "unsigned long long" | "unsigned long long int" => raw("c_ulonglong"),
+        n if words.len() == 1 => named(n),
+        n => Err(format!("unsupported type '{n}'")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    /// The framing ISPC writes around the declarations of each header.
+    const PROLOGUE: &str = r#"//
+// simple_ispc.h
+// (Header automatically generated by the ispc compiler.)
+// DO NOT EDIT THIS FILE.
+//
+
+#pragma once
+#include <stdint.h>
+
+
+
+#ifdef __cplusplus
+namespace ispc { /* namespace */
+#endif // __cplusplus
+
+#ifndef __ISPC_ALIGN__
+#if defined(__clang__) || !defined(_MSC_VER)
+// Clang, GCC, ICC
+#define __ISPC_ALIGN__(s) __attribute__((aligned(s)))
+#define __ISPC_ALIGNED_STRUCT__(s) struct __ISPC_ALIGN__(s)
+#else
+// Visual Studio
+#define __ISPC_ALIGN__(s) __declspec(align(s))
+#define __ISPC_ALIGNED_STRUCT__(s) __ISPC_ALIGN__(s) struct
+#endif
+#endif
+
+"#;
+
+    const EPILOGUE: &str = r#"
+#if defined(__cplusplus) && (! defined(__ISPC_NO_EXTERN_C) || !__ISPC_NO_EXTERN_C )
+} /* end extern C */
+#endif // __cplusplus
+
+
+#ifdef __cplusplus
+} /* namespace */
+#endif // __cplusplus
+"#;
+
+    const EXTERN_C: &str = r#"
+///////////////////////////////////////////////////////////////////////////
+// Functions exported from ispc code
+///////////////////////////////////////////////////////////////////////////
+#if defined(__cplusplus) && (! defined(__ISPC_NO_EXTERN_C) || !__ISPC_NO_EXTERN_C )
+extern "C" {
+#endif // __cplusplus
+"#;
+
+    const HEADER: &str = "/* automatically generated by ispc_compile from the ISPC headers */\n\n";
+
+    /// Write the declarations into ISPC headers and generate their bindings.
+    fn bindings(name: &str, headers: &[(&str, &str)], skip: &[&str]) -> Result<String, String> {
+        let dir = test_dir(name);
+        let paths: Vec<_> = headers
+            .iter()
+            .enumerate()
+            .map(|(i, (types, functions))| {
+                let path = dir.join(format!("header{i}_ispc.h"));
+                let text = format!("{PROLOGUE}{types}{EXTERN_C}{functions}{EPILOGUE}");
+                fs::write(&path, text).unwrap();
+                path
+            })
+            .collect();
+        let skip = skip.iter().map(|s| s.to_string()).collect();
+        let result = generate_bindings(&paths, &skip);
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn functions() {
+        let functions =
+            "    extern void add_lists(float * out, const float * a, const float * b, int32_t n);
+    extern bool any_negative(const double * vals, uint64_t count);
+    extern int32_t num_lanes();
+    extern void reset(void);
+";
+        assert_eq!(
+            bindings("header-functions", &[("", functions)], &[]).unwrap(),
+            format!(
+                "{HEADER}\
+unsafe extern \"C\" {{
+    pub fn add_lists(out: *mut f32, a: *const f32, b: *const f32, n: i32);
+}}
+unsafe extern \"C\" {{
+    pub fn any_negative(vals: *const f64, count: u64) -> bool;
+}}
+unsafe extern \"C\" {{
+    pub fn num_lanes() -> i32;
+}}
+unsafe extern \"C\" {{
+    pub fn reset();
+}}
+"
+            )
+        );
+    }
+
+    #[test]
+    fn structs_and_enums() {
+        let types = "
+#ifndef __ISPC_STRUCT_Particle__
+#define __ISPC_STRUCT_Particle__
+struct Particle {
+    float pos[3];
+    float mass;
+    int32_t id;
+    struct Particle * next;
+};
+#endif
+
+#ifndef __ISPC_ENUM_Color__
+#define __ISPC_ENUM_Color__
+enum Color {
+    RED = 0,
+    GREEN = 1,
+    BLUE = 4 
+};
+#endif
+
+";
+        let functions =
+            "    extern void step(struct Particle * particles, enum Color c, int32_t type);\n";
+        assert_eq!(
+            bindings("header-structs", &[(types, functions)], &[]).unwrap(),
+            format!(
+                "{HEADER}\
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Particle {{
+    pub pos: [f32; 3usize],
+    pub mass: f32,
+    pub id: i32,
+    pub next: *mut Particle,
+}}
+pub const Color_RED: Color = 0;
+pub const Color_GREEN: Color = 1;
+pub const Color_BLUE: Color = 4;
+pub type Color = ::std::os::raw::c_uint;
+unsafe extern \"C\" {{
+    pub fn step(particles: *mut Particle, c: Color, type_: i32);
+}}
+"
+            )
+        );
+    }
+
+    #[test]
+    fn aligned_vector_structs() {
+        // The vector types are written for both compilers, the struct holding
+        // them is aligned with the macro defined in the prologue
+        let types = "
+#ifndef __ISPC_VECTOR_float3__
+#define __ISPC_VECTOR_float3__
+#ifdef _MSC_VER
+__declspec( align(16) ) struct float3 { float v[3]; };
+#else
+struct float3 { float v[3]; } __attribute__ ((aligned(16)));
+#endif
+#endif
+
+#ifndef __ISPC_STRUCT_Ray__
+#define __ISPC_STRUCT_Ray__
+__ISPC_ALIGNED_STRUCT__(16) Ray {
+    float3 org;
+    float3 dir;
+    float tmax;
+};
+#endif
+
+";
+        let functions = "    extern void trace(const struct Ray &ray, float3 * color);\n";
+        let expected = format!(
+            "{HEADER}\
+#[repr(C, align(16))]
+#[derive(Debug, Copy, Clone)]
+pub struct float3 {{
+    pub v: [f32; 3usize],
+}}
+#[repr(C, align(16))]
+#[derive(Debug, Copy, Clone)]
+pub struct Ray {{
+    pub org: float3,
+    pub dir: float3,
+    pub tmax: f32,
+}}
+unsafe extern \"C\" {{
+    pub fn trace(ray: *const Ray, color: *mut float3);
+}}
+"
+        );
+        assert_eq!(
+            bindings("header-vectors", &[(types, functions)], &[]).unwrap(),
+            expected
+        );
+
+        // And as Visual Studio sees them
+        let msvc = types.replace("#ifdef _MSC_VER", "#if 1").replace(
+            "__ISPC_ALIGNED_STRUCT__(16)",
+            "__declspec(align(16)) struct",
+        );
+        assert_eq!(
+            bindings("header-msvc", &[(&msvc, functions)], &[]).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn function_pointers() {
+        let types = "
+#ifndef __ISPC_STRUCT_Callbacks__
+#define __ISPC_STRUCT_Callbacks__
+struct Callbacks {
+    void (*progress)(int32_t done, int32_t total);
+    float (*filter)(float, float);
+};
+#endif
+
+";
+        let functions =
+            "    extern void map(float * vals, int32_t n, float (*f)(float x, void * data), void * data);\n";
+        assert_eq!(
+            bindings("header-fn-pointers", &[(types, functions)], &[]).unwrap(),
+            format!(
+                "{HEADER}\
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Callbacks {{
+    pub progress: ::std::option::Option<unsafe extern \"C\" fn(done: i32, total: i32)>,
+    pub filter: ::std::option::Option<unsafe extern \"C\" fn(arg1: f32, arg2: f32) -> f32>,
+}}
+unsafe extern \"C\" {{
+    pub fn map(vals: *mut f32, n: i32, f: ::std::option::Option<unsafe extern \"C\" fn(x: f32, data: *mut ::std::os::raw::c_void) -> f32>, data: *mut ::std::os::raw::c_void);
+}}
+"
+            )
+        );
+    }
+
+    #[test]
+    fn shared_types_are_emitted_once() {
+        let types = "
+#ifndef __ISPC_STRUCT_Foo__
+#define __ISPC_STRUCT_Foo__
+struct Foo {
+    double x;
+};
+#endif
+
+#ifndef __ISPC_STRUCT_Bar__
+#define __ISPC_STRUCT_Bar__
+struct Bar {
+    int8_t y;
+};
+#endif
+
+";
+        let headers = [
+            (
+                types,
+                "    extern void a(struct Foo * foo, struct Bar * bar);\n",
+            ),
+            (types, "    extern void b(struct Foo * foo);\n"),
+        ];
+        assert_eq!(
+            bindings("header-shared", &headers, &["Bar"]).unwrap(),
+            format!(
+                "{HEADER}\
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct Foo {{
+    pub x: f64,
+}}
+unsafe extern \"C\" {{
+    pub fn a(foo: *mut Foo, bar: *mut Bar);
+}}
+unsafe extern \"C\" {{
+    pub fn b(foo: *mut Foo);
+}}
+"
+            )
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let err = bindings("header-error", &[("#error unsupported\n", "")], &[]).unwrap_err();
+        assert!(err.contains("unsupported"), "{err}");
+
+        let err = bindings("header-union", &[("union U { float f; };\n", "")], &[]).unwrap_err();
+        assert!(err.contains("unsupported declaration"), "{err}");
+
+        let err = bindings("header-unclosed", &[("#ifdef FOO\n", "")], &[]).unwrap_err();
+        assert!(err.contains("failed to preprocess"), "{err}");
+    }
+}
@@ -0,0 +1,165 @@
+//! Finding the ISPC code written inline in Rust source files with the
+//! `ispc!` macro from `ispc_macros`.
+
+use std::fs;
+use std::path::Path;
+
+use syn::parse::{Parse, ParseStream};
+use syn::visit::Visit;
+use syn::{Ident, LitStr, Token};
+
+use crate::error::IspcError;
+
+/// An ISPC library written inline in a Rust file.
+#[derive(Debug)]
+pub(crate) struct InlineKernel {
+    pub name: String,
+    pub source: String,
+    /// The line of the Rust file the source starts on.
+    pub line: usize,
+}
+
+impl InlineKernel {
+    /// The ISPC source with a `#line` directive so ISPC reports errors at
+    /// their location in the Rust file.
+    pub fn ispc_source(&self, rust_file: &Path) -> String {
+        let file = rust_file.display().to_string().replace('\\', "/");
+        format!("#line {} \"{file}\"\n{}", self.line, self.source)
+    }
+}
+
+struct Args {
+    name: Ident,
+    source: LitStr,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Args> {
+        let name = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let source = input.parse()?;
+        if !input.is_empty() {
+            input.parse::<Token![,]>()?;
+        }
+        Ok(Args { name, source })
+    }
+}
+
+struct Finder {
+    kernels: Vec<InlineKernel>,
+    errors: Vec<String>,
+}
+
+impl<'ast> Visit<'ast> for Finder {
+    fn visit_macro(&mut self, m: &'ast syn::Macro) {
+        if m.path.segments.last().is_none_or(|s| s.ident != "ispc") {
+            return;
+        }
+        match m.parse_body::<Args>() {
+            Ok(args) => self.kernels.push(InlineKernel {
+                name: args.name.to_string(),
+                source: args.source.value(),
+                line: args.source.span().start().line,
+            }),
+            Err(e) => self.errors.push(format!(
+                "invalid ispc! invocation on line {}: {e}",
+                m.path.segments[0].ident.span().start().line
+            )),
+        }
+    }
+}
+
+/// Find the `ispc!` invocations in the Rust file.
+pub(crate) fn find_kernels(rust_file: &Path) -> Result<Vec<InlineKernel>, IspcError> {
+    let err = |message: String| IspcError::InlineSource {
+        source_file: rust_file.to_path_buf(),
+        message,
+    };
+    let text = fs::read_to_string(rust_file).map_err(|e| IspcError::Io {
+        path: rust_file.to_path_buf(),
+        source: e,
+    })?;
+    let file = syn::parse_file(&text).map_err(|e| err(e.to_string()))?;
+    let mut finder = Finder {
+        kernels: Vec::new(),
+        errors: Vec::new(),
+    };
+    finder.visit_file(&file);
+    if !finder.errors.is_empty() {
+        return Err(err(finder.errors.join(", ")));
+    }
+    for (i, k) in finder.kernels.iter().enumerate() {
+        if finder.kernels[..i].iter().any(|o| o.name == k.name) {
+            return Err(err(format!(
+                "the name {} is used by more than one ispc! invocation",
+                k.name
+            )));
+        }
+    }
+    Ok(finder.kernels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    fn find(name: &str, text: &str) -> Result<Vec<InlineKernel>, IspcError> {
+        let file = test_dir(name).join("main.rs");
+        fs::write(&file, text).unwrap();
+        find_kernels(&file)
+    }
+
+    fn message(e: IspcError) -> String {
+        match e {
+            IspcError::InlineSource { message, .. } => message,
+            e => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[test]
+    fn finds_every_kernel() {
+        let text = r##"
+ispc::ispc!(scale, r#"
+export void scale(uniform float vals[], uniform int count) {
+    foreach (i = 0 ... count) {
+        if (vals[i] > 0) { vals[i] *= 2; }
+    }
+}
+"#);
+
+mod nested {
+    fn f() {
+        let s = "}";
+        ispc!(print, "export void print() { print(\"}\"); }",);
+    }
+}
+"##;
+        let kernels = find("inline-kernels", text).unwrap();
+        let names: Vec<_> = kernels.iter().map(|k| k.name.as_str()).collect();
+        assert_eq!(names, ["scale", "print"]);
+        assert!(kernels[0]
+            .source
+            .contains("if (vals[i] > 0) { vals[i] *= 2; }"));
+        assert!(kernels[0].source.trim_end().ends_with('}'));
+        assert_eq!(kernels[0].line, 2);
+        assert_eq!(kernels[1].source, "export void print() { print(\"}\"); }");
+        assert_eq!(kernels[1].line, 13);
+
+        let source = kernels[1].ispc_source(Path::new("src\\main.rs"));
+        assert!(source.starts_with("#line 13 \"src/main.rs\"\n"), "{source}");
+    }
+
+    #[test]
+    fn rejects_invalid_invocations() {
+        let e =
+            message(find("inline-args", "ispc!(\"scale\", \"export void f() {}\");").unwrap_err());
+        assert!(e.contains("invalid ispc! invocation on line 1"), "{e}");
+
+        let text = "ispc!(f, \"export void f() {}\");\nispc!(f, \"export void g() {}\");";
+        let e = message(find("inline-dup", text).unwrap_err());
+        assert!(e.contains("the name f is used by more than one"), "{e}");
+
+        assert!(find("inline-syntax", "fn main() {").is_err());
+    }
+}
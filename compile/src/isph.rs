@@ -0,0 +1,666 @@
+//! Generation of ISPC headers from Rust source files, so that types and
+//! constants shared between Rust and ISPC can be defined once on the Rust side.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use syn::{BinOp, Expr, Fields, Item, Lit, Type, UnOp};
+
+use crate::error::IspcError;
+
+/// Describes an ISPC header (`.isph`) to generate from the `#[repr(C)]` types
+/// and constants in Rust source files, see `Config::isph_header`. The header
+/// is written to the library's build directory, which is added to the include
+/// path so the ISPC code can `#include` it by name.
+///
+/// The following top level items of each source file are translated, in
+/// order to ISPC constants, enums and structs:
+///
+/// - `const` items with a primitive type or `usize`, whose value is a literal
+///   or simple arithmetic over literals and other constants.
+/// - Enums with `#[repr(C)]`, `#[repr(i32)]` or `#[repr(u32)]` and no fields.
+///   ISPC enum values share a single namespace, so they're prefixed with the
+///   enum name, e.g. `Mode::Fast` becomes `Mode_Fast`.
+/// - Structs with `#[repr(C)]` and named fields of primitive, pointer, array
+///   or other translated types.
+///
+/// Other items are ignored. Translated items which can't be represented in
+/// ISPC (e.g. using `usize`, or a packed struct) produce an error.
+///
+/// By default bindings are still generated for the types ISPC writes to its
+/// headers. Setting `rust_module` instead re-exports the Rust types from the
+/// bindings module, so exported functions taking them use the Rust types.
+///
+/// # Example
+/// With `src/shared.rs` containing
+/// ```
+/// pub const MAX_LIGHTS: i32 = 8;
+///
+/// #[repr(C)]
+/// pub struct Light {
+///     pub position: [f32; 3],
+///     pub intensity: f32,
+/// }
+/// ```
+/// the ISPC code can `#include "shared.isph"` to use `MAX_LIGHTS` and `Light`.
+/// ```no_run
+/// use ispc_compile::{Config, IsphHeader};
+///
+/// Config::new()
+///     .file("src/lights.ispc")
+///     .isph_header(
+///         IsphHeader::new("shared.isph")
+///             .source("src/shared.rs")
+///             .rust_module("crate::shared"),
+///     )
+///     .compile("lights");
+/// ```
+#[derive(Clone, Debug)]
+pub struct IsphHeader {
+    name: String,
+    sources: Vec<PathBuf>,
+    rust_module: Option<String>,
+}
+
+impl IsphHeader {
+    /// Generate the header `name`, e.g. `shared.isph`.
+    pub fn new(name: &str) -> IsphHeader {
+        IsphHeader {
+            name: name.to_owned(),
+            sources: Vec::new(),
+            rust_module: None,
+        }
+    }
+    /// Add a Rust source file to translate the types and constants of.
+    pub fn source<P: AsRef<Path>>(mut self, path: P) -> IsphHeader {
+        self.sources.push(path.as_ref().to_path_buf());
+        self
+    }
+    /// The path of the Rust module containing the types, e.g. `crate::shared`.
+    /// The types are then re-exported from the bindings module instead of
+    /// generating bindings for them.
+    pub fn rust_module(mut self, path: &str) -> IsphHeader {
+        self.rust_module = Some(path.to_owned());
+        self
+    }
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+    pub(crate) fn sources(&self) -> &[PathBuf] {
+        &self.sources
+    }
+    pub(crate) fn get_rust_module(&self) -> Option<&str> {
+        self.rust_module.as_deref()
+    }
+}
+
+/// The header generated from the Rust sources.
+pub(crate) struct Generated {
+    pub text: String,
+    /// The names of the structs and enums in the header.
+    pub types: Vec<String>,
+    /// The subset of `types` which are enums.
+    pub enums: Vec<String>,
+}
+
+/// Generate the ISPC header described by `header`.
+pub(crate) fn generate(header: &IsphHeader) -> Result<Generated, IspcError> {
+    let mut consts = Vec::new();
+    let mut enums = Vec::new();
+    let mut structs = Vec::new();
+    for s in &header.sources {
+        let err = |message: String| IspcError::IsphHeader {
+            source_file: s.clone(),
+            message,
+        };
+        let text = fs::read_to_string(s).map_err(|e| IspcError::Io {
+            path: s.clone(),
+            source: e,
+        })?;
+        let file = syn::parse_file(&text).map_err(|e| err(e.to_string()))?;
+        for item in &file.items {
+            match item {
+                Item::Const(c) => {
+                    if let Some(ty) = const_type(&c.ty) {
+                        let value =
+                            expr(&c.expr, ty).map_err(|e| err(format!("{}: {e}", c.ident)))?;
+                        consts.push(format!(
+                            "{}static const uniform {ty} {} = {value};\n",
+                            doc_comment(&c.attrs, ""),
+                            c.ident
+                        ));
+                    }
+                }
+                Item::Enum(e) => {
+                    if let Some(text) =
+                        enumeration(e).map_err(|m| err(format!("{}: {m}", e.ident)))?
+                    {
+                        enums.push((e.ident.to_string(), text));
+                    }
+                }
+                Item::Struct(s) => {
+                    if let Some(st) = structure(s).map_err(|m| err(format!("{}: {m}", s.ident)))? {
+                        structs.push(st);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let guard: String = header
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let sources: Vec<_> = header
+        .sources
+        .iter()
+        .map(|s| s.display().to_string())
+        .collect();
+    let mut text = format!(
+        "// Generated by ispc_compile from {}, do not edit.\n#ifndef __ISPC_RS_{guard}__\n#define __ISPC_RS_{guard}__\n\n",
+        sources.join(", ")
+    );
+    for c in &consts {
+        text.push_str(c);
+    }
+    for (_, e) in &enums {
+        text.push('\n');
+        text.push_str(e);
+    }
+    // ISPC needs structs to be declared before they're used in another struct,
+    // those only used through pointers can be declared ahead of their definition
+    let names: HashSet<_> = structs.iter().map(|s| s.name.clone()).collect();
+    let forward: HashSet<_> = structs.iter().flat_map(|s| &s.pointees).collect();
+    let forward: Vec<_> = structs
+        .iter()
+        .filter(|s| forward.contains(&s.name))
+        .collect();
+    if !forward.is_empty() {
+        text.push('\n');
+    }
+    for s in forward {
+        writeln!(text, "struct {};", s.name).unwrap();
+    }
+    let mut emitted = HashSet::new();
+    while emitted.len() < structs.len() {
+        let ready = structs.iter().find(|s| {
+            !emitted.contains(&s.name)
+                && s.uses
+                    .iter()
+                    .all(|u| !names.contains(u) || emitted.contains(u))
+        });
+        let Some(s) = ready else {
+            let remaining: Vec<_> = structs
+                .iter()
+                .filter(|s| !emitted.contains(&s.name))
+                .map(|s| s.name.clone())
+                .collect();
+            return Err(IspcError::IsphHeader {
+                source_file: header.sources.first().cloned().unwrap_or_default(),
+                message: format!("the structs {} contain each other", remaining.join(", ")),
+            });
+        };
+        text.push('\n');
+        text.push_str(&s.text);
+        emitted.insert(s.name.clone());
+    }
+    text.push_str("\n#endif\n");
+
+    let mut types: Vec<_> = enums.iter().map(|(n, _)| n.clone()).collect();
+    types.extend(structs.iter().map(|s| s.name.clone()));
+    Ok(Generated {
+        text,
+        types,
+        enums: enums.into_iter().map(|(n, _)| n).collect(),
+    })
+}
+
+/// Convert the doc comments in `attrs` to ISPC comments.
+fn doc_comment(attrs: &[syn::Attribute], indent: &str) -> String {
+    let mut out = String::new();
+    for a in attrs.iter().filter(|a| a.path().is_ident("doc")) {
+        if let syn::Meta::NameValue(nv) = &a.meta {
+            if let Expr::Lit(syn::ExprLit {
+                lit: Lit::Str(s), ..
+            }) = &nv.value
+            {
+                writeln!(out, "{indent}//{}", s.value().trim_end()).unwrap();
+            }
+        }
+    }
+    out
+}
+
+/// The arguments of the `#[repr(...)]` attributes on an item.
+fn reprs(attrs: &[syn::Attribute]) -> Result<Vec<String>, String> {
+    let mut reprs = Vec::new();
+    for a in attrs.iter().filter(|a| a.path().is_ident("repr")) {
+        a.parse_nested_meta(|m| {
+            let name = m
+                .path
+                .get_ident()
+                .map(|i| i.to_string())
+                .unwrap_or_default();
+            // Consume the arguments of e.g. align(16)
+            if m.input.peek(syn::token::Paren) {
+                let content;
+                syn::parenthesized!(content in m.input);
+                content.parse::<proc_macro2::TokenStream>()?;
+            }
+            reprs.push(name);
+            Ok(())
+        })
+        .map_err(|e| e.to_string())?;
+    }
+    Ok(reprs)
+}
+
+/// The ISPC type for a primitive Rust type, or `None` if it's not a primitive.
+fn primitive(ty: &Type) -> Option<&'static str> {
+    let Type::Path(p) = ty else {
+        return None;
+    };
+    let name = p.path.segments.last()?.ident.to_string();
+    Some(match name.as_str() {
+        "i8" | "c_char" | "c_schar" => "int8",
+        "i16" | "c_short" => "int16",
+        "i32" | "c_int" => "int32",
+        "i64" | "c_longlong" => "int64",
+        "u8" | "c_uchar" => "uint8",
+        "u16" | "c_ushort" => "uint16",
+        "u32" | "c_uint" => "uint32",
+        "u64" | "c_ulonglong" => "uint64",
+        "f32" | "c_float" => "float",
+        "f64" | "c_double" => "double",
+        "bool" => "bool",
+        _ => return None,
+    })
+}
+
+/// The ISPC type for the type of a constant. Sizes are allowed in constants
+/// (e.g. for array lengths) as they don't affect the layout of any types.
+fn const_type(ty: &Type) -> Option<&'static str> {
+    primitive(ty).or_else(|| {
+        let Type::Path(p) = ty else {
+            return None;
+        };
+        match p.path.get_ident()?.to_string().as_str() {
+            "usize" => Some("uint64"),
+            "isize" => Some("int64"),
+            _ => None,
+        }
+    })
+}
+
+/// Translate a constant expression to ISPC, where `ty` is the ISPC type of
+/// the constant, used to write float literals with the right precision.
+fn expr(e: &Expr, ty: &str) -> Result<String, String> {
+    Ok(match e {
+        Expr::Lit(l) => match &l.lit {
+            // Integer literals can have a float suffix, e.g. 1f32
+            Lit::Int(i) if !i.suffix().starts_with('f') => i.base10_digits().to_owned(),
+            Lit::Int(i) => float_literal(i.base10_digits(), i.suffix(), ty),
+            Lit::Float(f) => float_literal(f.base10_digits(), f.suffix(), ty),
+            Lit::Bool(b) => b.value.to_string(),
+            _ => return Err(String::from("only numeric and bool literals are supported")),
+        },
+        Expr::Unary(u) => {
+            let op = match u.op {
+                UnOp::Neg(_) => "-",
+                UnOp::Not(_) if ty == "bool" => "!",
+                UnOp::Not(_) => "~",
+                _ => return Err(String::from("unsupported unary operator")),
+            };
+            format!("{op}{}", expr(&u.expr, ty)?)
+        }
+        Expr::Binary(b) => {
+            let op = match b.op {
+                BinOp::Add(_) => "+",
+                BinOp::Sub(_) => "-",
+                BinOp::Mul(_) => "*",
+                BinOp::Div(_) => "/",
+                BinOp::Rem(_) => "%",
+                BinOp::Shl(_) => "<<",
+                BinOp::Shr(_) => ">>",
+                BinOp::BitAnd(_) => "&",
+                BinOp::BitOr(_) => "|",
+                BinOp::BitXor(_) => "^",
+                BinOp::And(_) => "&&",
+                BinOp::Or(_) => "||",
+                _ => return Err(String::from("unsupported binary operator")),
+            };
+            format!("{} {op} {}", expr(&b.left, ty)?, expr(&b.right, ty)?)
+        }
+        Expr::Paren(p) => format!("({})", expr(&p.expr, ty)?),
+        Expr::Group(g) => expr(&g.expr, ty)?,
+        Expr::Path(p) => match p.path.get_ident() {
+            Some(i) => i.to_string(),
+            None => {
+                return Err(String::from(
+                    "only constants in the same file can be referenced",
+                ))
+            }
+        },
+        Expr::Cast(c) => {
+            let cast = const_type(&c.ty).ok_or("only casts to primitive types are supported")?;
+            format!("(({cast}){})", expr(&c.expr, cast)?)
+        }
+        _ => return Err(String::from("unsupported constant expression")),
+    })
+}
+
+fn float_literal(digits: &str, suffix: &str, ty: &str) -> String {
+    let mut digits = digits.to_owned();
+    if !digits.contains(['.', 'e', 'E']) {
+        digits.push_str(".0");
+    }
+    if suffix == "f64" || (ty == "double" && suffix != "f32") {
+        digits.push('d');
+    }
+    digits
+}
+
+/// Translate a C-like enum, returning `None` if it doesn't have a C compatible repr.
+fn enumeration(e: &syn::ItemEnum) -> Result<Option<String>, String> {
+    let reprs = reprs(&e.attrs)?;
+    if reprs.is_empty() {
+        return Ok(None);
+    }
+    // ISPC enums are always 32 bit
+    if !reprs.iter().all(|r| r == "C" || r == "i32" || r == "u32") {
+        return Err(format!(
+            "repr({}) is not supported, ISPC enums are 32 bit",
+            reprs.join(", ")
+        ));
+    }
+    let mut text = format!("{}enum {} {{\n", doc_comment(&e.attrs, ""), e.ident);
+    for v in &e.variants {
+        if !matches!(v.fields, Fields::Unit) {
+            return Err(String::from("only enums without fields are supported"));
+        }
+        text.push_str(&doc_comment(&v.attrs, "    "));
+        match &v.discriminant {
+            Some((_, d)) => writeln!(text, "    {}_{} = {},", e.ident, v.ident, expr(d, "int32")?),
+            None => writeln!(text, "    {}_{},", e.ident, v.ident),
+        }
+        .unwrap();
+    }
+    text.push_str("};\n");
+    Ok(Some(text))
+}
+
+struct Struct {
+    name: String,
+    text: String,
+    /// The named types used by value in the fields.
+    uses: Vec<String>,
+    /// The named types only pointed to by the fields.
+    pointees: Vec<String>,
+}
+
+/// Translate a `#[repr(C)]` struct, returning `None` if it's not `#[repr(C)]`.
+fn structure(s: &syn::ItemStruct) -> Result<Option<Struct>, String> {
+    let reprs = reprs(&s.attrs)?;
+    if !reprs.iter().any(|r| r == "C") {
+        return Ok(None);
+    }
+    // ISPC has no way to change the alignment or packing of a struct
+    if let Some(r) = reprs.iter().find(|r| *r != "C") {
+        return Err(format!("repr({r}) is not supported by ISPC structs"));
+    }
+    if !s.generics.params.is_empty() {
+        return Err(String::from("generic structs are not supported"));
+    }
+    let Fields::Named(fields) = &s.fields else {
+        return Err(String::from("only structs with named fields are supported"));
+    };
+    let mut uses = Vec::new();
+    let mut pointees = Vec::new();
+    let mut text = format!("{}struct {} {{\n", doc_comment(&s.attrs, ""), s.ident);
+    for f in &fields.named {
+        let name = f.ident.as_ref().unwrap();
+        let (ty, dims) = field_type(&f.ty, &mut uses, &mut pointees)
+            .map_err(|e| format!("field {name}: {e}"))?;
+        text.push_str(&doc_comment(&f.attrs, "    "));
+        writeln!(text, "    {ty} {name}{dims};").unwrap();
+    }
+    text.push_str("};\n");
+    Ok(Some(Struct {
+        name: s.ident.to_string(),
+        text,
+        uses,
+        pointees,
+    }))
+}
+
+/// Translate the type of a struct field, returning the ISPC type and any array
+/// dimensions to write after the field name. Named types used by value are
+/// added to `uses` and those behind a pointer to `pointees`.
+fn field_type(
+    ty: &Type,
+    uses: &mut Vec<String>,
+    pointees: &mut Vec<String>,
+) -> Result<(String, String), String> {
+    match ty {
+        Type::Array(a) => {
+            let (elem, dims) = field_type(&a.elem, uses, pointees)?;
+            Ok((elem, format!("[{}]{dims}", expr(&a.len, "int32")?)))
+        }
+        Type::Ptr(p) => {
+            let mut pointed_to = Vec::new();
+            let pointee = match &*p.elem {
+                Type::Ptr(_) => format!(
+                    "{} uniform",
+                    field_type(&p.elem, &mut pointed_to, pointees)?.0
+                ),
+                Type::Path(t) if t.path.segments.last().is_some_and(|s| s.ident == "c_void") => {
+                    String::from("void")
+                }
+                Type::Array(_) => return Err(String::from("pointers to arrays are not supported")),
+                t => format!("uniform {}", field_type(t, &mut pointed_to, pointees)?.0),
+            };
+            pointees.extend(pointed_to);
+            let is_const = if p.const_token.is_some() {
+                "const "
+            } else {
+                ""
+            };
+            Ok((format!("{is_const}{pointee} *"), String::new()))
+        }
+        Type::Path(p) => {
+            if let Some(t) = primitive(ty) {
+                return Ok((t.to_owned(), String::new()));
+            }
+            let last = p.path.segments.last().ok_or("empty type path")?;
+            let name = last.ident.to_string();
+            if name == "usize" || name == "isize" {
+                return Err(format!(
+                    "{name} has no fixed size in ISPC, use a fixed width integer instead"
+                ));
+            }
+            if !last.arguments.is_empty() {
+                return Err(format!("generic type {name} is not supported"));
+            }
+            uses.push(name.clone());
+            Ok((name, String::new()))
+        }
+        _ => Err(String::from("unsupported field type")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    /// Generate the header for the Rust `source`, returning the text after
+    /// the line naming the source file.
+    fn header(name: &str, source: &str) -> Result<String, IspcError> {
+        let dir = test_dir(name);
+        let path = dir.join("shared.rs");
+        fs::write(&path, source).unwrap();
+        let result = generate(&IsphHeader::new("shared.isph").source(&path));
+        fs::remove_dir_all(&dir).unwrap();
+        let generated = result?;
+        let (_, text) = generated.text.split_once('\n').unwrap();
+        Ok(text.to_owned())
+    }
+
+    fn error(name: &str, source: &str) -> String {
+        match header(name, source) {
+            Err(IspcError::IsphHeader { message, .. }) => message,
+            r => panic!("expected an error translating the source, got {r:?}"),
+        }
+    }
+
+    #[test]
+    fn constants() {
+        let source = "
+use std::os::raw::c_char;
+
+/// The most lights in a scene
+pub const MAX_LIGHTS: usize = 8;
+pub const SCALE: f64 = 1.5 * 2.0;
+pub const HALF: f32 = 1f32 / 2.0;
+pub const MASK: u32 = !(1 << 4);
+pub const NEWLINE: c_char = 10;
+pub const ENABLED: bool = !false;
+pub const NAME: &str = \"ignored\";
+";
+        assert_eq!(
+            header("isph-consts", source).unwrap(),
+            "#ifndef __ISPC_RS_SHARED_ISPH__
+#define __ISPC_RS_SHARED_ISPH__
+
+// The most lights in a scene
+static const uniform uint64 MAX_LIGHTS = 8;
+static const uniform double SCALE = 1.5d * 2.0d;
+static const uniform float HALF = 1.0 / 2.0;
+static const uniform uint32 MASK = ~(1 << 4);
+static const uniform int8 NEWLINE = 10;
+static const uniform bool ENABLED = !false;
+
+#endif
+"
+        );
+    }
+
+    #[test]
+    fn enums_and_structs() {
+        let source = "
+use std::os::raw::{c_char, c_void};
+
+#[repr(C)]
+pub enum Mode {
+    Fast,
+    /// Trace more rays
+    Accurate = 4,
+    Debug,
+}
+
+// Not C compatible, so left out
+pub enum Internal {
+    A(u32),
+}
+
+#[repr(C)]
+pub struct Scene {
+    pub lights: [Light; MAX_LIGHTS],
+    pub grid: [[u8; 4]; 2],
+    pub first: *const Node,
+    pub user_data: *mut c_void,
+    pub names: *mut *const c_char,
+    pub mode: Mode,
+}
+
+#[repr(C)]
+pub struct Node {
+    pub next: *mut Node,
+    pub value: f32,
+}
+
+/// A light in the scene
+#[repr(C)]
+pub struct Light {
+    /// In world space
+    pub position: [f32; 3],
+    pub intensity: f32,
+}
+
+pub struct NotShared {
+    pub x: usize,
+}
+";
+        assert_eq!(
+            header("isph-types", source).unwrap(),
+            "#ifndef __ISPC_RS_SHARED_ISPH__
+#define __ISPC_RS_SHARED_ISPH__
+
+
+enum Mode {
+    Mode_Fast,
+    // Trace more rays
+    Mode_Accurate = 4,
+    Mode_Debug,
+};
+
+struct Node;
+
+struct Node {
+    uniform Node * next;
+    float value;
+};
+
+// A light in the scene
+struct Light {
+    // In world space
+    float position[3];
+    float intensity;
+};
+
+struct Scene {
+    Light lights[MAX_LIGHTS];
+    uint8 grid[2][4];
+    const uniform Node * first;
+    void * user_data;
+    const uniform int8 * uniform * names;
+    Mode mode;
+};
+
+#endif
+"
+        );
+    }
+
+    #[test]
+    fn unsupported_items() {
+        assert!(error("isph-usize", "#[repr(C)] struct A { x: usize }").contains("field x"));
+        assert!(
+            error("isph-packed", "#[repr(C, packed)] struct A { x: u8 }")
+                .contains("repr(packed) is not supported")
+        );
+        assert!(error("isph-tuple", "#[repr(C)] struct A(u32);").contains("named fields"));
+        assert!(error("isph-generic", "#[repr(C)] struct A<T> { x: T }").contains("generic"));
+        assert!(error("isph-enum-u8", "#[repr(u8)] enum E { A }").contains("32 bit"));
+        assert!(
+            error("isph-enum-fields", "#[repr(C)] enum E { A(u32) }").contains("without fields")
+        );
+        assert!(error("isph-const", "const X: i32 = foo();").contains("X: unsupported"));
+        assert!(error(
+            "isph-cycle",
+            "#[repr(C)] struct A { b: B }\n#[repr(C)] struct B { a: A }"
+        )
+        .contains("the structs A, B contain each other"));
+        assert!(!error("isph-syntax", "struct {").is_empty());
+    }
+}
@@ -0,0 +1,88 @@
+//! Runs the ISPC compiler invocations for a library concurrently, limited by
+//! the jobserver Cargo provides to build scripts so we don't oversubscribe
+//! the machine when other crates are being built at the same time.
+
+use std::env;
+use std::io;
+use std::process::{Command, Output};
+use std::sync::mpsc;
+use std::thread;
+
+use jobserver::{Acquired, Client};
+
+/// Run each command and collect the outputs, in the same order as the commands
+/// were passed. If `parallel` is set the commands are run concurrently, each
+/// one holding a jobserver token while it runs.
+pub(crate) fn run(commands: Vec<Command>, parallel: bool) -> Vec<io::Result<Output>> {
+    if !parallel || commands.len() < 2 {
+        return run_serial(commands);
+    }
+    let client = match jobserver_client() {
+        Some(c) => c,
+        None => return run_serial(commands),
+    };
+
+    // Our process already holds an implicit token which is passed around as
+    // `None`, any additional tokens acquired from the jobserver are sent by
+    // the helper thread as they become available.
+    let (tx, rx) = mpsc::channel::<Option<Acquired>>();
+    let helper_tx = tx.clone();
+    let helper = match client.into_helper_thread(move |token| {
+        if let Ok(token) = token {
+            let _ = helper_tx.send(Some(token));
+        }
+    }) {
+        Ok(h) => h,
+        Err(_) => return run_serial(commands),
+    };
+    tx.send(None).unwrap();
+
+    let outputs = thread::scope(|scope| {
+        let mut handles = Vec::with_capacity(commands.len());
+        for (i, mut cmd) in commands.into_iter().enumerate() {
+            if i > 0 {
+                helper.request_token();
+            }
+            let token = rx.recv().unwrap();
+            let tx = tx.clone();
+            handles.push(scope.spawn(move || {
+                let output = cmd.output();
+                match token {
+                    // Hand the implicit token back so another command can use it
+                    None => {
+                        let _ = tx.send(None);
+                    }
+                    Some(t) => drop(t),
+                }
+                output
+            }));
+        }
+        handles
+            .into_iter()
+            .map(|h| h.join().expect("ISPC compile thread panicked"))
+            .collect()
+    });
+    drop(helper);
+    outputs
+}
+
+fn run_serial(commands: Vec<Command>) -> Vec<io::Result<Output>> {
+    commands.into_iter().map(|mut c| c.output()).collect()
+}
+
+/// Get the jobserver passed to us by Cargo, or if we're not running under
+/// Cargo create our own limited to `NUM_JOBS` or the number of CPUs.
+fn jobserver_client() -> Option<Client> {
+    // Safety: the jobserver file descriptors are only used by this client and
+    // the build script does not close them elsewhere.
+    if let Some(client) = unsafe { Client::from_env() } {
+        return Some(client);
+    }
+    let jobs = env::var("NUM_JOBS")
+        .ok()
+        .and_then(|j| j.parse::<usize>().ok())
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
+    // We hold an implicit token ourselves, so one less is needed from the client
+    Client::new(jobs.saturating_sub(1)).ok()
+}
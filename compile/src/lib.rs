@@ -11,7 +11,10 @@
 //! Both the [ISPC compiler](https://ispc.github.io/) and [libclang](http://clang.llvm.org/)
 //! (for [rust-bindgen](https://github.com/crabtw/rust-bindgen)) must be available in your path
 //! to compile the ISPC code and generate the bindings. These are not required if using `ispc_rt`
-//! to link against a previously compiled library.
+//! to link against a previously compiled library. To build without libclang, disable the
+//! default `bindgen` feature and the bindings will be generated by parsing the ISPC headers
+//! instead, see `BindingBackend`. A specific ISPC compiler can be selected
+//! with `Config::ispc_path` or by setting the `ISPC_<TARGET>` or `ISPC` environment variables.
 //!
 //! ## Windows Users
 //!
@@ -19,25 +22,86 @@
 //! and Clang link with MSVC on Windows. For bindgen to find libclang you'll need to copy
 //! `libclang.lib` to `clang.lib` and place it in your path.
 //!
+//! # Overriding Options from the Environment
+//!
+//! The target and code generation options set in the build script can be
+//! overridden with environment variables, e.g. to build variants of a library
+//! in CI without changing `build.rs`. Each takes the name ISPC uses for the
+//! option, lists are comma separated.
+//!
+//! - `ISPC_RS_TARGETS`: the target ISAs, e.g. `avx2-i32x8,sse4-i32x4`
+//! - `ISPC_RS_CPU`: the target CPU, e.g. `skx`
+//! - `ISPC_RS_ARCH`: the target architecture, e.g. `x86-64`
+//! - `ISPC_RS_TARGET_OS`: the target OS, e.g. `linux`
+//! - `ISPC_RS_MATH_LIB`: the math library, e.g. `fast`
+//! - `ISPC_RS_ADDRESSING`: `32` or `64` bit addressing
+//! - `ISPC_RS_OPT`: additional optimization options, e.g. `fast-math,disable-fma`
+//!
 
+mod abi;
+mod compiler;
+pub mod diagnostics;
+pub mod error;
+mod file_options;
+mod fingerprint;
+mod header;
+mod inline;
+mod isph;
+mod jobs;
+mod manifest;
+mod metadata;
 pub mod opt;
+mod target;
+#[cfg(test)]
+mod test_util;
+mod validate;
+mod wrappers;
 
+#[cfg(feature = "bindgen")]
 pub use bindgen;
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::env;
 use std::fmt::Display;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::OnceLock;
 
+use ar_archive_writer::{
+    write_archive_to_stream, ArchiveKind, NewArchiveMember, DEFAULT_OBJECT_READER,
+};
 use regex::Regex;
-use semver::{BuildMetadata, Prerelease, Version};
+use semver::Version;
 
+pub use crate::diagnostics::{Diagnostic, Severity};
+pub use crate::error::IspcError;
+pub use crate::file_options::FileOptions;
+pub use crate::isph::IsphHeader;
 pub use crate::opt::{
-    Addressing, Architecture, MathLib, OptimizationOpt, TargetISA, TargetOS, CPU,
+    Addressing, Architecture, EmitKind, MathLib, OptimizationOpt, ParseOptionError, TargetISA,
+    TargetOS, CPU,
 };
+pub use crate::validate::SupportedTargets;
+pub use crate::wrappers::SafeWrapper;
+
+use crate::opt::ISA_OUTPUT_SUFFIXES;
+use crate::target::Target;
+
+/// Handy wrapper around calling exit that will log the message passed first
+/// then exit with a failure exit code.
+macro_rules! exit_failure {
+    ($fmt:expr) => {{
+        eprintln!($fmt);
+        std::process::exit(libc::EXIT_FAILURE);
+    }};
+    ($fmt:expr, $($arg:tt)*) => {{
+        eprintln!($fmt, $($arg)*);
+        std::process::exit(libc::EXIT_FAILURE);
+    }}
+}
 
 /// Compile the list of ISPC files into a static library and generate bindings
 /// using bindgen. The library name should not contain a lib prefix or a lib
@@ -61,26 +125,144 @@ pub fn compile_library(lib: &str, files: &[&str]) {
     cfg.compile(lib)
 }
 
-/// Handy wrapper around calling exit that will log the message passed first
-/// then exit with a failure exit code.
-macro_rules! exit_failure {
-    ($fmt:expr) => {{
-        eprintln!($fmt);
-        std::process::exit(libc::EXIT_FAILURE);
-    }};
-    ($fmt:expr, $($arg:tt)*) => {{
-        eprintln!($fmt, $($arg)*);
-        std::process::exit(libc::EXIT_FAILURE);
-    }}
+/// Compile each library listed in the `[package.metadata.ispc]` table of the
+/// crate's `Cargo.toml`, found through `CARGO_MANIFEST_DIR`, for the target
+/// being built.
+///
+/// Each library lists its source files, which may be glob patterns relative
+/// to the crate directory. The other options can be set for every library at
+/// the top of the table or for a single library, and use the names ISPC uses
+/// for them. `target-isas` is either a list, or a table of lists keyed by the
+/// target architecture as named by Rust. Defines set to `true` are defined
+/// without a value. The `allowlist-*` options filter the bindings generated
+/// by bindgen.
+///
+/// ```toml
+/// [package.metadata.ispc]
+/// math-lib = "fast"
+/// opt = ["fast-math"]
+/// target-isas = { x86_64 = ["sse2-i32x4", "avx2-i32x8"], aarch64 = ["neon-i32x4"] }
+/// # Write the libraries to src/ to package them with the crate
+/// out-dir = "src/"
+///
+/// [package.metadata.ispc.libraries.simple]
+/// files = ["src/simple.ispc", "src/kernels/*.ispc"]
+/// defines = { USE_SHADOWS = true, MAX_BOUNCES = 4 }
+/// include-paths = ["include"]
+/// allowlist-functions = ["simple_.*"]
+/// ```
+///
+/// This function will exit the process with `EXIT_FAILURE` if the table is
+/// invalid or any stage of compilation or linking fails, see
+/// `try_build_from_manifest` to handle the error instead.
+///
+/// # Example
+/// ```no_run
+/// // in build.rs
+/// ispc_compile::build_from_manifest();
+/// ```
+pub fn build_from_manifest() {
+    if let Err(e) = try_build_from_manifest() {
+        exit_failure!("{}", e);
+    }
+}
+
+/// Compile each library listed in the `[package.metadata.ispc]` table of the
+/// crate's `Cargo.toml`, as in `build_from_manifest`, returning an error
+/// instead of exiting the process if any library fails to build.
+pub fn try_build_from_manifest() -> Result<Vec<CompileOutput>, IspcError> {
+    let manifest = PathBuf::from(build_var("CARGO_MANIFEST_DIR")?).join("Cargo.toml");
+    Config::new()
+        .target(&build_var("TARGET")?)
+        .manifest_libraries(&manifest)?
+        .iter()
+        .map(|(lib, cfg)| cfg.try_compile(lib))
+        .collect()
+}
+
+/// Read an environment variable Cargo sets for build scripts.
+fn build_var(var: &str) -> Result<String, IspcError> {
+    env::var(var).map_err(|_| IspcError::MissingEnvVar {
+        var: var.to_owned(),
+    })
+}
+
+/// The kind of library the ISPC code is built into, see `Config::library_kind`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LibraryKind {
+    /// A static library linked into the Rust crate (the default).
+    #[default]
+    Static,
+    /// A shared library which is loaded at runtime, so it can be shared by
+    /// multiple programs and replaced without relinking them.
+    Shared,
+}
+
+/// How the Rust bindings are generated from the headers written by ISPC, see
+/// `Config::binding_backend`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BindingBackend {
+    /// Generate the bindings with bindgen, configured by `Config::bindgen_builder`.
+    /// This is the default when the `bindgen` feature is enabled and requires libclang.
+    #[cfg(feature = "bindgen")]
+    Bindgen,
+    /// Generate the bindings by parsing the ISPC headers directly. ISPC only
+    /// writes a small subset of C to its headers (structs, enums and the
+    /// exported functions), so they can be translated to Rust without
+    /// libclang. Bindings are generated for every declaration in the headers.
+    Header,
+}
+
+impl Default for BindingBackend {
+    #[cfg(feature = "bindgen")]
+    fn default() -> BindingBackend {
+        BindingBackend::Bindgen
+    }
+    #[cfg(not(feature = "bindgen"))]
+    fn default() -> BindingBackend {
+        BindingBackend::Header
+    }
+}
+
+/// The files produced by a successful call to `Config::try_compile`.
+#[derive(Debug, Clone)]
+pub struct CompileOutput {
+    /// The name of the library, as passed to `try_compile`.
+    pub lib: String,
+    /// The library the ISPC objects were assembled into. For a shared library
+    /// on Windows this is the DLL, the import library is placed next to it.
+    pub library: PathBuf,
+    /// Whether `library` is a static or shared library.
+    pub kind: LibraryKind,
+    /// The Rust file containing the generated bindings module.
+    pub bindings: PathBuf,
+    /// The package manifest recording what was compiled for each target, which
+    /// `ispc_rt::PackagedModule` checks when linking a packaged library.
+    pub manifest: PathBuf,
+    /// The object files which were assembled into the library.
+    pub objects: Vec<PathBuf>,
+    /// The headers generated by ISPC for each source file.
+    pub headers: Vec<PathBuf>,
+    /// The additional outputs requested with `Config::emit` for each source
+    /// file, including those for each ISA when compiling for multiple targets.
+    pub emitted: Vec<PathBuf>,
+    /// The warnings reported by ISPC for the files which were compiled, with
+    /// duplicates (e.g. from compiling for multiple ISAs) removed.
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 /// Extra configuration to be passed to ISPC
+#[derive(Clone)]
 pub struct Config {
-    ispc_version: Version,
-    ispc_files: Vec<PathBuf>,
+    ispc_path: Option<PathBuf>,
+    ispc_exe: OnceLock<PathBuf>,
+    ispc_version: OnceLock<Version>,
+    supported_targets: OnceLock<SupportedTargets>,
+    ispc_files: Vec<(PathBuf, FileOptions)>,
     include_paths: Vec<PathBuf>,
     // These options are set from the environment if not set by the user
     out_dir: Option<PathBuf>,
+    build_dir: Option<PathBuf>,
     debug: Option<bool>,
     opt_level: Option<u32>,
     target: Option<String>,
@@ -105,38 +287,346 @@ pub struct Config {
     architecture: Option<Architecture>,
     target_os: Option<TargetOS>,
     darwin_version_min: Option<(u32, u32)>,
+    #[cfg(feature = "bindgen")]
     bindgen_builder: bindgen::Builder,
+    binding_backend: BindingBackend,
+    parallel: bool,
+    library_kind: LibraryKind,
+    emit: BTreeSet<EmitKind>,
+    safe_wrappers: Vec<SafeWrapper>,
+    isph_headers: Vec<IsphHeader>,
 }
 
-impl Config {
-    pub fn new() -> Config {
-        // Query the ISPC compiler version. This also acts as a check that we can
-        // find the ISPC compiler when we need it later.
-        let cmd_output = Command::new("ispc")
-            .arg("--version")
-            .output()
-            .expect("Failed to find ISPC compiler in PATH");
-        if !cmd_output.status.success() {
-            exit_failure!("Failed to get ISPC version, is it in your PATH?");
+/// A type defined in Rust and shared with ISPC through a generated header,
+/// which is re-exported from the bindings module.
+struct SharedType {
+    name: String,
+    module: String,
+    #[cfg_attr(not(feature = "bindgen"), allow(dead_code))]
+    is_enum: bool,
+}
+
+/// The target and code generation options used for a build, after applying
+/// any overrides from the `ISPC_RS_*` environment variables.
+#[derive(Clone)]
+struct TargetOptions {
+    defines: Vec<(String, Option<String>)>,
+    include_paths: Vec<PathBuf>,
+    target_isa: Option<Vec<TargetISA>>,
+    cpu: Option<CPU>,
+    architecture: Option<Architecture>,
+    target_os: Option<TargetOS>,
+    math_lib: MathLib,
+    addressing: Option<Addressing>,
+    optimization_opts: BTreeSet<OptimizationOpt>,
+}
+
+impl TargetOptions {
+    /// Merge the options set for a single file over these options
+    fn merge(&self, file: &FileOptions) -> TargetOptions {
+        let mut opts = self.clone();
+        opts.defines.extend(file.defines.iter().cloned());
+        opts.include_paths
+            .extend(file.include_paths.iter().cloned());
+        opts.optimization_opts.extend(file.optimization_opts.iter());
+        opts.math_lib = file.math_lib.unwrap_or(opts.math_lib);
+        opts.addressing = file.addressing.or(opts.addressing);
+        opts.cpu = file.cpu_target.or(opts.cpu);
+        if file.target_isa.is_some() {
+            opts.target_isa = file.target_isa.clone();
         }
-        let ver_string = String::from_utf8_lossy(&cmd_output.stdout);
-        // The ISPC version will be the first version number printed
-        let re = Regex::new(r"(\d+\.\d+\.\d+)").unwrap();
-        let ispc_ver = Version::parse(
-            re.captures_iter(&ver_string)
-                .next()
-                .expect("Failed to parse ISPC version")
-                .get(1)
-                .expect("Failed to parse ISPC version")
-                .as_str(),
+        opts
+    }
+}
+
+/// The files ISPC writes out when compiling a single source file.
+struct SourceJob {
+    source: PathBuf,
+    object: PathBuf,
+    header: PathBuf,
+    deps: PathBuf,
+    /// The base name of the outputs, e.g. `foo_ispc`
+    name: String,
+    build_dir: PathBuf,
+    /// The suffixes ISPC may give the outputs for each ISA when compiling
+    /// for multiple targets
+    isa_suffixes: Vec<(TargetISA, Vec<String>)>,
+    /// The ISA-specific objects written when compiling for multiple targets,
+    /// see `find_outputs`
+    isa_objects: Vec<PathBuf>,
+    /// The additional outputs requested with `Config::emit`, with the output
+    /// path passed to ISPC and the files it wrote for each ISA
+    emits: Vec<(EmitKind, PathBuf, Vec<PathBuf>)>,
+    /// Records the inputs the outputs were last built from
+    fingerprint: PathBuf,
+    up_to_date: bool,
+}
+
+impl SourceJob {
+    /// Set up the output paths for `source`, using `name` as the base name
+    /// for the generated files, e.g. `foo_ispc`.
+    fn new(
+        source: &Path,
+        build_dir: &Path,
+        name: String,
+        isas: &[TargetISA],
+        emit: &BTreeSet<EmitKind>,
+        version: &Version,
+    ) -> SourceJob {
+        let emit_dir = build_dir.join("emit");
+        let emits = emit
+            .iter()
+            .map(|k| {
+                (
+                    *k,
+                    emit_dir.join(&name).with_extension(k.extension()),
+                    Vec::new(),
+                )
+            })
+            .collect();
+        SourceJob {
+            source: source.to_path_buf(),
+            object: build_dir.join(&name).with_extension("o"),
+            header: build_dir.join(&name).with_extension("h"),
+            deps: build_dir.join(&name).with_extension("idep"),
+            isa_suffixes: isas
+                .iter()
+                .map(|isa| (*isa, isa.output_suffixes(version)))
+                .collect(),
+            isa_objects: Vec::new(),
+            emits,
+            fingerprint: build_dir.join(&name).with_extension("fingerprint"),
+            name,
+            build_dir: build_dir.to_path_buf(),
+            up_to_date: false,
+        }
+    }
+    /// List the files in `dir` which are outputs for a specific ISA with the
+    /// extension `ext` and one of the `suffixes`, e.g. `foo_ispc_avx2.o`. The
+    /// outputs of other files can't match, as their names end with `_ispc`.
+    fn list_isa_outputs(
+        &self,
+        dir: &Path,
+        ext: &str,
+        suffixes: &[&str],
+    ) -> Result<Vec<PathBuf>, IspcError> {
+        let prefix = format!("{}_", self.name);
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(IspcError::Io {
+                    path: dir.to_path_buf(),
+                    source: e,
+                })
+            }
+        };
+        let mut outputs: Vec<_> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.extension().is_some_and(|e| e == ext)
+                    && p.file_stem()
+                        .and_then(|s| s.to_str())
+                        .and_then(|s| s.strip_prefix(&prefix))
+                        .is_some_and(|suffix| suffixes.contains(&suffix))
+            })
+            .collect();
+        outputs.sort();
+        Ok(outputs)
+    }
+    /// Find the outputs ISPC wrote in `dir` for each ISA, checking there's
+    /// one for every ISA compiled for.
+    fn isa_outputs(&self, dir: &Path, ext: &str) -> Result<Vec<PathBuf>, IspcError> {
+        let suffixes: Vec<_> = self
+            .isa_suffixes
+            .iter()
+            .flat_map(|(_, suffixes)| suffixes.iter().map(|s| s.as_str()))
+            .collect();
+        let found = self.list_isa_outputs(dir, ext, &suffixes)?;
+        let file_name = |suffix: &str| format!("{}_{suffix}.{ext}", self.name);
+        let missing = self.isa_suffixes.iter().any(|(_, suffixes)| {
+            !suffixes
+                .iter()
+                .any(|s| found.iter().any(|f| f.ends_with(file_name(s))))
+        });
+        if missing {
+            return Err(IspcError::MissingIsaOutputs {
+                file: self.source.clone(),
+                expected: self
+                    .isa_suffixes
+                    .iter()
+                    .map(|(isa, suffixes)| {
+                        let names: Vec<_> = suffixes.iter().map(|s| file_name(s)).collect();
+                        format!("{} for {isa}", names.join(" or "))
+                    })
+                    .collect(),
+                found,
+            });
+        }
+        Ok(found)
+    }
+    /// Find the ISA-specific objects and the extra outputs requested which
+    /// were written by ISPC.
+    fn find_outputs(&mut self) -> Result<(), IspcError> {
+        if self.isa_suffixes.is_empty() {
+            for (_, output, files) in &mut self.emits {
+                *files = vec![output.clone()];
+            }
+            return Ok(());
+        }
+        self.isa_objects = self.isa_outputs(&self.build_dir, "o")?;
+        let emit_dir = self.build_dir.join("emit");
+        let emitted = self
+            .emits
+            .iter()
+            .map(|(kind, _, _)| self.isa_outputs(&emit_dir, kind.extension()))
+            .collect::<Result<Vec<_>, _>>()?;
+        for ((_, _, files), found) in self.emits.iter_mut().zip(emitted) {
+            *files = found;
+        }
+        Ok(())
+    }
+    /// Remove the ISA-specific outputs from a previous build, so outputs for
+    /// ISAs which are no longer compiled for aren't included in the library.
+    fn remove_isa_outputs(&self) -> Result<(), IspcError> {
+        let mut stale = self.list_isa_outputs(&self.build_dir, "o", ISA_OUTPUT_SUFFIXES)?;
+        for (kind, _, _) in &self.emits {
+            stale.extend(self.list_isa_outputs(
+                &self.build_dir.join("emit"),
+                kind.extension(),
+                ISA_OUTPUT_SUFFIXES,
+            )?);
+        }
+        for f in stale {
+            fs::remove_file(&f).map_err(|e| IspcError::Io { path: f, source: e })?;
+        }
+        Ok(())
+    }
+    /// Check if the outputs from a previous build are all present and were
+    /// built from the same inputs, in which case we don't need to run ISPC.
+    fn is_up_to_date(&mut self, key: &[String]) -> bool {
+        let outputs_exist = [&self.object, &self.header, &self.deps]
+            .into_iter()
+            .all(|p| p.is_file())
+            && self.find_outputs().is_ok()
+            && self
+                .emits
+                .iter()
+                .flat_map(|(_, _, files)| files)
+                .all(|p| p.is_file());
+        self.up_to_date = outputs_exist
+            && fingerprint::compute(&self.source, &self.deps, key)
+                .map(|f| fingerprint::matches(&self.fingerprint, &f))
+                .unwrap_or(false);
+        self.up_to_date
+    }
+    /// Record the inputs used to build the outputs after a successful compile.
+    fn write_fingerprint(&self, key: &[String]) -> Result<(), IspcError> {
+        let f = fingerprint::compute(&self.source, &self.deps, key).unwrap_or_default();
+        fs::write(&self.fingerprint, f).map_err(|e| IspcError::Io {
+            path: self.fingerprint.clone(),
+            source: e,
+        })
+    }
+}
+
+/// The contents of `<lib>.targets.rs`, included by the bindings, which lists
+/// the ISPC targets compiled for in `ISPC_TARGETS`. When the package holds
+/// libraries for several architectures the list for each is selected with
+/// `cfg(target_arch)`, preferring `target` over other targets with the same
+/// architecture.
+fn targets_file_contents(target: &str, packaged: &BTreeMap<String, Vec<String>>) -> String {
+    let mut by_arch = BTreeMap::new();
+    for (triple, isas) in packaged {
+        let arch = target::rust_arch(triple);
+        if triple == target || !by_arch.contains_key(&arch) {
+            by_arch.insert(arch, isas);
+        }
+    }
+    let doc = "/// The ISPC targets the library was compiled for\n";
+    let list = |isas: &[String]| {
+        let quoted: Vec<_> = isas.iter().map(|t| format!("\"{t}\"")).collect();
+        format!(
+            "pub const ISPC_TARGETS: &[&str] = &[{}];\n",
+            quoted.join(", ")
         )
-        .expect("Failed to parse ISPC version");
+    };
+    if by_arch.len() == 1 {
+        return by_arch
+            .values()
+            .map(|isas| format!("{doc}{}", list(isas)))
+            .collect();
+    }
+    by_arch
+        .iter()
+        .map(|(arch, isas)| format!("{doc}#[cfg(target_arch = \"{arch}\")]\n{}", list(isas)))
+        .collect()
+}
 
+/// Choose the base name for the outputs of each source file, e.g. `foo_ispc`
+/// for `src/foo.ispc`. Files with the same stem are named from their path
+/// relative to the crate instead, e.g. `a_kernel_ispc` and `b_kernel_ispc`
+/// for `a/kernel.ispc` and `b/kernel.ispc`.
+fn output_names(sources: &[PathBuf]) -> Result<Vec<String>, IspcError> {
+    let stems = sources
+        .iter()
+        .map(|s| {
+            s.file_stem()
+                .and_then(|f| f.to_str())
+                .ok_or_else(|| IspcError::InvalidSourceName { file: s.clone() })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // Only characters that are valid in file names and header guards
+    let sanitize = |n: &str| -> String {
+        n.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    };
+    let manifest_dir = env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from);
+    let mut names: Vec<String> = Vec::with_capacity(sources.len());
+    for (i, s) in sources.iter().enumerate() {
+        let base = if stems.iter().filter(|o| **o == stems[i]).count() == 1 {
+            sanitize(stems[i])
+        } else {
+            let rel = manifest_dir
+                .as_ref()
+                .and_then(|d| s.strip_prefix(d).ok())
+                .unwrap_or(s);
+            let parts: Vec<_> = rel
+                .with_extension("")
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(p) => Some(sanitize(&p.to_string_lossy())),
+                    _ => None,
+                })
+                .collect();
+            parts.join("_")
+        };
+        let name = base + "_ispc";
+        if let Some(j) = names.iter().position(|n| *n == name) {
+            return Err(IspcError::OutputCollision {
+                name,
+                first: sources[j].clone(),
+                second: sources[i].clone(),
+            });
+        }
+        names.push(name);
+    }
+    Ok(names)
+}
+
+impl Config {
+    pub fn new() -> Config {
         Config {
-            ispc_version: ispc_ver,
+            ispc_path: None,
+            ispc_exe: OnceLock::new(),
+            ispc_version: OnceLock::new(),
+            supported_targets: OnceLock::new(),
             ispc_files: Vec::new(),
             include_paths: Vec::new(),
             out_dir: None,
+            build_dir: None,
             debug: None,
             opt_level: None,
             target: None,
@@ -160,12 +650,33 @@ impl Config {
             architecture: None,
             target_os: None,
             darwin_version_min: None,
+            #[cfg(feature = "bindgen")]
             bindgen_builder: Default::default(),
+            binding_backend: BindingBackend::default(),
+            parallel: true,
+            library_kind: LibraryKind::Static,
+            emit: BTreeSet::new(),
+            safe_wrappers: Vec::new(),
+            isph_headers: Vec::new(),
         }
     }
+    /// Set the ISPC compiler executable to use. If not set the compiler is
+    /// taken from the `ISPC_<TARGET>` or `ISPC` environment variables, then
+    /// searched for on the `PATH` and in some common install locations.
+    pub fn ispc_path<P: AsRef<Path>>(&mut self, path: P) -> &mut Config {
+        self.ispc_path = Some(path.as_ref().to_path_buf());
+        self
+    }
     /// Add an ISPC file to be compiled
     pub fn file<P: AsRef<Path>>(&mut self, file: P) -> &mut Config {
-        self.ispc_files.push(file.as_ref().to_path_buf());
+        self.ispc_files
+            .push((file.as_ref().to_path_buf(), FileOptions::default()));
+        self
+    }
+    /// Add an ISPC file to be compiled with options which override the ones
+    /// set for the library, see `FileOptions`
+    pub fn file_with<P: AsRef<Path>>(&mut self, file: P, options: FileOptions) -> &mut Config {
+        self.ispc_files.push((file.as_ref().to_path_buf(), options));
         self
     }
     /// Set the output directory to override the default of `env!("OUT_DIR")`
@@ -173,6 +684,13 @@ impl Config {
         self.out_dir = Some(dir.as_ref().to_path_buf());
         self
     }
+    /// Set the directory intermediate files are written to, to override the
+    /// default of `env!("OUT_DIR")/ispc`. Each library is built in its own
+    /// subdirectory.
+    pub fn build_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Config {
+        self.build_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
     /// Set whether debug symbols should be generated, symbols are generated by
     /// default if `env!("DEBUG") == "true"`
     pub fn debug(&mut self, debug: bool) -> &mut Config {
@@ -264,21 +782,10 @@ impl Config {
         self
     }
     /// Emit instrumentation code for ISPC to gather performance data such
-    /// as vector utilization.
+    /// as vector utilization. This requires ISPC 1.9.1 or newer, older versions
+    /// generate a header which isn't C compatible, and are reported as an
+    /// invalid configuration when compiling.
     pub fn instrument(&mut self) -> &mut Config {
-        let min_ver = Version {
-            major: 1,
-            minor: 9,
-            patch: 1,
-            pre: Prerelease::EMPTY,
-            build: BuildMetadata::EMPTY,
-        };
-        if self.ispc_version < min_ver {
-            exit_failure!(
-                "Error: instrumentation is not supported on ISPC versions \
-                          older than 1.9.1 as it generates a non-C compatible header"
-            );
-        }
         self.instrument = true;
         self
     }
@@ -294,19 +801,22 @@ impl Config {
         self
     }
     /// Select multiple target ISAs and vector widths. If none is specified ispc will
-    /// choose the host CPU ISA and vector width.
+    /// choose the host CPU ISA and vector width, except when the target triple
+    /// requires another ISA: NEON for ARM, or SSE2 when cross compiling to x86.
     /// Note that certain options are not compatible with this use case,
     /// e.g. AVX1.1 will replace AVX1, Host should not be passed (just use the default)
     pub fn target_isas(&mut self, targets: Vec<TargetISA>) -> &mut Config {
         self.target_isa = Some(targets);
         self
     }
-    /// Select the CPU architecture to target
+    /// Select the CPU architecture to target. If not set the architecture of
+    /// the target triple is used.
     pub fn target_arch(&mut self, arch: Architecture) -> &mut Config {
         self.architecture = Some(arch);
         self
     }
-    /// Select the target OS for cross compilation
+    /// Select the target OS for cross compilation. If not set the OS of the
+    /// target triple is used.
     pub fn target_os(&mut self, os: TargetOS) -> &mut Config {
         self.target_os = Some(os);
         self
@@ -321,162 +831,797 @@ impl Config {
         self.cargo_metadata = metadata;
         self
     }
+    /// Set whether the ISPC files should be compiled in parallel, which is
+    /// enabled by default. The number of concurrent ISPC processes is limited
+    /// by Cargo's jobserver, or `NUM_JOBS` if the jobserver isn't available.
+    pub fn parallel(&mut self, parallel: bool) -> &mut Config {
+        self.parallel = parallel;
+        self
+    }
+    /// Set whether the ISPC code is built into a static library (the default)
+    /// or a shared library. When building a shared library Cargo is told to
+    /// link it dynamically and an rpath to the output directory is added for
+    /// the binaries, tests and examples of the crate, so they can be run
+    /// from Cargo without setting the library search path.
+    ///
+    /// ISPC code using tasks calls `ISPCLaunch`, `ISPCSync` and `ISPCAlloc`,
+    /// which are provided by `ispc_rt` in the Rust program. The shared library
+    /// leaves these undefined, so the program loading it must export them,
+    /// e.g. by linking with `-rdynamic` on Linux. On Windows a DLL can't have
+    /// undefined symbols, so code using tasks can't be built as a shared library.
+    pub fn library_kind(&mut self, kind: LibraryKind) -> &mut Config {
+        self.library_kind = kind;
+        self
+    }
+    /// Also write out the assembly or LLVM IR for each file, which is useful
+    /// when tuning kernels. The outputs are compiled with the same arguments
+    /// as the objects and written to `$OUT_DIR/ispc/<lib>/emit`, with a file for each ISA
+    /// when compiling for multiple targets, e.g. `foo_ispc_avx2.s`.
+    pub fn emit(&mut self, kind: EmitKind) -> &mut Config {
+        self.emit.insert(kind);
+        self
+    }
+    /// Generate a safe wrapper for an exported function, taking slices and
+    /// references instead of raw pointers, see `SafeWrapper`. The wrappers are
+    /// placed in a `safe` module inside the bindings module, e.g. `simple::safe`.
+    pub fn safe_wrapper(&mut self, wrapper: SafeWrapper) -> &mut Config {
+        self.safe_wrappers.push(wrapper);
+        self
+    }
+    /// Generate an ISPC header from the types and constants in Rust source
+    /// files, see `IsphHeader`. The header can be included by all the ISPC
+    /// files in the library.
+    pub fn isph_header(&mut self, header: IsphHeader) -> &mut Config {
+        self.isph_headers.push(header);
+        self
+    }
+    /// Set the bindgen builder used to generate the bindings, e.g. to only
+    /// generate bindings for some functions. This is only used with
+    /// `BindingBackend::Bindgen`.
+    #[cfg(feature = "bindgen")]
     pub fn bindgen_builder(&mut self, builder: bindgen::Builder) -> &mut Self {
         self.bindgen_builder = builder;
         self
     }
+    /// Select how the Rust bindings are generated. Bindgen is used by default
+    /// when the `bindgen` feature is enabled, otherwise the ISPC headers are
+    /// parsed directly.
+    pub fn binding_backend(&mut self, backend: BindingBackend) -> &mut Config {
+        self.binding_backend = backend;
+        self
+    }
     /// The library name should not have any prefix or suffix, e.g. instead of
     /// `libexample.a` or `example.lib` simply pass `example`
+    ///
+    /// This function will exit the process with `EXIT_FAILURE` if any stage of
+    /// compilation or linking fails, see `try_compile` to handle the error instead.
     pub fn compile(&self, lib: &str) {
-        let dst = self.get_out_dir();
-        let build_dir = self.get_build_dir();
-        let default_args = self.default_args();
-        dbg!(&default_args);
+        if let Err(e) = self.try_compile(lib) {
+            exit_failure!("{}", e);
+        }
+    }
+    /// Compile the ISPC code written inline in `rust_file` with the `ispc!`
+    /// macro from `ispc_macros`. Each invocation is built into its own library
+    /// with the name given to the macro, using the options set on this config.
+    /// Any files added to the config are not included in these libraries.
+    ///
+    /// The macro only imports the bindings, so this must be called for every
+    /// Rust file using `ispc!`.
+    ///
+    /// This function will exit the process with `EXIT_FAILURE` if any stage of
+    /// compilation or linking fails, see `try_compile_inline` to handle the error instead.
+    pub fn compile_inline<P: AsRef<Path>>(&self, rust_file: P) {
+        if let Err(e) = self.try_compile_inline(rust_file) {
+            exit_failure!("{}", e);
+        }
+    }
+    /// Compile the ISPC code written inline in `rust_file`, as in `compile_inline`,
+    /// returning an error instead of exiting the process if any stage fails.
+    pub fn try_compile_inline<P: AsRef<Path>>(
+        &self,
+        rust_file: P,
+    ) -> Result<Vec<CompileOutput>, IspcError> {
+        let rust_file = rust_file.as_ref();
+        self.print(&format!("cargo:rerun-if-changed={}", rust_file.display()));
+        let mut outputs = Vec::new();
+        for kernel in inline::find_kernels(rust_file)? {
+            let build_dir = self.get_build_dir(&kernel.name)?;
+            let source = build_dir.join(&kernel.name).with_extension("ispc");
+            let io_err = |e| IspcError::Io {
+                path: source.clone(),
+                source: e,
+            };
+            fs::create_dir_all(&build_dir).map_err(io_err)?;
+            let text = kernel.ispc_source(rust_file);
+            if fs::read_to_string(&source).ok().as_deref() != Some(&text) {
+                fs::write(&source, text).map_err(io_err)?;
+            }
+            let mut cfg = self.clone();
+            cfg.ispc_files = vec![(source, FileOptions::default())];
+            outputs.push(cfg.try_compile(&kernel.name)?);
+        }
+        Ok(outputs)
+    }
+    /// Read the libraries listed in the `[package.metadata.ispc]` table of the
+    /// manifest `cargo_toml`, returning the name of each library and the config
+    /// to compile it with. The options in the table are set on a copy of this
+    /// config, see `build_from_manifest` for the format of the table.
+    pub fn manifest_libraries(
+        &self,
+        cargo_toml: &Path,
+    ) -> Result<Vec<(String, Config)>, IspcError> {
+        metadata::libraries(self, cargo_toml)
+    }
+    /// Compile the ISPC files into a library and generate the Rust bindings,
+    /// returning an error instead of exiting the process if any stage fails.
+    /// The library name should not have any prefix or suffix, as in `compile`.
+    pub fn try_compile(&self, lib: &str) -> Result<CompileOutput, IspcError> {
+        let mut opts = self.target_options()?;
+        self.check_options(&opts)?;
+        let dst = self.get_out_dir()?;
+        let build_dir = self.get_build_dir(lib)?;
+        let mut dirs = vec![dst.clone(), build_dir.clone()];
+        if !self.emit.is_empty() {
+            dirs.push(build_dir.join("emit"));
+        }
+        if !self.isph_headers.is_empty() {
+            dirs.push(build_dir.join("include"));
+        }
+        for dir in dirs {
+            fs::create_dir_all(&dir).map_err(|e| IspcError::Io {
+                path: dir,
+                source: e,
+            })?;
+        }
+        let shared_types = self.generate_isph_headers(&build_dir.join("include"))?;
+        if !self.isph_headers.is_empty() {
+            opts.include_paths.push(build_dir.join("include"));
+        }
+        let sources: Vec<_> = self.ispc_files.iter().map(|(s, _)| s.clone()).collect();
+        let names = output_names(&sources)?;
+        let ispc_version = self.try_ispc_version()?;
+        let version = ispc_version.to_string();
+
+        let mut jobs = Vec::with_capacity(self.ispc_files.len());
+        let mut targets = Vec::new();
+        let mut file_args = Vec::new();
+        let mut commands = Vec::with_capacity(self.ispc_files.len());
+        for ((s, file_opts), name) in self.ispc_files.iter().zip(names) {
+            let opts = opts.merge(file_opts);
+            let default_args = self.default_args(&opts)?;
+            for t in opts.target_isa.iter().flatten() {
+                if !targets.contains(t) {
+                    targets.push(*t);
+                }
+            }
+            file_args.push((s.clone(), default_args.clone()));
+            // Changing the compiler or any of the arguments invalidates all previous builds
+            let mut fingerprint_key = vec![version.clone()];
+            fingerprint_key.extend(default_args.iter().cloned());
+
+            self.print(&format!("cargo:rerun-if-changed={}", s.display()));
+
+            let isas = match opts.target_isa {
+                Some(ref t) if t.len() > 1 => &t[..],
+                _ => &[],
+            };
+            let mut job = SourceJob::new(s, &build_dir, name, isas, &self.emit, ispc_version);
+            if !job.is_up_to_date(&fingerprint_key) {
+                job.remove_isa_outputs()?;
+                commands.extend(self.source_commands(&default_args, &job));
+            }
+            jobs.push((job, fingerprint_key));
+        }
+
+        // The ISPC files are compiled concurrently, but we process the outputs
+        // in order so that the compiler output for each file stays together.
+        let mut outputs = jobs::run(commands, self.parallel).into_iter();
+        let mut next_output = || {
+            outputs
+                .next()
+                .unwrap_or_else(|| Err(io::Error::other("the compiler was not run")))
+                .map_err(|e| IspcError::CompilerNotFound {
+                    compiler: self.ispc_exe().to_path_buf(),
+                    source: e,
+                })
+        };
         let mut objects = vec![];
         let mut headers = vec![];
-        for s in &self.ispc_files {
-            let fname = s
-                .file_stem()
-                .expect("ISPC source files must be files")
-                .to_str()
-                .expect("ISPC source file names must be valid UTF-8");
-            self.print(&format!("cargo:rerun-if-changed={}", s.display()));
+        let mut emitted = vec![];
+        let mut diagnostics = vec![];
+        let mut seen_diagnostics = HashSet::new();
+        for (mut job, fingerprint_key) in jobs {
+            let s = &job.source;
+            if !job.up_to_date {
+                let output = next_output()?;
 
-            let ispc_fname = String::from(fname) + "_ispc";
-            let object = build_dir.join(ispc_fname.clone()).with_extension("o");
-            let header = build_dir.join(ispc_fname.clone()).with_extension("h");
-            let deps = build_dir.join(ispc_fname.clone()).with_extension("idep");
-            let output = Command::new("ispc")
-                .args(&default_args)
-                .arg(s)
-                .arg("-o")
-                .arg(&object)
-                .arg("-h")
-                .arg(&header)
-                .arg("-MMM")
-                .arg(&deps)
-                .output()
-                .unwrap();
-
-            if !output.stderr.is_empty() {
                 let stderr = String::from_utf8_lossy(&output.stderr);
-                for l in stderr.lines() {
-                    self.print(&format!("cargo:warning=(ISPC) {l}"));
+                let file_diagnostics = diagnostics::parse(&stderr);
+                // Headers shared between files and builds for multiple ISAs
+                // will report the same warning repeatedly, only show it once.
+                // Errors are reported in the failure message instead.
+                for d in file_diagnostics.iter().filter(|d| !d.is_error()) {
+                    if seen_diagnostics.insert(d.clone()) {
+                        self.print(&format!("cargo:warning=(ISPC) {d}"));
+                        for n in &d.notes {
+                            self.print(&format!("cargo:warning=(ISPC) {n}"));
+                        }
+                        diagnostics.push(d.clone());
+                    }
                 }
+                if !output.status.success() {
+                    return Err(IspcError::Compile {
+                        file: s.clone(),
+                        stderr: stderr.into_owned(),
+                        diagnostics: file_diagnostics,
+                    });
+                }
+                // The diagnostics for the extra outputs are the same as for
+                // the object, so they're only reported if it fails
+                for _ in &job.emits {
+                    let output = next_output()?;
+                    if !output.status.success() {
+                        let stderr = String::from_utf8_lossy(&output.stderr);
+                        return Err(IspcError::Compile {
+                            file: s.clone(),
+                            diagnostics: diagnostics::parse(&stderr),
+                            stderr: stderr.into_owned(),
+                        });
+                    }
+                }
+                job.write_fingerprint(&fingerprint_key)?;
             }
-            if !output.status.success() {
-                exit_failure!("Failed to compile ISPC source file {}", s.display());
-            }
-            objects.push(object);
-            headers.push(header);
 
             // Go this files dependencies and add them to Cargo's watch list
-            let deps_list = File::open(deps)
-                .unwrap_or_else(|_| panic!("Failed to open dependencies list for {}", s.display()));
+            let deps_list = File::open(&job.deps).map_err(|e| IspcError::DepFile {
+                file: s.clone(),
+                source: e,
+            })?;
             let reader = BufReader::new(deps_list);
             for d in reader.lines() {
                 // Don't depend on the ISPC "stdlib" file which is output as a dependency
-                let dep_name = d.unwrap();
+                let dep_name = d.map_err(|e| IspcError::DepFile {
+                    file: s.clone(),
+                    source: e,
+                })?;
                 self.print(&format!("cargo:rerun-if-changed={dep_name}"));
             }
 
             // Push on the additional ISA-specific object files if any were generated
-            if let Some(ref t) = self.target_isa {
-                if t.len() > 1 {
-                    for isa in t.iter() {
-                        let isa_fname = ispc_fname.clone() + "_" + &isa.lib_suffix();
-                        let isa_obj = build_dir.join(isa_fname).with_extension("o");
-                        objects.push(isa_obj);
-                    }
-                }
-            }
-        }
-        let libfile = lib.to_owned() + &self.get_target();
-        if !self.assemble(&libfile, &objects).success() {
-            exit_failure!("Failed to assemble ISPC objects into library {lib}");
+            job.find_outputs()?;
+            objects.push(job.object);
+            headers.push(job.header);
+            objects.extend(job.isa_objects);
+            emitted.extend(job.emits.into_iter().flat_map(|(_, _, files)| files));
         }
-        self.print(&format!("cargo:rustc-link-lib=static={libfile}"));
+        // Embed the hash of the headers in the library so the bindings can
+        // check they're used with the library they were generated for
+        let abi_hash = abi::hash_headers(&headers)?;
+        objects.push(self.compile_abi_hash(lib, &build_dir, abi_hash, &opts)?);
 
-        // Now generate a header we can give to bindgen and generate bindings
-        let bindgen_header = self.generate_bindgen_header(lib, &headers);
-        let bindings = self
-            .bindgen_builder
-            .clone()
-            .header(bindgen_header.to_str().unwrap());
+        let target = self.get_target()?;
+        let libfile = lib.to_owned() + &target;
+        let library = match self.library_kind {
+            LibraryKind::Static => {
+                let library = self.assemble(&libfile, &objects)?;
+                self.print(&format!("cargo:rustc-link-lib=static={libfile}"));
+                library
+            }
+            LibraryKind::Shared => {
+                let library = self.link_shared(&libfile, &objects)?;
+                self.print(&format!("cargo:rustc-link-lib=dylib={libfile}"));
+                if !target.contains("windows") {
+                    self.print(&format!(
+                        "cargo:rustc-link-arg=-Wl,-rpath,{}",
+                        dst.display()
+                    ));
+                }
+                library
+            }
+        };
 
+        let generated_bindings = self.generate_bindings(lib, &headers, &shared_types)?;
         let bindgen_file = dst.join(lib).with_extension("rs");
-
-        let generated_bindings = match bindings.generate() {
-            Ok(b) => b.to_string(),
-            Err(_) => exit_failure!("Failed to generating Rust bindings to {}", lib),
-        };
-        let mut file = match File::create(bindgen_file) {
-            Ok(f) => f,
-            Err(e) => exit_failure!("Failed to open bindgen mod file for writing: {}", e),
+        let io_err = |e| IspcError::Io {
+            path: bindgen_file.clone(),
+            source: e,
         };
+        let mut file = File::create(&bindgen_file).map_err(io_err)?;
         file.write_all("#[allow(non_camel_case_types,dead_code,non_upper_case_globals,non_snake_case,improper_ctypes)]\n"
-                       .as_bytes()).unwrap();
+                       .as_bytes()).map_err(io_err)?;
         file.write_all(format!("pub mod {lib} {{\n").as_bytes())
-            .unwrap();
-        file.write_all(generated_bindings.as_bytes()).unwrap();
-        file.write_all(b"}").unwrap();
+            .map_err(io_err)?;
+        file.write_all(generated_bindings.as_bytes())
+            .map_err(io_err)?;
+        for t in &shared_types {
+            file.write_all(format!("pub use {}::{};\n", t.module, t.name).as_bytes())
+                .map_err(io_err)?;
+        }
+        // Let the runtime report which of the targets is used, see `ispc_rt::cpu`.
+        // The targets are listed in a separate file as the bindings are shared by
+        // the libraries packaged for several targets
+        file.write_all(
+            format!("include!(concat!(env!(\"ISPC_OUT_DIR\"), \"/{lib}.targets.rs\"));\n")
+                .as_bytes(),
+        )
+        .map_err(io_err)?;
+        file.write_all(abi::bindings(lib, abi_hash).as_bytes())
+            .map_err(io_err)?;
+        if !self.safe_wrappers.is_empty() {
+            let safe = wrappers::generate(&generated_bindings, &self.safe_wrappers)?;
+            file.write_all(safe.as_bytes()).map_err(io_err)?;
+        }
+        file.write_all(b"}").map_err(io_err)?;
+
+        let isas: Vec<_> = targets.iter().map(|t| t.to_string()).collect();
+        let manifest = manifest::update(
+            &dst,
+            lib,
+            &target,
+            &manifest::TargetEntry {
+                library: &library,
+                kind: self.library_kind,
+                bindings: &bindgen_file,
+                bindings_filtered: self.bindings_filtered(),
+                isas: isas.clone(),
+                ispc_version: version,
+                files: file_args,
+            },
+        )?;
+        // List every target packaged in the output directory, wherever it came
+        // from, so the bindings report the ISAs of whichever library is linked
+        let mut packaged = manifest::packaged_isas(&dst, lib);
+        packaged.insert(target.clone(), isas);
+        let targets_file = dst.join(format!("{lib}.targets.rs"));
+        fs::write(&targets_file, targets_file_contents(&target, &packaged)).map_err(|e| {
+            IspcError::Io {
+                path: targets_file,
+                source: e,
+            }
+        })?;
 
         self.print(&format!("cargo:rustc-link-search=native={}", dst.display()));
         self.print(&format!("cargo:rustc-env=ISPC_OUT_DIR={}", dst.display()));
+        Ok(CompileOutput {
+            lib: lib.to_owned(),
+            library,
+            kind: self.library_kind,
+            bindings: bindgen_file,
+            manifest,
+            objects,
+            headers,
+            emitted,
+            diagnostics,
+        })
+    }
+    /// The ISPC commands which compile the source files of the library `lib`,
+    /// as run by `try_compile` when the files have changed, without running
+    /// them. This includes the commands writing the outputs added with `emit`.
+    pub fn ispc_commands(&self, lib: &str) -> Result<Vec<Command>, IspcError> {
+        let mut opts = self.target_options()?;
+        let build_dir = self.get_build_dir(lib)?;
+        if !self.isph_headers.is_empty() {
+            opts.include_paths.push(build_dir.join("include"));
+        }
+        let sources: Vec<_> = self.ispc_files.iter().map(|(s, _)| s.clone()).collect();
+        let names = output_names(&sources)?;
+        let ispc_version = self.try_ispc_version()?;
+        let mut commands = Vec::new();
+        for ((s, file_opts), name) in self.ispc_files.iter().zip(names) {
+            let opts = opts.merge(file_opts);
+            let isas = match opts.target_isa {
+                Some(ref t) if t.len() > 1 => &t[..],
+                _ => &[],
+            };
+            let job = SourceJob::new(s, &build_dir, name, isas, &self.emit, ispc_version);
+            commands.extend(self.source_commands(&self.default_args(&opts)?, &job));
+        }
+        Ok(commands)
+    }
+    /// The commands compiling the source file of `job` and writing its extra outputs.
+    fn source_commands(&self, args: &[String], job: &SourceJob) -> Vec<Command> {
+        let mut cmd = Command::new(self.ispc_exe());
+        cmd.args(args)
+            .arg(&job.source)
+            .arg("-o")
+            .arg(&job.object)
+            .arg("-h")
+            .arg(&job.header)
+            .arg("-MMM")
+            .arg(&job.deps);
+        let mut commands = vec![cmd];
+        for (kind, output, _) in &job.emits {
+            let mut cmd = Command::new(self.ispc_exe());
+            cmd.args(args)
+                .arg(&job.source)
+                .arg(kind.to_string())
+                .arg("-o")
+                .arg(output);
+            commands.push(cmd);
+        }
+        commands
+    }
+    /// Compile the function returning the ABI hash of the library, see `abi`.
+    fn compile_abi_hash(
+        &self,
+        lib: &str,
+        build_dir: &Path,
+        hash: u64,
+        opts: &TargetOptions,
+    ) -> Result<PathBuf, IspcError> {
+        let source = build_dir.join(format!("{lib}_abi_hash.ispc"));
+        let object = source.with_extension("o");
+        // The function has no vector code, so is compiled for a single ISA
+        // to produce a single object
+        let mut opts = opts.clone();
+        if let Some(isas) = opts.target_isa.as_mut() {
+            isas.truncate(1);
+        }
+        let args = self.default_args(&opts)?;
+        // Record the arguments in the source so changing them recompiles it
+        let text = format!(
+            "{}// Compiled with: {}\n",
+            abi::ispc_source(lib, hash),
+            args.join(" ")
+        );
+        if object.is_file() && fs::read_to_string(&source).ok().as_deref() == Some(&text) {
+            return Ok(object);
+        }
+        fs::write(&source, &text).map_err(|e| IspcError::Io {
+            path: source.clone(),
+            source: e,
+        })?;
+        let output = Command::new(self.ispc_exe())
+            .args(&args)
+            .arg(&source)
+            .arg("-o")
+            .arg(&object)
+            .output()
+            .map_err(|e| IspcError::CompilerNotFound {
+                compiler: self.ispc_exe().to_path_buf(),
+                source: e,
+            })?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(IspcError::Compile {
+                file: source,
+                diagnostics: diagnostics::parse(&stderr),
+                stderr: stderr.into_owned(),
+            });
+        }
+        Ok(object)
     }
     /// Get the ISPC compiler version.
+    ///
+    /// This will exit the process with `EXIT_FAILURE` if the ISPC compiler can't
+    /// be run or its version can't be parsed, see `try_ispc_version`.
     pub fn ispc_version(&self) -> &Version {
-        &self.ispc_version
-    }
-    /// Link the ISPC code into a static library on Unix using `ar`
-    #[cfg(unix)]
-    fn assemble(&self, lib: &str, objects: &[PathBuf]) -> ExitStatus {
-        Command::new("ar")
-            .arg("crus")
-            .arg(format!("lib{lib}.a"))
-            .args(objects)
-            .current_dir(self.get_out_dir())
-            .status()
-            .unwrap()
-    }
-    /// Link the ISPC code into a static library on Windows using `lib.exe`
-    #[cfg(windows)]
-    fn assemble(&self, lib: &str, objects: &[PathBuf]) -> ExitStatus {
-        let target = self.get_target();
-        let mut lib_cmd = cc::windows_registry::find_tool(&target, "lib.exe")
-            .expect("Failed to find lib.exe for MSVC toolchain, aborting")
-            .to_command();
-        lib_cmd
-            .arg(format!("/OUT:{lib}.lib"))
+        match self.try_ispc_version() {
+            Ok(v) => v,
+            Err(e) => exit_failure!("{}", e),
+        }
+    }
+    /// Get the ISPC compiler version, querying the compiler the first time
+    /// this is called. The path and version of the compiler are printed then,
+    /// which Cargo only shows for a build script with `cargo build -vv`.
+    pub fn try_ispc_version(&self) -> Result<&Version, IspcError> {
+        if let Some(v) = self.ispc_version.get() {
+            return Ok(v);
+        }
+        let cmd_output = Command::new(self.ispc_exe())
+            .arg("--version")
+            .output()
+            .map_err(|e| IspcError::CompilerNotFound {
+                compiler: self.ispc_exe().to_path_buf(),
+                source: e,
+            })?;
+        let ver_string = String::from_utf8_lossy(&cmd_output.stdout);
+        if !cmd_output.status.success() {
+            return Err(IspcError::VersionParse {
+                output: ver_string.into_owned(),
+            });
+        }
+        // The ISPC version will be the first version number printed
+        let re = Regex::new(r"(\d+\.\d+\.\d+)").unwrap();
+        let ispc_ver = re
+            .captures_iter(&ver_string)
+            .next()
+            .and_then(|c| Version::parse(c.get(1)?.as_str()).ok())
+            .ok_or_else(|| IspcError::VersionParse {
+                output: ver_string.to_string(),
+            })?;
+        self.print(&format!(
+            "ispc-rs: using ISPC {ispc_ver} from {}",
+            self.ispc_exe().display()
+        ));
+        Ok(self.ispc_version.get_or_init(|| ispc_ver))
+    }
+    /// Query the target ISAs, CPUs, architectures and OSes supported by the
+    /// ISPC compiler, running the compiler the first time this is called.
+    pub fn supported_targets(&self) -> Result<&SupportedTargets, IspcError> {
+        if let Some(s) = self.supported_targets.get() {
+            return Ok(s);
+        }
+        let supported = SupportedTargets::query(self.ispc_exe())?;
+        Ok(self.supported_targets.get_or_init(|| supported))
+    }
+    /// Check that the target ISA, CPU, architecture and OS options are supported
+    /// by the ISPC compiler and are valid in combination with each other. This
+    /// is done before compiling by `try_compile`, but can also be called
+    /// directly to check a configuration.
+    pub fn validate(&self) -> Result<(), IspcError> {
+        let opts = self.target_options()?;
+        self.check_options(&opts)
+    }
+    fn check_options(&self, opts: &TargetOptions) -> Result<(), IspcError> {
+        let version = self.try_ispc_version()?;
+        let supported = self.supported_targets()?;
+        let check = |opts: &TargetOptions| {
+            let opts = validate::Options {
+                isas: opts.target_isa.as_deref(),
+                cpu: opts.cpu.as_ref(),
+                arch: opts.architecture,
+                os: opts.target_os.as_ref(),
+            };
+            validate::check(&opts, supported, version)
+        };
+        let mut problems = check(opts);
+        // Files overriding the target ISAs or CPU must also be checked, but
+        // only report the problems that aren't already reported for the library
+        for (file, file_opts) in &self.ispc_files {
+            if file_opts.target_isa.is_none() && file_opts.cpu_target.is_none() {
+                continue;
+            }
+            for p in check(&opts.merge(file_opts)) {
+                if !problems.contains(&p) {
+                    problems.push(format!("{}: {p}", file.display()));
+                }
+            }
+        }
+        if self.instrument && *version < Version::new(1, 9, 1) {
+            problems.push(String::from(
+                "instrumentation requires ISPC 1.9.1 or newer, older versions generate a header which isn't C compatible",
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(IspcError::InvalidConfig { problems })
+        }
+    }
+    /// Get the path to the ISPC compiler which will be used, see `ispc_path`
+    /// for how it is selected.
+    pub fn ispc_exe(&self) -> &Path {
+        self.ispc_exe.get_or_init(|| {
+            if let Some(ref p) = self.ispc_path {
+                return p.clone();
+            }
+            let target = self.target.clone().or_else(|| env::var("TARGET").ok());
+            for v in compiler::env_vars(target.as_deref()) {
+                self.print(&format!("cargo:rerun-if-env-changed={v}"));
+            }
+            compiler::find(target.as_deref())
+        })
+    }
+    /// Assemble the ISPC objects into a static library. The archive is written
+    /// directly instead of with the system archiver, with the members sorted
+    /// and their timestamps and owners zeroed, so the library is the same on
+    /// every machine it's built on.
+    fn assemble(&self, lib: &str, objects: &[PathBuf]) -> Result<PathBuf, IspcError> {
+        let target = self.get_target()?;
+        let (libname, kind) = if target.contains("msvc") {
+            (format!("{lib}.lib"), ArchiveKind::Coff)
+        } else if target.contains("apple") {
+            (format!("lib{lib}.a"), ArchiveKind::Darwin)
+        } else {
+            (format!("lib{lib}.a"), ArchiveKind::Gnu)
+        };
+        let mut members = Vec::with_capacity(objects.len());
+        for o in objects {
+            let buf = fs::read(o).map_err(|e| IspcError::Io {
+                path: o.clone(),
+                source: e,
+            })?;
+            let name = o
+                .file_name()
+                .ok_or_else(|| IspcError::Archive {
+                    lib: lib.to_owned(),
+                    message: format!("object {} has no file name", o.display()),
+                })?
+                .to_string_lossy()
+                .into_owned();
+            members.push(NewArchiveMember::new(buf, &DEFAULT_OBJECT_READER, name));
+        }
+        members.sort_by(|a, b| a.member_name.cmp(&b.member_name));
+
+        let mut archive = io::Cursor::new(Vec::new());
+        write_archive_to_stream(&mut archive, &members, kind, false, None).map_err(|e| {
+            IspcError::Archive {
+                lib: lib.to_owned(),
+                message: format!("failed to write archive: {e}"),
+            }
+        })?;
+        let library = self.get_out_dir()?.join(libname);
+        fs::write(&library, archive.into_inner()).map_err(|e| IspcError::Io {
+            path: library.clone(),
+            source: e,
+        })?;
+        Ok(library)
+    }
+    /// Link the ISPC code into a shared library, using `link.exe` for MSVC
+    /// targets and the target's C compiler for others
+    fn link_shared(&self, lib: &str, objects: &[PathBuf]) -> Result<PathBuf, IspcError> {
+        let target = self.get_target()?;
+        let out_dir = self.get_out_dir()?;
+        let link_err = |message| IspcError::Link {
+            lib: lib.to_owned(),
+            message,
+            stderr: String::new(),
+        };
+        let (libname, mut cmd) = if target.contains("msvc") {
+            let libname = format!("{lib}.dll");
+            let mut cmd = cc::windows_registry::find_tool(&target, "link.exe")
+                .ok_or_else(|| {
+                    link_err(String::from("failed to find link.exe for MSVC toolchain"))
+                })?
+                .to_command();
+            cmd.arg("/NOLOGO")
+                .arg("/DLL")
+                .arg(format!("/OUT:{libname}"))
+                .arg(format!("/IMPLIB:{lib}.lib"));
+            (libname, cmd)
+        } else {
+            let compiler = cc::Build::new()
+                .target(&target)
+                .opt_level(self.get_opt_level()?)
+                .debug(self.get_debug()?)
+                .cargo_metadata(false)
+                .try_get_compiler()
+                .map_err(|e| link_err(format!("failed to find C compiler to link with: {e}")))?;
+            let mut cmd = compiler.to_command();
+            let libname = if target.contains("apple") {
+                let libname = format!("lib{lib}.dylib");
+                // The ISPC task system functions are looked up in the host program
+                cmd.arg("-dynamiclib")
+                    .arg(format!("-Wl,-install_name,@rpath/{libname}"))
+                    .arg("-Wl,-undefined,dynamic_lookup");
+                libname
+            } else if target.contains("windows") {
+                let libname = format!("{lib}.dll");
+                cmd.arg("-shared")
+                    .arg(format!("-Wl,--out-implib,lib{lib}.dll.a"));
+                libname
+            } else {
+                let libname = format!("lib{lib}.so");
+                cmd.arg("-shared").arg(format!("-Wl,-soname,{libname}"));
+                libname
+            };
+            cmd.arg("-o").arg(&libname);
+            (libname, cmd)
+        };
+        let output = cmd
             .args(objects)
-            .current_dir(self.get_out_dir())
-            .status()
-            .unwrap()
+            .current_dir(&out_dir)
+            .output()
+            .map_err(|e| link_err(format!("failed to run linker: {e}")))?;
+        if !output.status.success() {
+            return Err(IspcError::Link {
+                lib: lib.to_owned(),
+                message: format!("linker exited with {}", output.status),
+                // MSVC's link.exe writes its errors to stdout
+                stderr: String::from_utf8_lossy(if output.stderr.is_empty() {
+                    &output.stdout
+                } else {
+                    &output.stderr
+                })
+                .into_owned(),
+            });
+        }
+        Ok(out_dir.join(libname))
+    }
+    /// Write the ISPC headers generated from Rust source files to `dir`,
+    /// returning the types which should be re-exported from the Rust side
+    /// instead of generating bindings for them.
+    fn generate_isph_headers(&self, dir: &Path) -> Result<Vec<SharedType>, IspcError> {
+        let mut shared_types = Vec::new();
+        for h in &self.isph_headers {
+            for s in h.sources() {
+                self.print(&format!("cargo:rerun-if-changed={}", s.display()));
+            }
+            let generated = isph::generate(h)?;
+            let path = dir.join(h.name());
+            // Only rewrite the header if it changed, to keep the ISPC files up to date
+            if fs::read_to_string(&path).ok().as_deref() != Some(&generated.text) {
+                fs::write(&path, &generated.text).map_err(|e| IspcError::Io {
+                    path: path.clone(),
+                    source: e,
+                })?;
+            }
+            if let Some(module) = h.get_rust_module() {
+                shared_types.extend(generated.types.iter().map(|t| SharedType {
+                    name: t.clone(),
+                    module: module.to_owned(),
+                    is_enum: generated.enums.contains(t),
+                }));
+            }
+        }
+        Ok(shared_types)
+    }
+    /// Generate the Rust bindings for the ISPC headers with the selected backend,
+    /// leaving out the types shared from Rust.
+    fn generate_bindings(
+        &self,
+        lib: &str,
+        headers: &[PathBuf],
+        shared_types: &[SharedType],
+    ) -> Result<String, IspcError> {
+        let bindgen_err = |message: String| IspcError::Bindgen {
+            lib: lib.to_owned(),
+            message,
+        };
+        match self.binding_backend {
+            #[cfg(feature = "bindgen")]
+            BindingBackend::Bindgen => {
+                // Generate a header we can give to bindgen and generate bindings
+                let bindgen_header = self.generate_bindgen_header(lib, headers)?;
+                let bindgen_header = bindgen_header.to_str().ok_or_else(|| {
+                    bindgen_err(format!(
+                        "the header path {} isn't valid UTF-8",
+                        bindgen_header.display()
+                    ))
+                })?;
+                let mut bindings = self.bindgen_builder.clone().header(bindgen_header);
+                for t in shared_types {
+                    bindings = bindings.blocklist_type(&t.name);
+                    // Bindgen writes the enum variants as constants prefixed with the name
+                    if t.is_enum {
+                        bindings = bindings.blocklist_item(format!("{}_.*", t.name));
+                    }
+                }
+                Ok(bindings
+                    .generate()
+                    .map_err(|e| bindgen_err(e.to_string()))?
+                    .to_string())
+            }
+            BindingBackend::Header => {
+                let skip = shared_types.iter().map(|t| t.name.clone()).collect();
+                header::generate_bindings(headers, &skip).map_err(bindgen_err)
+            }
+        }
     }
     /// Generate a single header that includes all of our ISPC headers which we can
     /// pass to bindgen
-    fn generate_bindgen_header(&self, lib: &str, headers: &[PathBuf]) -> PathBuf {
+    #[cfg(feature = "bindgen")]
+    fn generate_bindgen_header(
+        &self,
+        lib: &str,
+        headers: &[PathBuf],
+    ) -> Result<PathBuf, IspcError> {
         let bindgen_header = self
-            .get_build_dir()
+            .get_build_dir(lib)?
             .join(format!("_{lib}_ispc_bindgen_header.h"));
-        let mut include_file = File::create(&bindgen_header).unwrap();
+        let io_err = |e| IspcError::Io {
+            path: bindgen_header.clone(),
+            source: e,
+        };
+        let mut include_file = File::create(&bindgen_header).map_err(io_err)?;
 
-        writeln!(include_file, "#include <stdint.h>").unwrap();
-        writeln!(include_file, "#include <stdbool.h>").unwrap();
+        writeln!(include_file, "#include <stdint.h>").map_err(io_err)?;
+        writeln!(include_file, "#include <stdbool.h>").map_err(io_err)?;
 
         for h in headers {
-            writeln!(include_file, "#include \"{}\"", h.display()).unwrap();
+            writeln!(include_file, "#include \"{}\"", h.display()).map_err(io_err)?;
         }
-        bindgen_header
+        Ok(bindgen_header)
     }
     /// Build up list of basic args for each target, debug, opt level, etc.
-    fn default_args(&self) -> Vec<String> {
+    fn default_args(&self, opts: &TargetOptions) -> Result<Vec<String>, IspcError> {
         let mut ispc_args = Vec::new();
-        let opt_level = self.get_opt_level();
-        if self.get_debug() {
+        let opt_level = self.get_opt_level()?;
+        if self.get_debug()? {
             ispc_args.push(String::from("-g"));
         }
-        if let Some(ref c) = self.cpu_target {
+        if let Some(ref c) = opts.cpu {
             ispc_args.push(c.to_string());
             // The ispc compiler crashes if we give -O0 and --cpu=generic,
             // see https://github.com/ispc/ispc/issues/1223
@@ -491,35 +1636,28 @@ impl Config {
             ispc_args.push(format!("-O{opt_level}"));
         }
 
-        // If we're on Unix we need position independent code
-        if cfg!(unix) {
+        // Everything but Windows needs position independent code
+        let target = self.get_target()?;
+        if Target::parse(&target).pic {
             ispc_args.push(String::from("--pic"));
         }
-        let target = self.get_target();
-        if target.starts_with("i686") {
-            ispc_args.push(String::from("--arch=x86"));
-        } else if target.starts_with("x86_64") {
-            ispc_args.push(String::from("--arch=x86-64"));
-        } else if target.starts_with("aarch64") {
-            ispc_args.push(String::from("--arch=aarch64"));
-        }
-        for (name, value) in &self.defines {
+        for (name, value) in &opts.defines {
             match value {
                 Some(value) => ispc_args.push(format!("-D{name}={value}")),
                 None => ispc_args.push(format!("-D{name}")),
             }
         }
-        ispc_args.push(self.math_lib.to_string());
-        if let Some(ref s) = self.addressing {
+        ispc_args.push(opts.math_lib.to_string());
+        if let Some(ref s) = opts.addressing {
             ispc_args.push(s.to_string());
         }
         if let Some(ref f) = self.force_alignment {
             ispc_args.push(format!("--force-alignment={f}"));
         }
-        for o in &self.optimization_opts {
+        for o in &opts.optimization_opts {
             ispc_args.push(o.to_string());
         }
-        for p in &self.include_paths {
+        for p in &opts.include_paths {
             ispc_args.push(format!("-I{}", p.display()));
         }
         if self.no_omit_frame_ptr {
@@ -549,7 +1687,11 @@ impl Config {
         if self.enable_llvm_intrinsics {
             ispc_args.push(String::from("--enable-llvm-intrinsics"));
         }
-        if let Some(ref t) = self.target_isa {
+        // Exported functions must be marked for export from a DLL
+        if self.library_kind == LibraryKind::Shared && target.contains("windows") {
+            ispc_args.push(String::from("--dllexport"));
+        }
+        if let Some(ref t) = opts.target_isa {
             let mut isa_str = String::from("--target=");
             for (i, isa) in t.iter().enumerate() {
                 if i > 0 {
@@ -559,61 +1701,179 @@ impl Config {
                 let _ = write!(isa_str, "{isa}");
             }
             ispc_args.push(isa_str);
-        } else if target.starts_with("aarch64") {
-            // For arm we may need to override the default target ISA,
-            // e.g. on macOS with ISPC running in Rosetta, ISPC will default to
-            // SSE4, but we need NEON
-
-            ispc_args.push(String::from("--target=neon-i32x4"));
         }
-        if let Some(ref a) = self.architecture {
+        if let Some(ref a) = opts.architecture {
             ispc_args.push(a.to_string());
         }
-        if let Some(ref o) = self.target_os {
+        if let Some(ref o) = opts.target_os {
             ispc_args.push(o.to_string());
         }
         if let Some((maj, min)) = self.darwin_version_min {
             ispc_args.push(format!("--darwin-version-min={maj}.{min}"));
         }
-        ispc_args
+        Ok(ispc_args)
     }
     /// Returns the user-set output directory if they've set one, otherwise
-    /// returns env("OUT_DIR")
-    fn get_out_dir(&self) -> PathBuf {
-        let p = self
-            .out_dir
-            .clone()
-            .unwrap_or_else(|| env::var_os("OUT_DIR").map(PathBuf::from).unwrap());
+    /// returns env("OUT_DIR"), or an error if it isn't set
+    pub fn get_out_dir(&self) -> Result<PathBuf, IspcError> {
+        let p = match self.out_dir {
+            Some(ref p) => p.clone(),
+            None => PathBuf::from(build_var("OUT_DIR")?),
+        };
         if p.is_relative() {
-            env::current_dir().unwrap().join(p)
+            let cwd = env::current_dir().map_err(|e| IspcError::Io {
+                path: p.clone(),
+                source: e,
+            })?;
+            Ok(cwd.join(p))
         } else {
-            p
+            Ok(p)
         }
     }
-    /// Returns the default cargo output dir for build scripts (env("OUT_DIR"))
-    fn get_build_dir(&self) -> PathBuf {
-        env::var_os("OUT_DIR").map(PathBuf::from).unwrap()
+    /// Returns the directory the intermediate files for `lib` are written to,
+    /// a subdirectory of the user-set build dir or of the default cargo output
+    /// dir for build scripts (env("OUT_DIR")) so that multiple libraries don't
+    /// overwrite each other
+    fn get_build_dir(&self, lib: &str) -> Result<PathBuf, IspcError> {
+        let dir = match self.build_dir {
+            Some(ref d) => d.clone(),
+            None => PathBuf::from(build_var("OUT_DIR")?).join("ispc"),
+        };
+        Ok(dir.join(lib))
     }
     /// Returns the user-set debug flag if they've set one, otherwise returns
     /// env("DEBUG")
-    fn get_debug(&self) -> bool {
-        self.debug
-            .unwrap_or_else(|| env::var("DEBUG").unwrap() == "true")
+    fn get_debug(&self) -> Result<bool, IspcError> {
+        match self.debug {
+            Some(d) => Ok(d),
+            None => Ok(build_var("DEBUG")? == "true"),
+        }
     }
     /// Returns the user-set optimization level if they've set one, otherwise
-    /// returns env("OPT_LEVEL")
-    fn get_opt_level(&self) -> u32 {
-        self.opt_level.unwrap_or_else(|| {
-            let opt = env::var("OPT_LEVEL").unwrap();
-            opt.parse::<u32>().unwrap()
-        })
+    /// returns env("OPT_LEVEL"). Cargo's size optimization levels `s` and `z`
+    /// map to ISPC's `-O1`, which optimizes for size.
+    fn get_opt_level(&self) -> Result<u32, IspcError> {
+        if let Some(opt) = self.opt_level {
+            return Ok(opt);
+        }
+        let opt = build_var("OPT_LEVEL")?;
+        match opt.as_str() {
+            "s" | "z" => Ok(1),
+            _ => opt.parse().map_err(|_| IspcError::InvalidEnvVar {
+                var: String::from("OPT_LEVEL"),
+                source: ParseOptionError {
+                    kind: "optimization level",
+                    value: opt.clone(),
+                },
+            }),
+        }
     }
     /// Returns the user-set target triple if they're set one, otherwise
     /// returns env("TARGET")
-    fn get_target(&self) -> String {
-        self.target
-            .clone()
-            .unwrap_or_else(|| env::var("TARGET").unwrap())
+    fn get_target(&self) -> Result<String, IspcError> {
+        match self.target {
+            Some(ref t) => Ok(t.clone()),
+            None => build_var("TARGET"),
+        }
+    }
+    /// Whether the bindgen builder filters the bindings, so they may leave out
+    /// some of the functions exported by the library
+    fn bindings_filtered(&self) -> bool {
+        #[cfg(feature = "bindgen")]
+        if self.binding_backend == BindingBackend::Bindgen {
+            return self.bindgen_builder.command_line_flags().iter().any(|f| {
+                f.starts_with("--allowlist-")
+                    || [
+                        "--blocklist-function",
+                        "--blocklist-item",
+                        "--blocklist-var",
+                    ]
+                    .contains(&f.as_str())
+            });
+        }
+        false
+    }
+    /// Returns the target and code generation options set on the config,
+    /// overridden by any set in the `ISPC_RS_*` environment variables
+    fn target_options(&self) -> Result<TargetOptions, IspcError> {
+        let mut optimization_opts = self.optimization_opts.clone();
+        optimization_opts.extend(
+            self.env_option_list::<OptimizationOpt>("ISPC_RS_OPT")?
+                .unwrap_or_default(),
+        );
+        // Options which aren't set are filled in from the target triple
+        let target = Target::parse(&self.get_target()?);
+        let host = env::var("HOST")
+            .map(|h| Target::parse(&h))
+            .unwrap_or(target);
+        let cpu = self.env_option("ISPC_RS_CPU")?.or(self.cpu_target);
+        let architecture = self
+            .env_option("ISPC_RS_ARCH")?
+            .or(self.architecture)
+            .or(target.arch);
+        let target_isa = self
+            .env_option_list("ISPC_RS_TARGETS")?
+            .or_else(|| self.target_isa.clone())
+            .or_else(|| {
+                // The ISA for the triple doesn't apply if targeting another architecture,
+                // and on x86 the CPU selects the ISA
+                let isa = target.default_isa(&host)?;
+                let is_neon = isa == TargetISA::Neoni32x4;
+                (architecture == target.arch && (is_neon || cpu.is_none())).then(|| vec![isa])
+            });
+        Ok(TargetOptions {
+            defines: self.defines.clone(),
+            include_paths: self.include_paths.clone(),
+            target_isa,
+            cpu,
+            architecture,
+            target_os: self
+                .env_option("ISPC_RS_TARGET_OS")?
+                .or(self.target_os)
+                .or(target.os),
+            math_lib: self
+                .env_option("ISPC_RS_MATH_LIB")?
+                .unwrap_or(self.math_lib),
+            addressing: self.env_option("ISPC_RS_ADDRESSING")?.or(self.addressing),
+            optimization_opts,
+        })
+    }
+    /// Parse the option set in the environment variable `var`, if any
+    fn env_option<T>(&self, var: &str) -> Result<Option<T>, IspcError>
+    where
+        T: FromStr<Err = ParseOptionError>,
+    {
+        self.print(&format!("cargo:rerun-if-env-changed={var}"));
+        match env::var(var) {
+            Ok(v) if !v.trim().is_empty() => {
+                v.parse().map(Some).map_err(|e| IspcError::InvalidEnvVar {
+                    var: var.to_owned(),
+                    source: e,
+                })
+            }
+            _ => Ok(None),
+        }
+    }
+    /// Parse the comma separated list of options set in the environment
+    /// variable `var`, if any
+    fn env_option_list<T>(&self, var: &str) -> Result<Option<Vec<T>>, IspcError>
+    where
+        T: FromStr<Err = ParseOptionError>,
+    {
+        self.print(&format!("cargo:rerun-if-env-changed={var}"));
+        match env::var(var) {
+            Ok(v) if !v.trim().is_empty() => v
+                .split(',')
+                .filter(|o| !o.trim().is_empty())
+                .map(|o| o.parse())
+                .collect::<Result<Vec<_>, _>>()
+                .map(Some)
+                .map_err(|e| IspcError::InvalidEnvVar {
+                    var: var.to_owned(),
+                    source: e,
+                }),
+            _ => Ok(None),
+        }
     }
     /// Print out cargo metadata if enabled
     fn print<T: Display>(&self, s: &T) {
@@ -628,3 +1888,103 @@ impl Default for Config {
         Config::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    #[test]
+    fn lists_only_isa_outputs() {
+        let dir = test_dir("isa-outputs");
+        let isas = [TargetISA::SSE42i32x4, TargetISA::AVX2i32x8];
+        let version = Version::new(1, 22, 0);
+        let job = SourceJob::new(
+            &dir.join("foo.ispc"),
+            &dir,
+            String::from("foo_ispc"),
+            &isas,
+            &BTreeSet::new(),
+            &version,
+        );
+        // The outputs of foo_ispc.ispc and a previous build for AVX-512
+        for f in [
+            "foo_ispc.o",
+            "foo_ispc_sse42.o",
+            "foo_ispc_avx2.o",
+            "foo_ispc_avx512skx.o",
+            "foo_ispc_ispc.o",
+            "foo_ispc_ispc_avx2.o",
+            "foo_ispc_avx2.h",
+        ] {
+            fs::write(dir.join(f), "").unwrap();
+        }
+        let names = |files: Vec<PathBuf>| -> Vec<String> {
+            files
+                .iter()
+                .map(|f| f.file_name().unwrap().to_string_lossy().into_owned())
+                .collect()
+        };
+        assert_eq!(
+            names(job.isa_outputs(&dir, "o").unwrap()),
+            ["foo_ispc_avx2.o", "foo_ispc_sse42.o"]
+        );
+
+        job.remove_isa_outputs().unwrap();
+        let mut left = names(
+            fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect(),
+        );
+        left.sort();
+        assert_eq!(
+            left,
+            [
+                "foo_ispc.o",
+                "foo_ispc_avx2.h",
+                "foo_ispc_ispc.o",
+                "foo_ispc_ispc_avx2.o"
+            ]
+        );
+        assert!(matches!(
+            job.isa_outputs(&dir, "o"),
+            Err(IspcError::MissingIsaOutputs { .. })
+        ));
+    }
+
+    #[test]
+    fn lists_targets_for_each_arch() {
+        let packaged = BTreeMap::from([
+            (
+                String::from("aarch64-apple-darwin"),
+                vec![String::from("neon-i32x4")],
+            ),
+            (
+                String::from("x86_64-apple-darwin"),
+                vec![String::from("sse4.2-i32x4"), String::from("avx2-i32x8")],
+            ),
+            (
+                String::from("x86_64-unknown-linux-gnu"),
+                vec![String::from("avx512skx-x16")],
+            ),
+        ]);
+        let text = targets_file_contents("x86_64-unknown-linux-gnu", &packaged);
+        assert!(text.contains(
+            "#[cfg(target_arch = \"aarch64\")]\npub const ISPC_TARGETS: &[&str] = &[\"neon-i32x4\"];"
+        ));
+        // The target being built is listed for its architecture
+        assert!(text.contains(
+            "#[cfg(target_arch = \"x86_64\")]\npub const ISPC_TARGETS: &[&str] = &[\"avx512skx-x16\"];"
+        ));
+        assert!(!text.contains("avx2-i32x8"));
+
+        let single = BTreeMap::from([(
+            String::from("x86_64-unknown-linux-gnu"),
+            vec![String::from("avx2-i32x8")],
+        )]);
+        let text = targets_file_contents("x86_64-unknown-linux-gnu", &single);
+        assert!(!text.contains("cfg"));
+        assert!(text.contains("&[\"avx2-i32x8\"]"));
+    }
+}
@@ -0,0 +1,218 @@
+//! The package manifest written next to the compiled library and bindings,
+//! which `ispc_rt::PackagedModule` reads to check the library was packaged for
+//! the target being built and hasn't been modified since.
+//!
+//! The manifest `<lib>.manifest.json` holds an entry for each target triple
+//! the library has been compiled for with the same output directory, so
+//! libraries for several triples can be packaged together. See the
+//! `ispc_rt::package` module for the format.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::error::IspcError;
+use crate::fingerprint::to_hex;
+use crate::LibraryKind;
+
+/// The version of the manifest format, which is increased when changes are
+/// made that `ispc_rt` versions reading the older format can't handle.
+const FORMAT_VERSION: u64 = 1;
+
+/// What was compiled for one target triple.
+pub(crate) struct TargetEntry<'a> {
+    pub library: &'a Path,
+    pub kind: LibraryKind,
+    pub bindings: &'a Path,
+    /// Whether the bindings were filtered to leave out some of the functions
+    /// the library exports
+    pub bindings_filtered: bool,
+    pub isas: Vec<String>,
+    pub ispc_version: String,
+    /// The source files compiled and the ISPC arguments used for each
+    pub files: Vec<(PathBuf, Vec<String>)>,
+}
+
+/// The path of the manifest for `lib` in the directory `dir`.
+pub(crate) fn path(dir: &Path, lib: &str) -> PathBuf {
+    dir.join(format!("{lib}.manifest.json"))
+}
+
+/// Add or replace the entry for `target` in the manifest of `lib`, keeping the
+/// entries for the other targets previously compiled to the same directory.
+pub(crate) fn update(
+    dir: &Path,
+    lib: &str,
+    target: &str,
+    entry: &TargetEntry,
+) -> Result<PathBuf, IspcError> {
+    let manifest_path = path(dir, lib);
+    let mut targets = read_targets(&manifest_path, lib);
+
+    let files: Vec<_> = entry
+        .files
+        .iter()
+        .map(|(source, args)| json!({ "source": source.display().to_string(), "args": args }))
+        .collect();
+    targets.insert(
+        target.to_owned(),
+        json!({
+            "library": file_name(entry.library),
+            "kind": match entry.kind {
+                LibraryKind::Static => "static",
+                LibraryKind::Shared => "shared",
+            },
+            "library_sha256": hash_file(entry.library)?,
+            "bindings": file_name(entry.bindings),
+            "bindings_sha256": hash_file(entry.bindings)?,
+            "bindings_filtered": entry.bindings_filtered,
+            "isas": entry.isas,
+            "ispc_version": entry.ispc_version,
+            "files": files,
+        }),
+    );
+    let mut manifest = Map::new();
+    manifest.insert(String::from("format"), json!(FORMAT_VERSION));
+    manifest.insert(String::from("lib"), json!(lib));
+    manifest.insert(String::from("targets"), Value::Object(targets));
+
+    let text = serde_json::to_string_pretty(&Value::Object(manifest))
+        .expect("the manifest is always valid JSON");
+    fs::write(&manifest_path, text + "\n").map_err(|e| IspcError::Io {
+        path: manifest_path.clone(),
+        source: e,
+    })?;
+    Ok(manifest_path)
+}
+
+/// The ISAs listed for each target triple in the manifest of `lib` in `dir`,
+/// which is empty if there's no manifest yet.
+pub(crate) fn packaged_isas(dir: &Path, lib: &str) -> BTreeMap<String, Vec<String>> {
+    read_targets(&path(dir, lib), lib)
+        .into_iter()
+        .map(|(target, entry)| {
+            let isas = entry["isas"]
+                .as_array()
+                .map(|a| {
+                    a.iter()
+                        .filter_map(|i| i.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default();
+            (target, isas)
+        })
+        .collect()
+}
+
+/// The targets listed in an existing manifest, a manifest in an older format
+/// or for another library is replaced.
+fn read_targets(manifest_path: &Path, lib: &str) -> Map<String, Value> {
+    fs::read_to_string(manifest_path)
+        .ok()
+        .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+        .filter(|m| m["format"] == FORMAT_VERSION && m["lib"] == lib)
+        .and_then(|mut m| match m["targets"].take() {
+            Value::Object(targets) => Some(targets),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+/// Compute the SHA-256 of a file as a hex string.
+pub(crate) fn hash_file(path: &Path) -> Result<String, IspcError> {
+    let data = fs::read(path).map_err(|e| IspcError::Io {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    Ok(to_hex(&Sha256::digest(data)))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    fn update_target(dir: &Path, lib: &str, target: &str, isas: &[&str]) -> Value {
+        let library = dir.join(format!("lib{lib}{target}.a"));
+        let bindings = dir.join(format!("{lib}.rs"));
+        fs::write(&library, target).unwrap();
+        fs::write(&bindings, "bindings").unwrap();
+        let entry = TargetEntry {
+            library: &library,
+            kind: LibraryKind::Static,
+            bindings: &bindings,
+            bindings_filtered: false,
+            isas: isas.iter().map(|i| i.to_string()).collect(),
+            ispc_version: String::from("1.25.3"),
+            files: vec![(PathBuf::from("src/simple.ispc"), vec![String::from("-O2")])],
+        };
+        let path = update(dir, lib, target, &entry).unwrap();
+        serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn merges_targets() {
+        let dir = test_dir("manifest-merge");
+        update_target(&dir, "simple", "x86_64-unknown-linux-gnu", &["sse2-i32x4"]);
+        update_target(&dir, "simple", "aarch64-apple-darwin", &["neon-i32x4"]);
+        // Rebuilding a target replaces its entry
+        let manifest = update_target(
+            &dir,
+            "simple",
+            "x86_64-unknown-linux-gnu",
+            &["sse2-i32x4", "avx2-i32x8"],
+        );
+        assert_eq!(manifest["format"], FORMAT_VERSION);
+        assert_eq!(manifest["lib"], "simple");
+        let linux = &manifest["targets"]["x86_64-unknown-linux-gnu"];
+        assert_eq!(linux["library"], "libsimplex86_64-unknown-linux-gnu.a");
+        assert_eq!(linux["kind"], "static");
+        assert_eq!(
+            linux["library_sha256"],
+            to_hex(&Sha256::digest("x86_64-unknown-linux-gnu"))
+        );
+        assert_eq!(linux["bindings"], "simple.rs");
+        assert_eq!(linux["files"][0]["source"], "src/simple.ispc");
+        assert_eq!(linux["files"][0]["args"], json!(["-O2"]));
+
+        assert_eq!(
+            packaged_isas(&dir, "simple"),
+            BTreeMap::from([
+                (
+                    String::from("aarch64-apple-darwin"),
+                    vec![String::from("neon-i32x4")]
+                ),
+                (
+                    String::from("x86_64-unknown-linux-gnu"),
+                    vec![String::from("sse2-i32x4"), String::from("avx2-i32x8")]
+                ),
+            ])
+        );
+        assert!(packaged_isas(&dir, "other").is_empty());
+    }
+
+    #[test]
+    fn replaces_unreadable_manifests() {
+        let dir = test_dir("manifest-replace");
+        let path = path(&dir, "simple");
+        for old in [
+            r#"{ "format": 0, "lib": "simple", "targets": { "old-target": {} } }"#,
+            r#"{ "format": 1, "lib": "other", "targets": { "old-target": {} } }"#,
+            "not json",
+        ] {
+            fs::write(&path, old).unwrap();
+            let manifest = update_target(&dir, "simple", "x86_64-unknown-linux-gnu", &[]);
+            let targets: Vec<_> = manifest["targets"].as_object().unwrap().keys().collect();
+            assert_eq!(targets, ["x86_64-unknown-linux-gnu"], "{old}");
+        }
+    }
+}
@@ -0,0 +1,436 @@
+//! Building the libraries described by the `[package.metadata.ispc]` table of
+//! a crate's `Cargo.toml`, so a build script doesn't have to list the files and
+//! options itself. See `build_from_manifest` for the format of the table.
+//!
+//! The options set at the top level of the table apply to every library, and
+//! the options set for a library are merged over them in the same way as
+//! `FileOptions`: lists are added to those of the package, other options
+//! replace the package's setting.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use toml::{Table, Value};
+
+use crate::error::IspcError;
+use crate::opt::{Addressing, MathLib, OptimizationOpt, TargetISA, CPU};
+use crate::target;
+use crate::Config;
+
+/// Read the libraries listed in the `[package.metadata.ispc]` table of the
+/// manifest `cargo_toml`, returning the name of each and the configuration to
+/// build it with, which starts from the options set on `base`.
+pub(crate) fn libraries(
+    base: &Config,
+    cargo_toml: &Path,
+) -> Result<Vec<(String, Config)>, IspcError> {
+    let invalid = |message: String| IspcError::InvalidMetadata {
+        manifest: cargo_toml.to_path_buf(),
+        message,
+    };
+    let text = fs::read_to_string(cargo_toml).map_err(|e| IspcError::Io {
+        path: cargo_toml.to_path_buf(),
+        source: e,
+    })?;
+    let manifest: Table = text
+        .parse()
+        .map_err(|e: toml::de::Error| invalid(e.to_string()))?;
+    let metadata = manifest
+        .get("package")
+        .and_then(|p| p.get("metadata"))
+        .and_then(|m| m.get("ispc"))
+        .ok_or_else(|| invalid(String::from("there is no [package.metadata.ispc] table")))?
+        .as_table()
+        .ok_or_else(|| invalid(String::from("package.metadata.ispc must be a table")))?;
+    let libraries = metadata
+        .get("libraries")
+        .and_then(Value::as_table)
+        .filter(|l| !l.is_empty())
+        .ok_or_else(|| invalid(String::from("no libraries are listed in libraries")))?;
+
+    let crate_dir = cargo_toml.parent().unwrap_or(Path::new("."));
+    let arch = target::rust_arch(&base.get_target()?);
+    let mut package = base.clone();
+    package.print(&format!("cargo:rerun-if-changed={}", cargo_toml.display()));
+    apply(&mut package, metadata, crate_dir, &arch, &["libraries"])
+        .map_err(|e| invalid(format!("package.metadata.ispc: {e}")))?;
+
+    let mut configs = Vec::new();
+    for (lib, options) in libraries {
+        let err = |e: String| invalid(format!("libraries.{lib}: {e}"));
+        let options = options
+            .as_table()
+            .ok_or_else(|| err(String::from("must be a table")))?;
+        let mut cfg = package.clone();
+        apply(&mut cfg, options, crate_dir, &arch, &["files"]).map_err(err)?;
+        let patterns = strings(
+            options
+                .get("files")
+                .ok_or_else(|| err(String::from("no files are listed")))?,
+        )
+        .map_err(|e| err(format!("files: {e}")))?;
+        for pattern in &patterns {
+            for file in
+                find_files(&cfg, crate_dir, pattern).map_err(|e| err(format!("files: {e}")))?
+            {
+                cfg.file(file);
+            }
+        }
+        configs.push((lib.clone(), cfg));
+    }
+    Ok(configs)
+}
+
+/// Set the options in `table` on the config. Keys in `skip` are read by the
+/// caller, any other unknown keys are an error to catch misspelled options.
+fn apply(
+    cfg: &mut Config,
+    table: &Table,
+    crate_dir: &Path,
+    arch: &str,
+    skip: &[&str],
+) -> Result<(), String> {
+    for (key, value) in table {
+        let with_key = |e: String| format!("{key}: {e}");
+        match key.as_str() {
+            k if skip.contains(&k) => {}
+            "out-dir" => {
+                cfg.out_dir(crate_dir.join(string(value).map_err(with_key)?));
+            }
+            "include-paths" => {
+                for p in strings(value).map_err(with_key)? {
+                    cfg.include_path(crate_dir.join(p));
+                }
+            }
+            "defines" => {
+                let defines = value
+                    .as_table()
+                    .ok_or_else(|| with_key(String::from("expected a table")))?;
+                for (name, value) in defines {
+                    match value {
+                        Value::Boolean(true) => {
+                            cfg.add_define(name, None);
+                        }
+                        Value::Boolean(false) => {}
+                        Value::String(s) => {
+                            cfg.add_define(name, Some(s));
+                        }
+                        Value::Integer(_) | Value::Float(_) => {
+                            cfg.add_define(name, Some(&value.to_string()));
+                        }
+                        _ => {
+                            return Err(format!(
+                                "{key}.{name}: expected a string, number or boolean"
+                            ))
+                        }
+                    }
+                }
+            }
+            "target-isas" => {
+                // Either a list for every architecture, or a table of lists
+                // keyed by the Rust name of the architecture
+                let isas = match value {
+                    Value::Table(by_arch) => by_arch.get(arch),
+                    _ => Some(value),
+                };
+                if let Some(isas) = isas {
+                    cfg.target_isas(parse_list::<TargetISA>(isas).map_err(with_key)?);
+                }
+            }
+            "math-lib" => {
+                cfg.math_lib(parse::<MathLib>(value).map_err(with_key)?);
+            }
+            "addressing" => {
+                // Allow writing the number of bits without quotes
+                let bits = match value {
+                    Value::Integer(i) => Value::String(i.to_string()),
+                    v => v.clone(),
+                };
+                cfg.addressing(parse::<Addressing>(&bits).map_err(with_key)?);
+            }
+            "cpu" => {
+                cfg.cpu(parse::<CPU>(value).map_err(with_key)?);
+            }
+            "opt" => {
+                for opt in parse_list::<OptimizationOpt>(value).map_err(with_key)? {
+                    cfg.optimization_opt(opt);
+                }
+            }
+            "allowlist-functions" | "allowlist-types" | "allowlist-vars" => {
+                allowlist(cfg, key, &strings(value).map_err(with_key)?).map_err(with_key)?;
+            }
+            _ => return Err(format!("unknown key `{key}`")),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "bindgen")]
+fn allowlist(cfg: &mut Config, key: &str, patterns: &[String]) -> Result<(), String> {
+    let mut builder = cfg.bindgen_builder.clone();
+    for p in patterns {
+        builder = match key {
+            "allowlist-functions" => builder.allowlist_function(p),
+            "allowlist-types" => builder.allowlist_type(p),
+            _ => builder.allowlist_var(p),
+        };
+    }
+    cfg.bindgen_builder(builder);
+    Ok(())
+}
+
+#[cfg(not(feature = "bindgen"))]
+fn allowlist(_: &mut Config, _: &str, _: &[String]) -> Result<(), String> {
+    Err(String::from(
+        "the bindings can only be filtered with the bindgen feature of ispc_compile",
+    ))
+}
+
+/// Find the files matching the glob `pattern`, relative to the crate directory.
+fn find_files(cfg: &Config, crate_dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let full = crate_dir.join(pattern);
+    let paths = glob::glob(&full.to_string_lossy()).map_err(|e| format!("`{pattern}`: {e}"))?;
+    let mut files = Vec::new();
+    for p in paths {
+        let p = p.map_err(|e| e.to_string())?;
+        if p.is_file() {
+            files.push(relative_to_cwd(crate_dir, p));
+        }
+    }
+    if files.is_empty() {
+        return Err(format!("`{pattern}` didn't match any files"));
+    }
+    // Rerun if files are added to the directory the pattern searches
+    let is_glob = |s: &str| s.contains(['*', '?', '[']);
+    if is_glob(pattern) {
+        let dir: PathBuf = Path::new(pattern)
+            .components()
+            .take_while(|c| !is_glob(&c.as_os_str().to_string_lossy()))
+            .collect();
+        cfg.print(&format!(
+            "cargo:rerun-if-changed={}",
+            crate_dir.join(dir).display()
+        ));
+    }
+    Ok(files)
+}
+
+/// Build scripts run in the crate directory, so keep the paths relative to it
+/// when possible as they're recorded in the package manifest.
+fn relative_to_cwd(crate_dir: &Path, path: PathBuf) -> PathBuf {
+    let in_crate_dir = env::current_dir()
+        .and_then(|d| Ok(d.canonicalize()? == crate_dir.canonicalize()?))
+        .unwrap_or(false);
+    match path.strip_prefix(crate_dir) {
+        Ok(p) if in_crate_dir => p.to_path_buf(),
+        _ => path,
+    }
+}
+
+fn string(value: &Value) -> Result<&str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| String::from("expected a string"))
+}
+
+fn strings(value: &Value) -> Result<Vec<String>, String> {
+    value
+        .as_array()
+        .and_then(|a| a.iter().map(|s| s.as_str().map(String::from)).collect())
+        .ok_or_else(|| String::from("expected a list of strings"))
+}
+
+fn parse<T: FromStr>(value: &Value) -> Result<T, String>
+where
+    T::Err: ToString,
+{
+    string(value)?.parse().map_err(|e: T::Err| e.to_string())
+}
+
+fn parse_list<T: FromStr>(value: &Value) -> Result<Vec<T>, String>
+where
+    T::Err: ToString,
+{
+    strings(value)?
+        .iter()
+        .map(|s| s.parse().map_err(|e: T::Err| e.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_dir;
+
+    fn config() -> Config {
+        let mut cfg = Config::new();
+        cfg.cargo_metadata(false).target("x86_64-unknown-linux-gnu");
+        cfg
+    }
+
+    fn table(text: &str) -> Table {
+        text.parse().unwrap()
+    }
+
+    fn apply_str(cfg: &mut Config, text: &str, arch: &str) -> Result<(), String> {
+        apply(cfg, &table(text), Path::new("/crate"), arch, &["files"])
+    }
+
+    #[test]
+    fn applies_options() {
+        let mut cfg = config();
+        apply_str(
+            &mut cfg,
+            r#"
+out-dir = "src/"
+include-paths = ["include"]
+defines = { USE_FOO = true, OFF = false, WIDTH = 4, NAME = "foo" }
+math-lib = "fast"
+addressing = 64
+cpu = "skylake"
+opt = ["fast-math", "disable-assertions"]
+files = ["ignored"]
+"#,
+            "x86_64",
+        )
+        .unwrap();
+        assert_eq!(cfg.out_dir, Some(PathBuf::from("/crate/src/")));
+        assert_eq!(cfg.include_paths, [PathBuf::from("/crate/include")]);
+        let mut defines = cfg.defines.clone();
+        defines.sort();
+        assert_eq!(
+            defines,
+            [
+                (String::from("NAME"), Some(String::from("foo"))),
+                (String::from("USE_FOO"), None),
+                (String::from("WIDTH"), Some(String::from("4"))),
+            ]
+        );
+        assert_eq!(cfg.math_lib, MathLib::Fast);
+        assert_eq!(cfg.addressing, Some(Addressing::A64));
+        assert_eq!(cfg.cpu_target, Some(CPU::Skylake));
+        assert_eq!(
+            cfg.optimization_opts,
+            [
+                OptimizationOpt::FastMath,
+                OptimizationOpt::DisableAssertions
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn target_isas_by_arch() {
+        let by_arch =
+            r#"target-isas = { x86_64 = ["sse2-i32x4", "avx2-i32x8"], aarch64 = ["neon-i32x4"] }"#;
+        let mut cfg = config();
+        apply_str(&mut cfg, by_arch, "x86_64").unwrap();
+        assert_eq!(
+            cfg.target_isa,
+            Some(vec![TargetISA::SSE2i32x4, TargetISA::AVX2i32x8])
+        );
+        apply_str(&mut cfg, by_arch, "aarch64").unwrap();
+        assert_eq!(cfg.target_isa, Some(vec![TargetISA::Neoni32x4]));
+        // Architectures which aren't listed keep ISPC's default
+        let mut cfg = config();
+        apply_str(&mut cfg, by_arch, "arm").unwrap();
+        assert_eq!(cfg.target_isa, None);
+
+        apply_str(&mut cfg, r#"target-isas = ["avx512skx-x16"]"#, "arm").unwrap();
+        assert_eq!(cfg.target_isa, Some(vec![TargetISA::AVX512SKXx16]));
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        for (text, message) in [
+            ("outdir = \"src\"", "unknown key `outdir`"),
+            ("libraries = {}", "unknown key `libraries`"),
+            ("math-lib = \"fastest\"", "math-lib: "),
+            ("addressing = 48", "addressing: "),
+            ("cpu = 1", "cpu: expected a string"),
+            ("opt = [\"fast-math\", \"slow-math\"]", "opt: "),
+            ("target-isas = [\"avx3-i32x8\"]", "target-isas: "),
+            (
+                "target-isas = { x86_64 = \"avx2-i32x8\" }",
+                "target-isas: expected a list of strings",
+            ),
+            (
+                "include-paths = \"include\"",
+                "include-paths: expected a list",
+            ),
+            ("defines = { A = [1] }", "defines.A: expected a string"),
+            ("defines = [\"A\"]", "defines: expected a table"),
+        ] {
+            let e = apply_str(&mut config(), text, "x86_64").unwrap_err();
+            assert!(e.starts_with(message), "{text}: {e}");
+        }
+        // The invalid value is named in the error
+        let e = apply_str(&mut config(), "math-lib = \"fastest\"", "x86_64").unwrap_err();
+        assert!(e.contains("fastest"), "{e}");
+    }
+
+    #[test]
+    fn expands_globs() {
+        let dir = test_dir("metadata-globs");
+        for f in [
+            "src/simple.ispc",
+            "src/kernels/a.ispc",
+            "src/kernels/b.ispc",
+            "src/kernels/notes.txt",
+        ] {
+            let path = dir.join(f);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let manifest = dir.join("Cargo.toml");
+        fs::write(
+            &manifest,
+            r#"
+[package]
+name = "globs"
+
+[package.metadata.ispc]
+math-lib = "fast"
+
+[package.metadata.ispc.libraries.simple]
+files = ["src/simple.ispc", "src/kernels/*.ispc"]
+math-lib = "default"
+
+[package.metadata.ispc.libraries.missing]
+files = ["src/other/*.ispc"]
+"#,
+        )
+        .unwrap();
+        match libraries(&config(), &manifest) {
+            Err(IspcError::InvalidMetadata { message, .. }) => assert_eq!(
+                message,
+                "libraries.missing: files: `src/other/*.ispc` didn't match any files"
+            ),
+            r => panic!("expected an invalid metadata error, got {:?}", r.err()),
+        }
+
+        let text = fs::read_to_string(&manifest).unwrap();
+        let end = text
+            .find("[package.metadata.ispc.libraries.missing]")
+            .unwrap();
+        fs::write(&manifest, &text[..end]).unwrap();
+        let libs = libraries(&config(), &manifest).unwrap();
+        assert_eq!(libs.len(), 1);
+        let (name, cfg) = &libs[0];
+        assert_eq!(name, "simple");
+        let files: Vec<_> = cfg.ispc_files.iter().map(|(f, _)| f.clone()).collect();
+        assert_eq!(
+            files,
+            [
+                dir.join("src/simple.ispc"),
+                dir.join("src/kernels/a.ispc"),
+                dir.join("src/kernels/b.ispc"),
+            ]
+        );
+        // The library's options replace the package's
+        assert_eq!(cfg.math_lib, MathLib::ISPCDefault);
+    }
+}
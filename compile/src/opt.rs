@@ -1,7 +1,47 @@
 //! This module has various option flags and configs we can pass to ISPC,
 //! located here for convience and clutter reduction.
+//!
+//! Each option can also be parsed from the name ISPC uses for it, with or
+//! without the flag, e.g. both `"skx"` and `"--cpu=skx"` parse to `CPU::Skx`.
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use semver::Version;
+
+/// Error returned when parsing an option from a string fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOptionError {
+    /// The kind of option being parsed, e.g. `"target ISA"`.
+    pub kind: &'static str,
+    /// The string which couldn't be parsed.
+    pub value: String,
+}
+
+impl fmt::Display for ParseOptionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown {} '{}'", self.kind, self.value)
+    }
+}
+
+impl Error for ParseOptionError {}
+
+/// Normalize an option name for matching, removing the `flag` prefix if present.
+fn option_name(s: &str, flag: &str) -> String {
+    let s = s.trim();
+    s.strip_prefix(flag).unwrap_or(s).to_ascii_lowercase()
+}
+
+fn parse_error(kind: &'static str, value: &str) -> ParseOptionError {
+    ParseOptionError {
+        kind,
+        value: value.to_owned(),
+    }
+}
 
 /// Different math libraries that ISPC can use for computations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MathLib {
     /// Use ispc's built-in math functions (the default).
     ISPCDefault,
@@ -24,7 +64,22 @@ impl std::fmt::Display for MathLib {
     }
 }
 
+impl FromStr for MathLib {
+    type Err = ParseOptionError;
+
+    fn from_str(s: &str) -> Result<MathLib, ParseOptionError> {
+        match option_name(s, "--math-lib=").as_str() {
+            "default" => Ok(MathLib::ISPCDefault),
+            "fast" => Ok(MathLib::Fast),
+            "svml" => Ok(MathLib::SVML),
+            "system" => Ok(MathLib::System),
+            _ => Err(parse_error("math library", s)),
+        }
+    }
+}
+
 /// Select the target CPU architecture
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Architecture {
     Arm,
     Aarch64,
@@ -45,9 +100,25 @@ impl std::fmt::Display for Architecture {
     }
 }
 
+impl FromStr for Architecture {
+    type Err = ParseOptionError;
+
+    fn from_str(s: &str) -> Result<Architecture, ParseOptionError> {
+        match option_name(s, "--arch=").as_str() {
+            "arm" => Ok(Architecture::Arm),
+            "aarch64" => Ok(Architecture::Aarch64),
+            "x86" => Ok(Architecture::X86),
+            "x86_64" | "x86-64" => Ok(Architecture::X64),
+            "xe64" => Ok(Architecture::Xe64),
+            _ => Err(parse_error("architecture", s)),
+        }
+    }
+}
+
 /// Select 32 or 64 bit addressing to be used by ISPC. Note: 32-bit
 /// addressing calculations are done by default, even on 64 bit target
 /// architectures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Addressing {
     /// Select 32 bit addressing calculations.
     A32,
@@ -64,8 +135,20 @@ impl std::fmt::Display for Addressing {
     }
 }
 
+impl FromStr for Addressing {
+    type Err = ParseOptionError;
+
+    fn from_str(s: &str) -> Result<Addressing, ParseOptionError> {
+        match option_name(s, "--addressing=").as_str() {
+            "32" => Ok(Addressing::A32),
+            "64" => Ok(Addressing::A64),
+            _ => Err(parse_error("addressing mode", s)),
+        }
+    }
+}
+
 /// ISPC target CPU ISA options. If none is set, ISPC will target the machine being compiled on.
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum CPU {
     Generic,
     X8664,
@@ -187,8 +270,65 @@ impl std::fmt::Display for CPU {
     }
 }
 
+impl FromStr for CPU {
+    type Err = ParseOptionError;
+
+    fn from_str(s: &str) -> Result<CPU, ParseOptionError> {
+        match option_name(s, "--cpu=").as_str() {
+            "generic" => Ok(CPU::Generic),
+            "x86-64" => Ok(CPU::X8664),
+            "bonnell" | "atom" => Ok(CPU::Bonnell),
+            "core2" => Ok(CPU::Core2),
+            "penryn" => Ok(CPU::Penryn),
+            "nehalem" | "corei7" => Ok(CPU::Nehalem),
+            "ps4" | "btver2" => Ok(CPU::Ps4),
+            "sandybridge" | "corei7-avx" => Ok(CPU::SandyBridge),
+            "ivybridge" | "core-avx-i" => Ok(CPU::IvyBridge),
+            "haswell" | "core-avx2" => Ok(CPU::Haswell),
+            "broadwell" => Ok(CPU::Broadwell),
+            "skylake" => Ok(CPU::Skylake),
+            "knl" => Ok(CPU::Knl),
+            "skx" => Ok(CPU::Skx),
+            "icl" | "icelake-client" => Ok(CPU::Icl),
+            "silvermont" | "slm" => Ok(CPU::Silvermont),
+            "icx" | "icelake-server" => Ok(CPU::Icx),
+            "tgl" | "tigerlake" => Ok(CPU::Tgl),
+            "adl" | "alderlake" => Ok(CPU::Adl),
+            "mtl" | "meteorlake" => Ok(CPU::Mtl),
+            "spr" | "sapphirerapids" => Ok(CPU::Spr),
+            "gnr" | "graniterapids" => Ok(CPU::Gnr),
+            "arl" | "arrowlake" => Ok(CPU::Arl),
+            "lnl" | "lunarlake" => Ok(CPU::Lnl),
+            "znver1" => Ok(CPU::Znver1),
+            "znver2" | "ps5" => Ok(CPU::Znver2),
+            "znver3" => Ok(CPU::Znver3),
+            #[allow(deprecated)]
+            "cortex-a9" => Ok(CPU::CortexA9),
+            #[allow(deprecated)]
+            "cortex-a15" => Ok(CPU::CortexA15),
+            "cortex-a35" => Ok(CPU::CortexA35),
+            "cortex-a53" => Ok(CPU::CortexA53),
+            "cortex-a55" => Ok(CPU::CortexA55),
+            "cortex-a57" => Ok(CPU::CortexA57),
+            "cortex-a78" => Ok(CPU::CortexA78),
+            "cortex-a510" => Ok(CPU::CortexA510),
+            "cortex-a520" => Ok(CPU::CortexA520),
+            "apple-a7" => Ok(CPU::AppleA7),
+            "apple-a10" => Ok(CPU::AppleA10),
+            "apple-a11" => Ok(CPU::AppleA11),
+            "apple-a12" => Ok(CPU::AppleA12),
+            "apple-a13" => Ok(CPU::AppleA13),
+            "apple-a14" => Ok(CPU::AppleA14),
+            "apple-a15" => Ok(CPU::AppleA15),
+            "apple-a16" => Ok(CPU::AppleA16),
+            "apple-a17" => Ok(CPU::AppleA17),
+            _ => Err(parse_error("CPU", s)),
+        }
+    }
+}
+
 /// ISPC optimization options.
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Ord, PartialOrd, Eq, PartialEq)]
 pub enum OptimizationOpt {
     /// Remove assertion statements from final code.
     DisableAssertions,
@@ -230,9 +370,29 @@ impl std::fmt::Display for OptimizationOpt {
     }
 }
 
+impl FromStr for OptimizationOpt {
+    type Err = ParseOptionError;
+
+    fn from_str(s: &str) -> Result<OptimizationOpt, ParseOptionError> {
+        match option_name(s, "--opt=").as_str() {
+            "disable-assertions" => Ok(OptimizationOpt::DisableAssertions),
+            "disable-fma" => Ok(OptimizationOpt::DisableFMA),
+            "disable-gathers" => Ok(OptimizationOpt::DisableGathers),
+            "disable-loop-unroll" => Ok(OptimizationOpt::DisableLoopUnroll),
+            "disable-scatters" => Ok(OptimizationOpt::DisableScatters),
+            "disable-zmm" => Ok(OptimizationOpt::DisableZmm),
+            "fast-masked-vload" => Ok(OptimizationOpt::FastMaskedVload),
+            "fast-math" => Ok(OptimizationOpt::FastMath),
+            "force-aligned-memory" => Ok(OptimizationOpt::ForceAlignedMemory),
+            "reset-ftz-daz" => Ok(OptimizationOpt::ResetFTZDaz),
+            _ => Err(parse_error("optimization option", s)),
+        }
+    }
+}
+
 /// Target instruction sets and vector widths available to specialize for. The
 /// default if none is set will be the host CPU's ISA and vector width.
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TargetISA {
     Host,
     // Generic
@@ -332,8 +492,8 @@ pub enum TargetISA {
 }
 
 impl TargetISA {
-    /// Returns the library-suffix associated with the target. Adjust these
-    /// strings to match your naming conventions.
+    /// Returns the suffix ISPC adds to the names of the outputs for this ISA
+    /// when compiling for multiple targets, e.g. `avx2` for `foo_ispc_avx2.o`.
     pub fn lib_suffix(&self) -> String {
         match *self {
             TargetISA::Host => String::from("host"),
@@ -422,8 +582,61 @@ impl TargetISA {
             TargetISA::XEHPCx16 | TargetISA::XEHPCx32 => String::from("xehpc"),
         }
     }
+
+    /// The suffixes the outputs for this ISA may have with the given ISPC
+    /// version, most likely first. ISPC has renamed some of them between
+    /// versions, so the outputs written are matched against all of these.
+    pub(crate) fn output_suffixes(&self, version: &Version) -> Vec<String> {
+        // ISPC 1.22 gave SSE4.1 and SSE4.2 separate names, previously both were sse4
+        let split_sse4 = *version >= Version::new(1, 22, 0);
+        let mut suffixes = Vec::new();
+        match *self {
+            TargetISA::SSE41i8x16
+            | TargetISA::SSE41i16x8
+            | TargetISA::SSE41i32x4
+            | TargetISA::SSE41i32x8 => suffixes.push(String::from("sse41")),
+            TargetISA::SSE42i8x16
+            | TargetISA::SSE42i16x8
+            | TargetISA::SSE42i32x4
+            | TargetISA::SSE42i32x8
+            | TargetISA::SSE4i8x16
+            | TargetISA::SSE4i16x8
+            | TargetISA::SSE4i32x4
+            | TargetISA::SSE4i32x8 => suffixes.push(String::from("sse42")),
+            _ => {}
+        }
+        if split_sse4 {
+            suffixes.push(self.lib_suffix());
+        } else {
+            suffixes.insert(0, self.lib_suffix());
+        }
+        suffixes
+    }
 }
 
+/// Every suffix ISPC gives the outputs for a specific ISA, with any version,
+/// see `TargetISA::output_suffixes`.
+pub(crate) const ISA_OUTPUT_SUFFIXES: &[&str] = &[
+    "host",
+    "generic",
+    "sse2",
+    "sse4",
+    "sse41",
+    "sse42",
+    "avx",
+    "avx2",
+    "avx2vnni",
+    "avx512knl",
+    "avx512skx",
+    "avx512icl",
+    "avx512spr",
+    "neon",
+    "gen9",
+    "xelp",
+    "xehpg",
+    "xehpc",
+];
+
 impl std::fmt::Display for TargetISA {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match *self {
@@ -523,7 +736,98 @@ impl std::fmt::Display for TargetISA {
     }
 }
 
+impl FromStr for TargetISA {
+    type Err = ParseOptionError;
+
+    fn from_str(s: &str) -> Result<TargetISA, ParseOptionError> {
+        match option_name(s, "--target=").as_str() {
+            "host" => Ok(TargetISA::Host),
+            "generic-i16x16" => Ok(TargetISA::Generici16x16),
+            "generic-i16x8" => Ok(TargetISA::Generici16x8),
+            "generic-i1x16" => Ok(TargetISA::Generici1x16),
+            "generic-i1x32" => Ok(TargetISA::Generici1x32),
+            "generic-i1x4" => Ok(TargetISA::Generici1x4),
+            "generic-i1x64" => Ok(TargetISA::Generici1x64),
+            "generic-i1x8" => Ok(TargetISA::Generici1x8),
+            "generic-i32x16" => Ok(TargetISA::Generici32x16),
+            "generic-i32x4" => Ok(TargetISA::Generici32x4),
+            "generic-i32x8" => Ok(TargetISA::Generici32x8),
+            "generic-i64x4" => Ok(TargetISA::Generici64x4),
+            "generic-i8x16" => Ok(TargetISA::Generici8x16),
+            "generic-i8x32" => Ok(TargetISA::Generici8x32),
+            "sse2-i32x4" => Ok(TargetISA::SSE2i32x4),
+            "sse2-i32x8" => Ok(TargetISA::SSE2i32x8),
+            "sse4.1-i8x16" => Ok(TargetISA::SSE41i8x16),
+            "sse4.1-i16x8" => Ok(TargetISA::SSE41i16x8),
+            "sse4.1-i32x4" => Ok(TargetISA::SSE41i32x4),
+            "sse4.1-i32x8" => Ok(TargetISA::SSE41i32x8),
+            "sse4.2-i8x16" => Ok(TargetISA::SSE42i8x16),
+            "sse4.2-i16x8" => Ok(TargetISA::SSE42i16x8),
+            "sse4.2-i32x4" => Ok(TargetISA::SSE42i32x4),
+            "sse4.2-i32x8" => Ok(TargetISA::SSE42i32x8),
+            "sse4-i8x16" => Ok(TargetISA::SSE4i8x16),
+            "sse4-i16x8" => Ok(TargetISA::SSE4i16x8),
+            "sse4-i32x4" => Ok(TargetISA::SSE4i32x4),
+            "sse4-i32x8" => Ok(TargetISA::SSE4i32x8),
+            "avx1-i32x4" => Ok(TargetISA::AVX1i32x4),
+            "avx1-i32x8" => Ok(TargetISA::AVX1i32x8),
+            "avx1-i32x16" => Ok(TargetISA::AVX1i32x16),
+            "avx1-i64x4" => Ok(TargetISA::AVX1i64x4),
+            "avx2-i32x8" => Ok(TargetISA::AVX2i32x8),
+            "avx2-i32x16" => Ok(TargetISA::AVX2i32x16),
+            "avx2-i64x4" => Ok(TargetISA::AVX2i64x4),
+            "avx2-i8x32" => Ok(TargetISA::AVX2i8x32),
+            "avx2-i16x16" => Ok(TargetISA::AVX2i16x16),
+            "avx2-i32x4" => Ok(TargetISA::AVX2i32x4),
+            "avx2vnni-i32x4" => Ok(TargetISA::AVX2VNNIi32x4),
+            "avx2vnni-i32x8" => Ok(TargetISA::AVX2VNNIi32x8),
+            "avx2vnni-i32x16" => Ok(TargetISA::AVX2VNNIi32x16),
+            #[allow(deprecated)]
+            "avx512knl-i32x16" => Ok(TargetISA::AVX512KNLi32x16),
+            #[allow(deprecated)]
+            "avx512skx-i32x16" => Ok(TargetISA::AVX512SKXi32x16),
+            #[allow(deprecated)]
+            "avx512skx-i32x8" => Ok(TargetISA::AVX512SKXi32x8),
+            #[allow(deprecated)]
+            "avx512knl-x16" => Ok(TargetISA::AVX512KNLx16),
+            "avx512skx-x4" => Ok(TargetISA::AVX512SKXx4),
+            "avx512skx-x8" => Ok(TargetISA::AVX512SKXx8),
+            "avx512skx-x16" => Ok(TargetISA::AVX512SKXx16),
+            "avx512skx-x32" => Ok(TargetISA::AVX512SKXx32),
+            "avx512skx-x64" => Ok(TargetISA::AVX512SKXx64),
+            "avx512icl-x4" => Ok(TargetISA::AVX512ICLx4),
+            "avx512icl-x8" => Ok(TargetISA::AVX512ICLx8),
+            "avx512icl-x16" => Ok(TargetISA::AVX512ICLx16),
+            "avx512icl-x32" => Ok(TargetISA::AVX512ICLx32),
+            "avx512icl-x64" => Ok(TargetISA::AVX512ICLx64),
+            "avx512spr-x4" => Ok(TargetISA::AVX512SPRx4),
+            "avx512spr-x8" => Ok(TargetISA::AVX512SPRx8),
+            "avx512spr-x16" => Ok(TargetISA::AVX512SPRx16),
+            "avx512spr-x32" => Ok(TargetISA::AVX512SPRx32),
+            "avx512spr-x64" => Ok(TargetISA::AVX512SPRx64),
+            "neon-i8x16" => Ok(TargetISA::Neoni8x16),
+            "neon-i8x32" => Ok(TargetISA::Neoni8x32),
+            "neon-i16x8" => Ok(TargetISA::Neoni16x8),
+            "neon-i16x16" => Ok(TargetISA::Neoni16x16),
+            "neon-i32x4" => Ok(TargetISA::Neoni32x4),
+            "neon-i32x8" => Ok(TargetISA::Neoni32x8),
+            #[allow(deprecated)]
+            "gen9-x8" => Ok(TargetISA::GEN9x8),
+            #[allow(deprecated)]
+            "gen9-x16" => Ok(TargetISA::GEN9x16),
+            "xelp-x8" => Ok(TargetISA::XELPx8),
+            "xelp-x16" => Ok(TargetISA::XELPx16),
+            "xehpg-x8" => Ok(TargetISA::XEHPGx8),
+            "xehpg-x16" => Ok(TargetISA::XEHPGx16),
+            "xehpc-x16" => Ok(TargetISA::XEHPCx16),
+            "xehpc-x32" => Ok(TargetISA::XEHPCx32),
+            _ => Err(parse_error("target ISA", s)),
+        }
+    }
+}
+
 /// Target OS to specialize for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TargetOS {
     Windows,
     Ps4,
@@ -531,6 +835,7 @@ pub enum TargetOS {
     Macos,
     Android,
     Ios,
+    FreeBsd,
 }
 
 impl TargetOS {
@@ -542,6 +847,7 @@ impl TargetOS {
             TargetOS::Macos => String::from("macos"),
             TargetOS::Android => String::from("android"),
             TargetOS::Ios => String::from("ios"),
+            TargetOS::FreeBsd => String::from("freebsd"),
         }
     }
 }
@@ -555,6 +861,294 @@ impl std::fmt::Display for TargetOS {
             TargetOS::Macos => write!(f, "--target-os=macos"),
             TargetOS::Android => write!(f, "--target-os=android"),
             TargetOS::Ios => write!(f, "--target-os=ios"),
+            TargetOS::FreeBsd => write!(f, "--target-os=freebsd"),
+        }
+    }
+}
+
+impl FromStr for TargetOS {
+    type Err = ParseOptionError;
+
+    fn from_str(s: &str) -> Result<TargetOS, ParseOptionError> {
+        match option_name(s, "--target-os=").as_str() {
+            "windows" => Ok(TargetOS::Windows),
+            "ps4" => Ok(TargetOS::Ps4),
+            "linux" => Ok(TargetOS::Linux),
+            "macos" => Ok(TargetOS::Macos),
+            "android" => Ok(TargetOS::Android),
+            "ios" => Ok(TargetOS::Ios),
+            "freebsd" => Ok(TargetOS::FreeBsd),
+            _ => Err(parse_error("target OS", s)),
+        }
+    }
+}
+
+/// Additional outputs ISPC can write for each source file alongside the
+/// object file, see `Config::emit`.
+#[derive(Clone, Copy, Debug, Ord, PartialOrd, Eq, PartialEq)]
+pub enum EmitKind {
+    /// Assembly for the target ISA.
+    Asm,
+    /// Human readable LLVM IR.
+    LlvmIr,
+    /// LLVM bitcode.
+    LlvmBitcode,
+}
+
+impl EmitKind {
+    /// Returns the file extension used for this kind of output.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            EmitKind::Asm => "s",
+            EmitKind::LlvmIr => "ll",
+            EmitKind::LlvmBitcode => "bc",
+        }
+    }
+}
+
+impl std::fmt::Display for EmitKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            EmitKind::Asm => write!(f, "--emit-asm"),
+            EmitKind::LlvmIr => write!(f, "--emit-llvm-text"),
+            EmitKind::LlvmBitcode => write!(f, "--emit-llvm"),
+        }
+    }
+}
+
+impl FromStr for EmitKind {
+    type Err = ParseOptionError;
+
+    fn from_str(s: &str) -> Result<EmitKind, ParseOptionError> {
+        match option_name(s, "--emit-").as_str() {
+            "asm" => Ok(EmitKind::Asm),
+            "llvm-text" => Ok(EmitKind::LlvmIr),
+            "llvm" => Ok(EmitKind::LlvmBitcode),
+            _ => Err(parse_error("output kind", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fmt::{Debug, Display};
+
+    /// Check each option is displayed as the ISPC flag, which parses back to it.
+    fn round_trip<T>(all: &[T])
+    where
+        T: Display + Debug + PartialEq + FromStr<Err = ParseOptionError>,
+    {
+        for o in all {
+            assert_eq!(o.to_string().parse::<T>().as_ref(), Ok(o), "{o}");
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn option_round_trip() {
+        round_trip(&[
+            MathLib::ISPCDefault,
+            MathLib::Fast,
+            MathLib::SVML,
+            MathLib::System,
+        ]);
+        round_trip(&[
+            Architecture::Arm,
+            Architecture::Aarch64,
+            Architecture::X86,
+            Architecture::X64,
+            Architecture::Xe64,
+        ]);
+        round_trip(&[Addressing::A32, Addressing::A64]);
+        round_trip(&[
+            CPU::Generic,
+            CPU::X8664,
+            CPU::Bonnell,
+            CPU::Core2,
+            CPU::Penryn,
+            CPU::Nehalem,
+            CPU::Ps4,
+            CPU::SandyBridge,
+            CPU::IvyBridge,
+            CPU::Haswell,
+            CPU::Broadwell,
+            CPU::Skylake,
+            CPU::Knl,
+            CPU::Skx,
+            CPU::Icl,
+            CPU::Silvermont,
+            CPU::Icx,
+            CPU::Tgl,
+            CPU::Adl,
+            CPU::Mtl,
+            CPU::Spr,
+            CPU::Gnr,
+            CPU::Arl,
+            CPU::Lnl,
+            CPU::Znver1,
+            CPU::Znver2,
+            CPU::Znver3,
+            CPU::CortexA9,
+            CPU::CortexA15,
+            CPU::CortexA35,
+            CPU::CortexA53,
+            CPU::CortexA55,
+            CPU::CortexA57,
+            CPU::CortexA78,
+            CPU::CortexA510,
+            CPU::CortexA520,
+            CPU::AppleA7,
+            CPU::AppleA10,
+            CPU::AppleA11,
+            CPU::AppleA12,
+            CPU::AppleA13,
+            CPU::AppleA14,
+            CPU::AppleA15,
+            CPU::AppleA16,
+            CPU::AppleA17,
+        ]);
+        round_trip(&[
+            OptimizationOpt::DisableAssertions,
+            OptimizationOpt::DisableFMA,
+            OptimizationOpt::DisableGathers,
+            OptimizationOpt::DisableLoopUnroll,
+            OptimizationOpt::DisableScatters,
+            OptimizationOpt::DisableZmm,
+            OptimizationOpt::FastMaskedVload,
+            OptimizationOpt::FastMath,
+            OptimizationOpt::ForceAlignedMemory,
+            OptimizationOpt::ResetFTZDaz,
+        ]);
+        round_trip(&[
+            TargetOS::Windows,
+            TargetOS::Ps4,
+            TargetOS::Linux,
+            TargetOS::Macos,
+            TargetOS::Android,
+            TargetOS::Ios,
+            TargetOS::FreeBsd,
+        ]);
+        round_trip(&[EmitKind::Asm, EmitKind::LlvmIr, EmitKind::LlvmBitcode]);
+
+        // Synonyms parse to the same option
+        assert_eq!("corei7".parse(), Ok(CPU::Nehalem));
+        assert_eq!("--arch=x86-64".parse(), Ok(Architecture::X64));
+        assert_eq!(
+            "avx9".parse::<TargetISA>(),
+            Err(ParseOptionError {
+                kind: "target ISA",
+                value: String::from("avx9"),
+            })
+        );
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn target_isa_round_trip() {
+        let all = [
+            TargetISA::Host,
+            TargetISA::Generici16x16,
+            TargetISA::Generici16x8,
+            TargetISA::Generici1x16,
+            TargetISA::Generici1x32,
+            TargetISA::Generici1x4,
+            TargetISA::Generici1x64,
+            TargetISA::Generici1x8,
+            TargetISA::Generici32x16,
+            TargetISA::Generici32x4,
+            TargetISA::Generici32x8,
+            TargetISA::Generici64x4,
+            TargetISA::Generici8x16,
+            TargetISA::Generici8x32,
+            TargetISA::SSE2i32x4,
+            TargetISA::SSE2i32x8,
+            TargetISA::SSE41i8x16,
+            TargetISA::SSE41i16x8,
+            TargetISA::SSE41i32x4,
+            TargetISA::SSE41i32x8,
+            TargetISA::SSE42i8x16,
+            TargetISA::SSE42i16x8,
+            TargetISA::SSE42i32x4,
+            TargetISA::SSE42i32x8,
+            TargetISA::SSE4i8x16,
+            TargetISA::SSE4i16x8,
+            TargetISA::SSE4i32x4,
+            TargetISA::SSE4i32x8,
+            TargetISA::AVX1i32x4,
+            TargetISA::AVX1i32x8,
+            TargetISA::AVX1i32x16,
+            TargetISA::AVX1i64x4,
+            TargetISA::AVX2i32x8,
+            TargetISA::AVX2i32x16,
+            TargetISA::AVX2i64x4,
+            TargetISA::AVX2i8x32,
+            TargetISA::AVX2i16x16,
+            TargetISA::AVX2i32x4,
+            TargetISA::AVX2VNNIi32x4,
+            TargetISA::AVX2VNNIi32x8,
+            TargetISA::AVX2VNNIi32x16,
+            TargetISA::AVX512KNLi32x16,
+            TargetISA::AVX512SKXi32x16,
+            TargetISA::AVX512SKXi32x8,
+            TargetISA::AVX512KNLx16,
+            TargetISA::AVX512SKXx4,
+            TargetISA::AVX512SKXx8,
+            TargetISA::AVX512SKXx16,
+            TargetISA::AVX512SKXx32,
+            TargetISA::AVX512SKXx64,
+            TargetISA::AVX512ICLx4,
+            TargetISA::AVX512ICLx8,
+            TargetISA::AVX512ICLx16,
+            TargetISA::AVX512ICLx32,
+            TargetISA::AVX512ICLx64,
+            TargetISA::AVX512SPRx4,
+            TargetISA::AVX512SPRx8,
+            TargetISA::AVX512SPRx16,
+            TargetISA::AVX512SPRx32,
+            TargetISA::AVX512SPRx64,
+            TargetISA::Neoni8x16,
+            TargetISA::Neoni8x32,
+            TargetISA::Neoni16x8,
+            TargetISA::Neoni16x16,
+            TargetISA::Neoni32x4,
+            TargetISA::Neoni32x8,
+            TargetISA::GEN9x8,
+            TargetISA::GEN9x16,
+            TargetISA::XELPx8,
+            TargetISA::XELPx16,
+            TargetISA::XEHPGx8,
+            TargetISA::XEHPGx16,
+            TargetISA::XEHPCx16,
+            TargetISA::XEHPCx32,
+        ];
+        for isa in all {
+            for version in [Version::new(1, 21, 0), Version::new(1, 22, 0)] {
+                for suffix in isa.output_suffixes(&version) {
+                    assert!(ISA_OUTPUT_SUFFIXES.contains(&suffix.as_str()), "{isa}");
+                }
+            }
+        }
+        // The SSE4 aliases are passed to ISPC with the SSE4.2 names, so they
+        // parse back to the SSE4.2 targets, which are the same ISA
+        let sse4 = [
+            TargetISA::SSE4i8x16,
+            TargetISA::SSE4i16x8,
+            TargetISA::SSE4i32x4,
+            TargetISA::SSE4i32x8,
+        ];
+        round_trip(
+            &all.into_iter()
+                .filter(|i| !sse4.contains(i))
+                .collect::<Vec<_>>(),
+        );
+        for isa in sse4 {
+            let parsed = isa.to_string().parse::<TargetISA>().unwrap();
+            assert!(parsed.to_string().starts_with("sse4.2-"), "{isa}");
+            assert_eq!(parsed.to_string(), isa.to_string());
+            assert_eq!(parsed.lib_suffix(), isa.lib_suffix());
         }
+        assert_eq!("sse4-i32x4".parse(), Ok(TargetISA::SSE4i32x4));
+        assert_eq!("sse4.2-i32x4".parse(), Ok(TargetISA::SSE42i32x4));
     }
 }
@@ -0,0 +1,191 @@
+//! Mapping of Rust target triples to the ISPC architecture, target OS and
+//! default target ISA, so cross compiling works without setting them by hand.
+
+use crate::opt::{Architecture, TargetISA, TargetOS};
+
+/// The ISPC options implied by a Rust target triple.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Target {
+    pub arch: Option<Architecture>,
+    pub os: Option<TargetOS>,
+    /// Whether position independent code must be generated, which is the
+    /// case for every target except Windows.
+    pub pic: bool,
+    /// Whether the target is an ARM processor with NEON.
+    neon: bool,
+}
+
+impl Target {
+    /// Parse a target triple such as `aarch64-unknown-linux-gnu` or
+    /// `armv7-linux-androideabi`. Parts of the triple ISPC doesn't support
+    /// are left unset, leaving them to ISPC's defaults.
+    pub fn parse(triple: &str) -> Target {
+        let mut parts = triple.split('-');
+        let arch = parts.next().unwrap_or_default();
+        let rest: Vec<_> = parts.collect();
+        let has = |s: &str| rest.iter().any(|p| p.starts_with(s));
+
+        let arch = match arch {
+            "x86_64" | "x86_64h" => Some(Architecture::X64),
+            "i386" | "i586" | "i686" => Some(Architecture::X86),
+            "aarch64" | "arm64" | "arm64e" | "arm64ec" => Some(Architecture::Aarch64),
+            a if a.starts_with("arm") || a.starts_with("thumb") => Some(Architecture::Arm),
+            _ => None,
+        };
+        // Android triples also contain linux, so check for it first
+        let os = if has("windows") {
+            Some(TargetOS::Windows)
+        } else if has("android") {
+            Some(TargetOS::Android)
+        } else if has("linux") {
+            Some(TargetOS::Linux)
+        } else if has("darwin") || has("macos") {
+            Some(TargetOS::Macos)
+        } else if has("ios") {
+            Some(TargetOS::Ios)
+        } else if has("freebsd") {
+            Some(TargetOS::FreeBsd)
+        } else {
+            None
+        };
+        Target {
+            arch,
+            os,
+            pic: os != Some(TargetOS::Windows),
+            neon: matches!(arch, Some(Architecture::Aarch64))
+                || ["armv7", "armv8", "thumbv7neon", "thumbv8"]
+                    .iter()
+                    .any(|a| triple.starts_with(a)),
+        }
+    }
+
+    /// The target ISA to use if none was set. ISPC defaults to the best ISA of
+    /// the machine it runs on, which is only right when building for the same
+    /// architecture. ARM targets with NEON always select it, as ISPC may also be
+    /// running under emulation (e.g. Rosetta on macOS) and default to SSE4.
+    pub fn default_isa(&self, host: &Target) -> Option<TargetISA> {
+        if self.neon {
+            return Some(TargetISA::Neoni32x4);
+        }
+        match self.arch? {
+            // Match the baseline ISA Rust assumes for x86 when cross compiling
+            Architecture::X64 | Architecture::X86 if host.arch != self.arch => {
+                Some(TargetISA::SSE2i32x4)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The Rust name of the architecture of the target triple, as used for
+/// `cfg(target_arch)` and `CARGO_CFG_TARGET_ARCH`.
+pub(crate) fn rust_arch(triple: &str) -> String {
+    match Target::parse(triple).arch {
+        Some(Architecture::X64) => String::from("x86_64"),
+        Some(Architecture::X86) => String::from("x86"),
+        Some(Architecture::Aarch64) => String::from("aarch64"),
+        Some(Architecture::Arm) => String::from("arm"),
+        _ => triple.split('-').next().unwrap_or_default().to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_triples() {
+        use Architecture::{Aarch64, Arm, X64, X86};
+        use TargetOS::{Android, Ios, Linux, Macos, Windows};
+        let table = [
+            ("x86_64-unknown-linux-gnu", Some(X64), Some(Linux), false),
+            ("x86_64-pc-windows-msvc", Some(X64), Some(Windows), false),
+            ("x86_64-pc-windows-gnu", Some(X64), Some(Windows), false),
+            ("x86_64-apple-darwin", Some(X64), Some(Macos), false),
+            ("x86_64-linux-android", Some(X64), Some(Android), false),
+            ("x86_64-apple-ios", Some(X64), Some(Ios), false),
+            ("i686-pc-windows-msvc", Some(X86), Some(Windows), false),
+            (
+                "aarch64-unknown-linux-gnu",
+                Some(Aarch64),
+                Some(Linux),
+                true,
+            ),
+            (
+                "aarch64-pc-windows-msvc",
+                Some(Aarch64),
+                Some(Windows),
+                true,
+            ),
+            ("aarch64-apple-darwin", Some(Aarch64), Some(Macos), true),
+            ("aarch64-linux-android", Some(Aarch64), Some(Android), true),
+            ("aarch64-apple-ios", Some(Aarch64), Some(Ios), true),
+            (
+                "armv7-unknown-linux-gnueabihf",
+                Some(Arm),
+                Some(Linux),
+                true,
+            ),
+            ("armv7-linux-androideabi", Some(Arm), Some(Android), true),
+            ("armv7-apple-ios", Some(Arm), Some(Ios), true),
+            (
+                "thumbv7neon-linux-androideabi",
+                Some(Arm),
+                Some(Android),
+                true,
+            ),
+            ("arm-unknown-linux-gnueabihf", Some(Arm), Some(Linux), false),
+            ("riscv64gc-unknown-linux-gnu", None, Some(Linux), false),
+            ("wasm32-unknown-unknown", None, None, false),
+        ];
+        for (triple, arch, os, neon) in table {
+            let t = Target::parse(triple);
+            assert_eq!(
+                t,
+                Target {
+                    arch,
+                    os,
+                    pic: os != Some(Windows),
+                    neon,
+                },
+                "{triple}"
+            );
+        }
+    }
+
+    #[test]
+    fn default_isas() {
+        let x64_host = Target::parse("x86_64-unknown-linux-gnu");
+        let arm_host = Target::parse("aarch64-apple-darwin");
+        let neon = Some(TargetISA::Neoni32x4);
+        let sse2 = Some(TargetISA::SSE2i32x4);
+        let table = [
+            // Native x86 builds leave the choice to ISPC
+            ("x86_64-unknown-linux-gnu", &x64_host, None),
+            ("x86_64-pc-windows-msvc", &x64_host, None),
+            // Cross compiling for x86 uses the baseline ISA
+            ("x86_64-apple-darwin", &arm_host, sse2),
+            ("x86_64-linux-android", &arm_host, sse2),
+            ("i686-unknown-linux-gnu", &x64_host, sse2),
+            // NEON is always selected, even natively
+            ("aarch64-apple-darwin", &arm_host, neon),
+            ("aarch64-apple-darwin", &x64_host, neon),
+            ("aarch64-linux-android", &x64_host, neon),
+            ("armv7-linux-androideabi", &x64_host, neon),
+            ("armv7-apple-ios", &arm_host, neon),
+            ("arm-unknown-linux-gnueabihf", &x64_host, None),
+            ("riscv64gc-unknown-linux-gnu", &x64_host, None),
+        ];
+        for (triple, host, isa) in table {
+            assert_eq!(Target::parse(triple).default_isa(host), isa, "{triple}");
+        }
+    }
+
+    #[test]
+    fn rust_arches() {
+        assert_eq!(rust_arch("x86_64-pc-windows-msvc"), "x86_64");
+        assert_eq!(rust_arch("i686-unknown-linux-gnu"), "x86");
+        assert_eq!(rust_arch("arm64-apple-ios"), "aarch64");
+        assert_eq!(rust_arch("thumbv7neon-linux-androideabi"), "arm");
+    }
+}
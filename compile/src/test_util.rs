@@ -0,0 +1,14 @@
+//! Helpers shared by the unit tests.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Create an empty temporary directory for a test, named after the test and
+/// the process so concurrent test runs don't share it.
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("ispc-rs-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,417 @@
+//! Checks the target ISA, CPU, architecture and OS options against the ones
+//! supported by the installed ISPC compiler and against each other, so that
+//! invalid combinations are reported clearly before we try to compile.
+
+use std::path::Path;
+use std::process::Command;
+
+use regex::Regex;
+use semver::Version;
+
+use crate::error::IspcError;
+use crate::opt::{Architecture, TargetISA, TargetOS, CPU};
+
+/// The targets, CPUs, architectures and OSes supported by an ISPC compiler,
+/// as listed in the output of `ispc --help`. Any of the lists may be empty if
+/// they couldn't be found in the help text, in which case the options of that
+/// kind are not checked against the compiler.
+#[derive(Debug, Clone, Default)]
+pub struct SupportedTargets {
+    pub isas: Vec<String>,
+    pub cpus: Vec<String>,
+    pub archs: Vec<String>,
+    pub oses: Vec<String>,
+}
+
+impl SupportedTargets {
+    /// Query the supported targets of the ISPC compiler at `ispc`.
+    pub fn query(ispc: &Path) -> Result<SupportedTargets, IspcError> {
+        let output =
+            Command::new(ispc)
+                .arg("--help")
+                .output()
+                .map_err(|e| IspcError::CompilerNotFound {
+                    compiler: ispc.to_path_buf(),
+                    source: e,
+                })?;
+        Ok(SupportedTargets::parse(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+    /// Parse the lists of supported options out of the `ispc --help` text.
+    pub fn parse(help: &str) -> SupportedTargets {
+        SupportedTargets {
+            isas: option_list(help, "<t>="),
+            cpus: option_list(help, "<type>="),
+            archs: option_list(help, "--arch="),
+            oses: option_list(help, "<os>="),
+        }
+    }
+}
+
+/// Find the `{a, b, c}` list following `marker` in the help text, which may
+/// wrap over multiple lines. CPU names can also list synonyms, e.g.
+/// `corei7 (synonyms: nehalem)`, which are included in the list.
+fn option_list(help: &str, marker: &str) -> Vec<String> {
+    let start = match help.find(&format!("{marker}{{")) {
+        Some(s) => s + marker.len() + 1,
+        None => return Vec::new(),
+    };
+    let end = match help[start..].find('}') {
+        Some(e) => start + e,
+        None => return Vec::new(),
+    };
+    let synonyms = Regex::new(r"\(synonyms?:\s*([^)]*)\)").unwrap();
+    let list = synonyms.replace_all(&help[start..end], ", $1");
+    list.split([',', '|'])
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+/// The broad family of processors an option applies to, used to find
+/// options which can't be combined.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Family {
+    X86,
+    Arm,
+    Xe,
+}
+
+/// The suffix ISPC gives the outputs for the ISA in a multi-target build,
+/// e.g. `avx2` for `avx2-i32x8`. ISAs with the same suffix, such as the `sse4`
+/// and `sse4.2` names for the same ISA, would overwrite each other's outputs.
+fn output_suffix(isa: &TargetISA, ispc_version: &Version) -> String {
+    isa.output_suffixes(ispc_version)
+        .into_iter()
+        .next()
+        .unwrap_or_default()
+}
+
+fn isa_family(isa: &TargetISA) -> Option<Family> {
+    match isa.lib_suffix().as_str() {
+        "host" | "generic" => None,
+        "neon" => Some(Family::Arm),
+        "gen9" | "xelp" | "xehpg" | "xehpc" => Some(Family::Xe),
+        _ => Some(Family::X86),
+    }
+}
+
+fn cpu_family(cpu: &CPU) -> Option<Family> {
+    let name = cpu.to_string();
+    let name = name.trim_start_matches("--cpu=");
+    if name == "generic" {
+        None
+    } else if name.starts_with("cortex") || name.starts_with("apple") {
+        Some(Family::Arm)
+    } else {
+        Some(Family::X86)
+    }
+}
+
+fn arch_family(arch: &Architecture) -> Family {
+    match arch {
+        Architecture::X86 | Architecture::X64 => Family::X86,
+        Architecture::Arm | Architecture::Aarch64 => Family::Arm,
+        Architecture::Xe64 => Family::Xe,
+    }
+}
+
+/// ISPC accepts both `x86-64` and `x86_64` for the architecture, so compare
+/// option names ignoring the separator.
+fn is_listed(list: &[String], name: &str) -> bool {
+    list.is_empty()
+        || list
+            .iter()
+            .any(|s| s.replace('_', "-") == name.replace('_', "-"))
+}
+
+/// The options to be validated for a build.
+pub(crate) struct Options<'a> {
+    pub isas: Option<&'a [TargetISA]>,
+    pub cpu: Option<&'a CPU>,
+    pub arch: Option<Architecture>,
+    pub os: Option<&'a TargetOS>,
+}
+
+/// Check the options against each other and against the targets supported by
+/// the compiler, returning a description of each problem found.
+pub(crate) fn check(
+    opts: &Options<'_>,
+    supported: &SupportedTargets,
+    ispc_version: &Version,
+) -> Vec<String> {
+    let mut problems = Vec::new();
+    let removed_in_1_26 = *ispc_version >= Version::new(1, 26, 0);
+
+    if let Some(isas) = opts.isas {
+        if isas.len() > 1 && isas.contains(&TargetISA::Host) {
+            problems.push(String::from(
+                "TargetISA::Host can't be combined with other target ISAs, \
+                 leave the target unset to compile for the host ISA",
+            ));
+        }
+        for (i, isa) in isas.iter().enumerate() {
+            if !is_listed(&supported.isas, &isa.to_string()) {
+                problems.push(format!(
+                    "target ISA {isa} is not supported by ISPC {ispc_version}"
+                ));
+            }
+            #[allow(deprecated)]
+            if removed_in_1_26 && *isa == TargetISA::AVX512KNLx16 {
+                problems.push(format!(
+                    "target ISA {isa} was removed in ISPC 1.26, found ISPC {ispc_version}"
+                ));
+            }
+            // ISPC dispatches between ISA families, so only one width per family is allowed
+            let suffix = output_suffix(isa, ispc_version);
+            if let Some(other) = isas[..i]
+                .iter()
+                .find(|o| output_suffix(o, ispc_version) == suffix)
+            {
+                problems.push(format!(
+                    "target ISAs {other} and {isa} are variants of the same ISA, \
+                     only one variant of each ISA can be used in a multi-target build"
+                ));
+            }
+            if let (Some(f), Some(arch)) = (isa_family(isa), opts.arch) {
+                if f != arch_family(&arch) {
+                    problems.push(format!(
+                        "target ISA {isa} can't be used with architecture {}",
+                        arch.to_string().trim_start_matches("--arch=")
+                    ));
+                }
+            }
+        }
+        let families: Vec<_> = isas.iter().filter_map(isa_family).collect();
+        if families.windows(2).any(|f| f[0] != f[1]) {
+            problems.push(String::from(
+                "target ISAs for different processor families can't be combined in one library",
+            ));
+        }
+    }
+
+    if let Some(cpu) = opts.cpu {
+        let name = cpu.to_string();
+        let name = name.trim_start_matches("--cpu=");
+        if !is_listed(&supported.cpus, name) {
+            problems.push(format!(
+                "CPU {name} is not supported by ISPC {ispc_version}"
+            ));
+        }
+        #[allow(deprecated)]
+        if removed_in_1_26 && (*cpu == CPU::CortexA9 || *cpu == CPU::CortexA15) {
+            problems.push(format!(
+                "CPU {name} was removed in ISPC 1.26, found ISPC {ispc_version}"
+            ));
+        }
+        if let (Some(f), Some(arch)) = (cpu_family(cpu), opts.arch) {
+            if f != arch_family(&arch) {
+                problems.push(format!(
+                    "CPU {name} can't be used with architecture {}",
+                    arch.to_string().trim_start_matches("--arch=")
+                ));
+            }
+        }
+        if let (Some(f), Some(isas)) = (cpu_family(cpu), opts.isas) {
+            for isa in isas {
+                if isa_family(isa).is_some_and(|i| i != f) {
+                    problems.push(format!("CPU {name} can't be used with target ISA {isa}"));
+                }
+            }
+        }
+    }
+
+    if let Some(arch) = opts.arch {
+        let name = arch.to_string();
+        let name = name.trim_start_matches("--arch=");
+        if !is_listed(&supported.archs, name) {
+            problems.push(format!(
+                "architecture {name} is not supported by ISPC {ispc_version}"
+            ));
+        }
+    }
+
+    if let Some(os) = opts.os {
+        let name = os.lib_suffix();
+        if !is_listed(&supported.oses, &name) {
+            problems.push(format!(
+                "target OS {name} is not supported by ISPC {ispc_version}"
+            ));
+        }
+        let valid_archs: &[Architecture] = match os {
+            TargetOS::Ios => &[Architecture::Arm, Architecture::Aarch64],
+            TargetOS::Ps4 => &[Architecture::X64],
+            TargetOS::Macos => &[Architecture::X64, Architecture::Aarch64],
+            TargetOS::Windows => &[Architecture::X86, Architecture::X64, Architecture::Aarch64],
+            TargetOS::FreeBsd => &[
+                Architecture::X86,
+                Architecture::X64,
+                Architecture::Arm,
+                Architecture::Aarch64,
+            ],
+            TargetOS::Linux | TargetOS::Android => &[
+                Architecture::X86,
+                Architecture::X64,
+                Architecture::Arm,
+                Architecture::Aarch64,
+                Architecture::Xe64,
+            ],
+        };
+        if let Some(arch) = opts.arch {
+            if !valid_archs.contains(&arch) {
+                problems.push(format!(
+                    "target OS {name} can't be used with architecture {}",
+                    arch.to_string().trim_start_matches("--arch=")
+                ));
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The target options listed by `ispc --help`, which wraps the long lists.
+    const HELP: &str = "\
+Usage: ispc [<options>] <file to compile>
+    [--addressing={32,64}]\t\tSelect 32- or 64-bit addressing.
+    [--arch={x86-64, x86, arm, aarch64, xe64}]\tSelect target architecture
+    [--cpu=<type>]\t\t\tSelect target CPU type
+         <type>={default, x86-64, atom (synonyms: bonnell), core2, penryn, corei7 (synonyms: nehalem), core-avx2 (synonyms: haswell), skx, znver2 (synonyms: ps5), znver3, cortex-a53, apple-a14}
+    [--target=<t>]\t\t\tSelect target ISA and width.
+        <t>={host, sse2-i32x4, sse2-i32x8, sse4.1-i32x4, sse4.2-i32x4, avx1-i32x8, avx2-i32x8, avx2-i32x16, avx512skx-x16,
+             neon-i32x4, xelp-x8}
+    [--target-os=<os>]\t\t\tSelect target OS.  <os>={windows, ps4, linux, custom_linux, macos, android, ios}
+";
+
+    fn options(isas: &[TargetISA]) -> Options<'_> {
+        Options {
+            isas: Some(isas),
+            cpu: None,
+            arch: None,
+            os: None,
+        }
+    }
+
+    #[test]
+    fn parses_help() {
+        let supported = SupportedTargets::parse(HELP);
+        assert_eq!(supported.archs, ["x86-64", "x86", "arm", "aarch64", "xe64"]);
+        assert_eq!(supported.isas.len(), 11);
+        assert_eq!(supported.isas[0], "host");
+        // The list continues on the next line
+        assert_eq!(supported.isas[10], "xelp-x8");
+        assert!(supported.cpus.iter().any(|c| c == "corei7"));
+        assert!(supported.cpus.iter().any(|c| c == "nehalem"));
+        assert!(!supported.cpus.iter().any(|c| c.contains("synonym")));
+        assert_eq!(supported.oses[3], "custom_linux");
+
+        let empty = SupportedTargets::parse("Usage: ispc [<options>] <file to compile>");
+        assert!(empty.isas.is_empty() && empty.cpus.is_empty());
+        assert!(empty.archs.is_empty() && empty.oses.is_empty());
+    }
+
+    #[test]
+    fn checks_support() {
+        let supported = SupportedTargets::parse(HELP);
+        let version = Version::new(1, 24, 0);
+        let isas = [TargetISA::SSE2i32x4, TargetISA::AVX2i32x8];
+        let opts = Options {
+            cpu: Some(&CPU::Nehalem),
+            arch: Some(Architecture::X64),
+            os: Some(&TargetOS::Linux),
+            ..options(&isas)
+        };
+        assert_eq!(check(&opts, &supported, &version), Vec::<String>::new());
+
+        let isas = [TargetISA::AVX512SPRx16];
+        let opts = Options {
+            cpu: Some(&CPU::Lnl),
+            os: Some(&TargetOS::FreeBsd),
+            ..options(&isas)
+        };
+        assert_eq!(
+            check(&opts, &supported, &version),
+            [
+                "target ISA avx512spr-x16 is not supported by ISPC 1.24.0",
+                "CPU lnl is not supported by ISPC 1.24.0",
+                "target OS freebsd is not supported by ISPC 1.24.0",
+            ]
+        );
+
+        // Nothing is reported as unsupported if the help couldn't be parsed
+        let unknown = SupportedTargets::default();
+        assert!(check(&opts, &unknown, &version).is_empty());
+    }
+
+    #[test]
+    fn rejects_isas_writing_the_same_outputs() {
+        let supported = SupportedTargets::default();
+        let check_isas = |isas: &[TargetISA], version| check(&options(isas), &supported, &version);
+        let same_isa = |isas: &[TargetISA]| {
+            vec![format!(
+                "target ISAs {} and {} are variants of the same ISA, only one variant of each ISA can be used in a multi-target build",
+                isas[0], isas[1]
+            )]
+        };
+        let v1_24 = Version::new(1, 24, 0);
+        let v1_21 = Version::new(1, 21, 0);
+
+        let widths = [TargetISA::AVX2i32x8, TargetISA::AVX2i32x16];
+        assert_eq!(check_isas(&widths, v1_24.clone()), same_isa(&widths));
+        // Two names for SSE4.2
+        let sse42 = [TargetISA::SSE4i32x4, TargetISA::SSE42i32x4];
+        assert_eq!(check_isas(&sse42, v1_24.clone()), same_isa(&sse42));
+        // SSE4.1 and SSE4.2 only write separate outputs since ISPC 1.22
+        let sse4 = [TargetISA::SSE41i32x4, TargetISA::SSE42i32x4];
+        assert!(check_isas(&sse4, v1_24.clone()).is_empty());
+        assert_eq!(check_isas(&sse4, v1_21), same_isa(&sse4));
+        let avx = [
+            TargetISA::AVX2i32x8,
+            TargetISA::AVX2VNNIi32x8,
+            TargetISA::AVX512SKXx16,
+        ];
+        assert!(check_isas(&avx, v1_24).is_empty());
+    }
+
+    #[test]
+    fn rejects_mixed_families() {
+        let supported = SupportedTargets::default();
+        let version = Version::new(1, 26, 0);
+        let isas = [TargetISA::AVX2i32x8, TargetISA::Neoni32x4];
+        let opts = Options {
+            arch: Some(Architecture::Aarch64),
+            os: Some(&TargetOS::Ios),
+            ..options(&isas)
+        };
+        assert_eq!(
+            check(&opts, &supported, &version),
+            [
+                "target ISA avx2-i32x8 can't be used with architecture aarch64",
+                "target ISAs for different processor families can't be combined in one library",
+            ]
+        );
+
+        #[allow(deprecated)]
+        let isas = [TargetISA::AVX512KNLx16];
+        let opts = Options {
+            cpu: Some(&CPU::CortexA53),
+            arch: Some(Architecture::X64),
+            os: Some(&TargetOS::Ios),
+            ..options(&isas)
+        };
+        assert_eq!(
+            check(&opts, &supported, &version),
+            [
+                "target ISA avx512knl-x16 was removed in ISPC 1.26, found ISPC 1.26.0",
+                "CPU cortex-a53 can't be used with architecture x86_64",
+                "CPU cortex-a53 can't be used with target ISA avx512knl-x16",
+                "target OS ios can't be used with architecture x86_64",
+            ]
+        );
+    }
+}
@@ -0,0 +1,501 @@
+//! Generation of safe Rust wrappers for exported ISPC functions, using a
+//! description of how each pointer parameter is used to replace it with a
+//! slice or reference.
+
+use std::fmt::Write;
+
+use quote::ToTokens;
+use syn::{FnArg, ForeignItem, Item, Pat, PatType, ReturnType, Type};
+
+use crate::error::IspcError;
+
+/// Describes how to wrap an exported ISPC function in a safe Rust function,
+/// see `Config::safe_wrapper`. Each pointer parameter of the function must be
+/// described as a slice or reference, other parameters are passed through.
+///
+/// The length of a slice is either given by an integer parameter, which is
+/// removed from the wrapper and set from the slice length (all slices sharing
+/// it must have the same length), or by an expression over the wrapper's
+/// parameters giving the minimum length of the slice.
+///
+/// Only parameters declared `const` in the ISPC code can be taken as shared
+/// slices or references, as ISPC could write through any other pointer.
+/// Output buffers are taken as mutable slices or references, so the borrow
+/// checker ensures they don't alias any other parameter.
+///
+/// # Example
+/// For the ISPC function
+/// ```c
+/// export void add(const uniform float a[], const uniform float b[],
+///                 uniform float out[], uniform int count);
+/// ```
+/// the description below generates `safe::add(a: &[f32], b: &[f32], out: &mut [f32])`
+/// in the bindings module.
+/// ```no_run
+/// use ispc_compile::{Config, SafeWrapper};
+///
+/// Config::new()
+///     .file("src/add.ispc")
+///     .safe_wrapper(
+///         SafeWrapper::new("add")
+///             .slice("a", "count")
+///             .slice("b", "count")
+///             .slice_mut("out", "count"),
+///     )
+///     .compile("add");
+/// ```
+#[derive(Clone, Debug)]
+pub struct SafeWrapper {
+    name: String,
+    params: Vec<(String, ParamKind)>,
+}
+
+#[derive(Clone, Debug)]
+enum ParamKind {
+    Slice(String),
+    SliceMut(String),
+    Ref,
+    RefMut,
+}
+
+impl SafeWrapper {
+    /// Describe the exported ISPC function `name`.
+    pub fn new(name: &str) -> SafeWrapper {
+        SafeWrapper {
+            name: name.to_owned(),
+            params: Vec::new(),
+        }
+    }
+    /// Take the pointer parameter `param` as a `&[T]`, with its length given
+    /// by the parameter or expression `len`. The parameter must be `const`.
+    pub fn slice(mut self, param: &str, len: &str) -> SafeWrapper {
+        self.params
+            .push((param.to_owned(), ParamKind::Slice(len.to_owned())));
+        self
+    }
+    /// Take the pointer parameter `param` as a `&mut [T]`, with its length
+    /// given by the parameter or expression `len`.
+    pub fn slice_mut(mut self, param: &str, len: &str) -> SafeWrapper {
+        self.params
+            .push((param.to_owned(), ParamKind::SliceMut(len.to_owned())));
+        self
+    }
+    /// Take the pointer parameter `param` as a `&T`, e.g. for a uniform struct.
+    /// The parameter must be `const`.
+    pub fn reference(mut self, param: &str) -> SafeWrapper {
+        self.params.push((param.to_owned(), ParamKind::Ref));
+        self
+    }
+    /// Take the pointer parameter `param` as a `&mut T`.
+    pub fn reference_mut(mut self, param: &str) -> SafeWrapper {
+        self.params.push((param.to_owned(), ParamKind::RefMut));
+        self
+    }
+    fn kind(&self, param: &str) -> Option<&ParamKind> {
+        self.params.iter().find(|(p, _)| p == param).map(|(_, k)| k)
+    }
+}
+
+/// An exported function declaration parsed from the generated bindings.
+pub(crate) struct ExternFn {
+    pub name: String,
+    pub params: Vec<(String, Type)>,
+    pub ret: Option<Type>,
+}
+
+/// Find the declarations of the extern functions in the bindings.
+pub(crate) fn extern_fns(bindings: &str) -> syn::Result<Vec<ExternFn>> {
+    let file = syn::parse_file(bindings)?;
+    let mut fns = Vec::new();
+    for item in &file.items {
+        let Item::ForeignMod(m) = item else {
+            continue;
+        };
+        for f in &m.items {
+            let ForeignItem::Fn(f) = f else {
+                continue;
+            };
+            let params = f
+                .sig
+                .inputs
+                .iter()
+                .filter_map(|a| match a {
+                    FnArg::Typed(PatType { pat, ty, .. }) => match &**pat {
+                        Pat::Ident(p) => Some((p.ident.to_string(), (**ty).clone())),
+                        _ => None,
+                    },
+                    FnArg::Receiver(_) => None,
+                })
+                .collect();
+            let ret = match &f.sig.output {
+                ReturnType::Default => None,
+                ReturnType::Type(_, ty) => Some((**ty).clone()),
+            };
+            fns.push(ExternFn {
+                name: f.sig.ident.to_string(),
+                params,
+                ret,
+            });
+        }
+    }
+    Ok(fns)
+}
+
+/// Write out `ty` as Rust code, spelling pointers and paths the way bindgen does.
+fn type_name(ty: &Type) -> String {
+    match ty {
+        Type::Ptr(p) => format!(
+            "*{} {}",
+            if p.mutability.is_some() {
+                "mut"
+            } else {
+                "const"
+            },
+            type_name(&p.elem)
+        ),
+        Type::Path(p)
+            if p.qself.is_none() && p.path.segments.iter().all(|s| s.arguments.is_empty()) =>
+        {
+            let segments: Vec<_> = p
+                .path
+                .segments
+                .iter()
+                .map(|s| s.ident.to_string())
+                .collect();
+            let leading = if p.path.leading_colon.is_some() {
+                "::"
+            } else {
+                ""
+            };
+            format!("{leading}{}", segments.join("::"))
+        }
+        ty => ty.to_token_stream().to_string(),
+    }
+}
+
+/// Generate the `safe` module with a wrapper for each described function.
+pub(crate) fn generate(bindings: &str, wrappers: &[SafeWrapper]) -> Result<String, IspcError> {
+    let fns = extern_fns(bindings).map_err(|e| IspcError::SafeWrapper {
+        function: wrappers[0].name.clone(),
+        message: format!("failed to parse the generated bindings: {e}"),
+    })?;
+    let mut out = String::from("pub mod safe {\n    #[allow(unused_imports)]\n    use super::*;\n");
+    for w in wrappers {
+        let f = fns
+            .iter()
+            .find(|f| f.name == w.name)
+            .ok_or_else(|| IspcError::SafeWrapper {
+                function: w.name.clone(),
+                message: String::from("no exported function with this name was found"),
+            })?;
+        out.push_str(&wrap(f, w)?);
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+fn wrap(f: &ExternFn, w: &SafeWrapper) -> Result<String, IspcError> {
+    let err = |message: String| IspcError::SafeWrapper {
+        function: f.name.clone(),
+        message,
+    };
+    for (p, _) in &w.params {
+        if !f.params.iter().any(|(n, _)| n == p) {
+            return Err(err(format!("the function has no parameter named {p}")));
+        }
+    }
+    let is_param = |n: &str| f.params.iter().any(|(p, _)| p == n);
+
+    // Length parameters are removed from the wrapper and set from the slices
+    let is_length = |n: &str| {
+        w.params
+            .iter()
+            .any(|(_, k)| matches!(k, ParamKind::Slice(l) | ParamKind::SliceMut(l) if l == n))
+    };
+
+    let mut sig = Vec::new();
+    let mut args = Vec::new();
+    let mut len_checks = String::new();
+    let mut min_len_checks = String::new();
+    // The first slice using each length parameter sets its value
+    let mut lengths: Vec<(&str, &str)> = Vec::new();
+    for (name, ty) in &f.params {
+        let pointer = match ty {
+            Type::Ptr(p) => Some((&*p.elem, p.mutability.is_some())),
+            _ => None,
+        };
+        let ty = type_name(ty);
+        let (kind, (pointee, is_mut)) = match (w.kind(name), pointer) {
+            // The wrapper can't tell whether the inner pointers are valid
+            (Some(_), Some((Type::Ptr(_), _))) => {
+                return Err(err(format!(
+                    "parameter {name} has type {ty}, pointers to pointers can't be wrapped"
+                )))
+            }
+            (Some(k), Some(p)) => (k, p),
+            (Some(_), None) => {
+                return Err(err(format!(
+                    "parameter {name} has type {ty}, only pointers can be wrapped"
+                )))
+            }
+            (None, Some(_)) => {
+                return Err(err(format!(
+                    "pointer parameter {name} must be described as a slice or reference"
+                )))
+            }
+            (None, None) => {
+                if !is_length(name) {
+                    sig.push(format!("{name}: {ty}"));
+                }
+                args.push(name.clone());
+                continue;
+            }
+        };
+        let pointee = type_name(pointee);
+        // ISPC may write through pointers which aren't const
+        if is_mut {
+            let mutable = match kind {
+                ParamKind::Slice(_) => Some("slice_mut"),
+                ParamKind::Ref => Some("reference_mut"),
+                _ => None,
+            };
+            if let Some(mutable) = mutable {
+                return Err(err(format!(
+                    "parameter {name} has type {ty}, so it must be taken with {mutable} \
+                     unless it's declared const in the ISPC code"
+                )));
+            }
+        }
+        match kind {
+            ParamKind::Slice(_) => {
+                sig.push(format!("{name}: &[{pointee}]"));
+                args.push(format!("{name}.as_ptr()"));
+            }
+            ParamKind::SliceMut(_) => {
+                sig.push(format!("{name}: &mut [{pointee}]"));
+                args.push(format!("{name}.as_mut_ptr()"));
+            }
+            ParamKind::Ref => {
+                sig.push(format!("{name}: &{pointee}"));
+                args.push(format!("{name} as *const {pointee}"));
+            }
+            ParamKind::RefMut => {
+                sig.push(format!("{name}: &mut {pointee}"));
+                args.push(format!("{name} as *mut {pointee}"));
+            }
+        }
+        if let ParamKind::Slice(len) | ParamKind::SliceMut(len) = kind {
+            if !is_param(len) {
+                // A negative or oversized length must not wrap around to a small one
+                writeln!(
+                    min_len_checks,
+                    "        let {name}_min_len = usize::try_from({len}).expect(\"{0}: {len} is not a valid length for {name}\");\n        \
+                     assert!({name}.len() >= {name}_min_len, \"{0}: {name} must have at least {len} elements\");",
+                    f.name
+                )
+                .unwrap();
+            } else if let Some((_, first)) = lengths.iter().find(|(l, _)| l == len) {
+                writeln!(
+                    len_checks,
+                    "        assert_eq!({name}.len(), {first}.len(), \"{}: {name} and {first} must have the same length\");",
+                    f.name
+                )
+                .unwrap();
+            } else {
+                lengths.push((len, name));
+            }
+        }
+    }
+    let mut body = len_checks;
+    for (len, slice) in &lengths {
+        let ty = type_name(&f.params.iter().find(|(p, _)| p == len).unwrap().1);
+        writeln!(
+            body,
+            "        let {len}: {ty} = {slice}.len().try_into().expect(\"{}: {slice} is too long\");",
+            f.name
+        )
+        .unwrap();
+    }
+    body.push_str(&min_len_checks);
+    let ret = f
+        .ret
+        .as_ref()
+        .map(|r| format!(" -> {}", type_name(r)))
+        .unwrap_or_default();
+    Ok(format!(
+        "    /// Safe wrapper for the exported ISPC function `{name}`.\n    pub fn {name}({sig}){ret} {{\n        super::ispc_rs_verify();\n{body}        unsafe {{ super::{name}({args}) }}\n    }}\n",
+        name = f.name,
+        sig = sig.join(", "),
+        args = args.join(", "),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BINDINGS: &str = r#"
+extern "C" {
+    pub fn add(a: *const f32, b: *const f32, out: *mut f32, count: i32);
+}
+extern "C" {
+    pub fn sum(vals: *const f32, count: u32) -> f32;
+}
+extern "C" {
+    pub fn scale(params: *mut Params, vals: *mut f32, n: i32, stride: i32);
+}
+extern "C" {
+    pub fn apply(
+        vals: *mut f32,
+        count: i32,
+        f: ::std::option::Option<unsafe extern "C" fn(x: f32) -> f32>,
+    );
+}
+extern "C" {
+    pub fn gather(rows: *const *const f32, out: *mut f32, count: i32);
+}
+"#;
+
+    fn extern_fn(name: &str) -> ExternFn {
+        extern_fns(BINDINGS)
+            .unwrap()
+            .into_iter()
+            .find(|f| f.name == name)
+            .unwrap()
+    }
+
+    fn message(e: IspcError) -> String {
+        match e {
+            IspcError::SafeWrapper { message, .. } => message,
+            e => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[test]
+    fn parses_extern_fns() {
+        let fns = extern_fns(BINDINGS).unwrap();
+        let names: Vec<_> = fns.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["add", "sum", "scale", "apply", "gather"]);
+        let params: Vec<_> = fns[0]
+            .params
+            .iter()
+            .map(|(n, t)| format!("{n}: {}", type_name(t)))
+            .collect();
+        assert_eq!(
+            params,
+            [
+                "a: *const f32",
+                "b: *const f32",
+                "out: *mut f32",
+                "count: i32"
+            ]
+        );
+        assert!(fns[0].ret.is_none());
+        assert_eq!(fns[1].ret.as_ref().map(type_name).as_deref(), Some("f32"));
+
+        // The parentheses of function pointer parameters don't end the parameter list
+        let params: Vec<_> = fns[3].params.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(params, ["vals", "count", "f"]);
+        assert!(type_name(&fns[3].params[2].1).contains("fn (x : f32) -> f32"));
+        assert_eq!(type_name(&fns[4].params[0].1), "*const *const f32");
+
+        assert!(extern_fns("extern \"C\" { pub fn broken(a: i32; }").is_err());
+    }
+
+    #[test]
+    fn wraps_slices_sharing_a_length() {
+        let w = SafeWrapper::new("add")
+            .slice("a", "count")
+            .slice("b", "count")
+            .slice_mut("out", "count");
+        let safe = wrap(&extern_fn("add"), &w).unwrap();
+        assert!(safe.contains("pub fn add(a: &[f32], b: &[f32], out: &mut [f32]) {"));
+        assert!(safe.contains("assert_eq!(b.len(), a.len()"));
+        assert!(safe.contains("assert_eq!(out.len(), a.len()"));
+        assert!(safe.contains("let count: i32 = a.len().try_into()"));
+        assert!(
+            safe.contains("unsafe { super::add(a.as_ptr(), b.as_ptr(), out.as_mut_ptr(), count) }")
+        );
+    }
+
+    #[test]
+    fn wraps_minimum_lengths_and_references() {
+        let w = SafeWrapper::new("scale")
+            .reference_mut("params")
+            .slice_mut("vals", "n * stride");
+        let safe = wrap(&extern_fn("scale"), &w).unwrap();
+        assert!(safe.contains(
+            "pub fn scale(params: &mut Params, vals: &mut [f32], n: i32, stride: i32) {"
+        ));
+        assert!(safe.contains("let vals_min_len = usize::try_from(n * stride).expect("));
+        assert!(safe.contains("assert!(vals.len() >= vals_min_len"));
+        assert!(safe.contains("super::scale(params as *mut Params, vals.as_mut_ptr(), n, stride)"));
+
+        let w = SafeWrapper::new("sum").slice("vals", "count");
+        let safe = wrap(&extern_fn("sum"), &w).unwrap();
+        assert!(safe.contains("pub fn sum(vals: &[f32]) -> f32 {"));
+    }
+
+    #[test]
+    fn passes_function_pointers_through() {
+        let w = SafeWrapper::new("apply").slice_mut("vals", "count");
+        let safe = wrap(&extern_fn("apply"), &w).unwrap();
+        assert!(
+            safe.contains("pub fn apply(vals: &mut [f32], f: "),
+            "{safe}"
+        );
+        assert!(
+            safe.contains("super::apply(vals.as_mut_ptr(), count, f)"),
+            "{safe}"
+        );
+    }
+
+    #[test]
+    fn rejects_pointers_to_pointers() {
+        let w = SafeWrapper::new("gather")
+            .slice("rows", "count")
+            .slice_mut("out", "count");
+        let e = message(wrap(&extern_fn("gather"), &w).unwrap_err());
+        assert!(e.contains("rows has type *const *const f32"), "{e}");
+        assert!(e.contains("pointers to pointers"), "{e}");
+    }
+
+    #[test]
+    fn rejects_shared_borrows_of_mut_pointers() {
+        let w = SafeWrapper::new("add")
+            .slice("a", "count")
+            .slice("b", "count")
+            .slice("out", "count");
+        let e = message(wrap(&extern_fn("add"), &w).unwrap_err());
+        assert!(e.contains("out has type *mut f32"), "{e}");
+        assert!(e.contains("slice_mut"), "{e}");
+
+        let w = SafeWrapper::new("scale")
+            .reference("params")
+            .slice_mut("vals", "n");
+        let e = message(wrap(&extern_fn("scale"), &w).unwrap_err());
+        assert!(e.contains("reference_mut"), "{e}");
+    }
+
+    #[test]
+    fn rejects_incomplete_descriptions() {
+        let w = SafeWrapper::new("add")
+            .slice("a", "count")
+            .slice("b", "count");
+        let e = message(wrap(&extern_fn("add"), &w).unwrap_err());
+        assert!(e.contains("pointer parameter out must be described"), "{e}");
+
+        let w = SafeWrapper::new("sum").slice("values", "count");
+        let e = message(wrap(&extern_fn("sum"), &w).unwrap_err());
+        assert!(e.contains("no parameter named values"), "{e}");
+
+        let w = SafeWrapper::new("sum")
+            .slice("vals", "count")
+            .slice("count", "1");
+        let e = message(wrap(&extern_fn("sum"), &w).unwrap_err());
+        assert!(e.contains("only pointers can be wrapped"), "{e}");
+
+        let e = message(generate(BINDINGS, &[SafeWrapper::new("mul")]).unwrap_err());
+        assert!(e.contains("no exported function"), "{e}");
+    }
+}
@@ -0,0 +1,139 @@
+//! Procedural macros for writing ISPC code inline in Rust source files.
+//!
+//! The `ispc!` macro holds the ISPC source of a small library alongside the
+//! Rust code using it. The build script finds the macro invocations in the
+//! Rust file and compiles each one with `ispc_compile`, then the macro expands
+//! to the generated bindings module, in the same way as `ispc_module!`.
+//!
+//! The macro never runs ISPC: a procedural macro can't emit the `cargo:`
+//! directives to link the library or rebuild when the source changes, so a
+//! build script calling `Config::compile_inline` on each Rust file using
+//! `ispc!` is required. Without it the expansion fails as `ISPC_OUT_DIR` is
+//! not set.
+//!
+//! ```toml
+//! # Cargo.toml
+//! [dependencies]
+//! ispc = "2.0"
+//!
+//! [build-dependencies]
+//! ispc = "2.0"
+//! ```
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     ispc::Config::new().compile_inline("src/main.rs");
+//! }
+//! ```
+//!
+//! ```ignore
+//! // src/main.rs
+//! ispc::ispc!(scale, r#"
+//!     export void scale(uniform float vals[], uniform float s, uniform int count) {
+//!         foreach (i = 0 ... count) {
+//!             vals[i] *= s;
+//!         }
+//!     }
+//! "#);
+//!
+//! fn main() {
+//!     let mut vals = vec![1.0; 16];
+//!     unsafe { scale::scale(vals.as_mut_ptr(), 2.0, vals.len() as i32) };
+//! }
+//! ```
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Delimiter, TokenTree};
+
+/// Import the bindings to ISPC code written inline, as `ispc!(name, r#"..."#)`.
+///
+/// The first argument names the library and the bindings module, the second
+/// is a string literal containing the ISPC source. The source is compiled by
+/// `Config::compile_inline` in the build script, which must be passed the Rust
+/// file containing the macro, the macro only checks its arguments and imports
+/// the bindings. The bindings check the library with `ispc::verify_abi`, so
+/// the macro must be used through the `ispc` crate.
+#[proc_macro]
+pub fn ispc(input: TokenStream) -> TokenStream {
+    match parse(input.into()) {
+        Ok(name) => format!(
+            "#[allow(unused_macros)]\n\
+             macro_rules! ispc_rs_verify_abi {{\n\
+                 ($name:expr, $bindings_hash:expr, $library_hash:expr) => {{\n\
+                     ::ispc::verify_abi($name, $bindings_hash, $library_hash)\n\
+                 }};\n\
+             }}\n\
+             include!(concat!(env!(\"ISPC_OUT_DIR\"), \"/{name}.rs\"));"
+        )
+        .parse()
+        .unwrap(),
+        Err(message) => format!("compile_error!({message:?});").parse().unwrap(),
+    }
+}
+
+/// Check the macro was passed a name and a string literal, returning the name.
+fn parse(input: proc_macro2::TokenStream) -> Result<String, String> {
+    const USAGE: &str = "expected ispc!(name, r#\"ISPC source\"#)";
+    let mut tokens = input.into_iter().flat_map(|t| match t {
+        // Arguments forwarded from another macro may be wrapped in a group
+        TokenTree::Group(g) if g.delimiter() == Delimiter::None => g.stream().into_iter().collect(),
+        t => vec![t],
+    });
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(name)) => name.to_string(),
+        _ => return Err(String::from(USAGE)),
+    };
+    match tokens.next() {
+        Some(TokenTree::Punct(p)) if p.as_char() == ',' => {}
+        _ => return Err(String::from(USAGE)),
+    }
+    match tokens.next() {
+        Some(TokenTree::Literal(l)) if l.to_string().starts_with(['"', 'r']) => {}
+        _ => return Err(String::from(USAGE)),
+    }
+    match (tokens.next(), tokens.next()) {
+        (None, None) => Ok(name),
+        (Some(TokenTree::Punct(p)), None) if p.as_char() == ',' => Ok(name),
+        _ => Err(String::from(USAGE)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(input: &str) -> Result<String, String> {
+        parse(input.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_name_and_source() {
+        assert_eq!(
+            parse_str(r##"scale, r#"export void f() { if (true) { } }"#"##).as_deref(),
+            Ok("scale")
+        );
+        assert_eq!(
+            parse_str(r#"sum, "export void f() {}","#).as_deref(),
+            Ok("sum")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        for input in [
+            "",
+            "scale",
+            "scale,",
+            r#""scale", "source""#,
+            "scale, 1",
+            r#"scale "source""#,
+            r#"scale, "source", extra"#,
+            r#"scale, "source",,"#,
+        ] {
+            assert!(parse_str(input).is_err(), "{input}");
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! Reading the symbol table of a static library, to check the functions
+//! declared in the packaged bindings are exported by the packaged library.
+//!
+//! Static libraries are `ar` archives, which begin with a member listing the
+//! symbols defined by the objects in the archive. The GNU, BSD (macOS) and
+//! MSVC variants of the format use different member names and layouts for the
+//! symbol table, which are all handled here.
+
+use std::collections::BTreeSet;
+
+const MAGIC: &[u8] = b"!<arch>\n";
+const HEADER_LEN: usize = 60;
+
+/// Read the names of the symbols defined in the archive `data`. Returns an
+/// error message if the archive is invalid or has no symbol table.
+pub(crate) fn symbols(data: &[u8]) -> Result<BTreeSet<String>, String> {
+    if !data.starts_with(MAGIC) {
+        return Err(String::from("not an archive file"));
+    }
+    // The symbol table must be the first member
+    let offset = MAGIC.len();
+    let header = data
+        .get(offset..offset + HEADER_LEN)
+        .filter(|h| &h[58..60] == b"`\n")
+        .ok_or_else(|| String::from("invalid member header"))?;
+    let size = field(&header[48..58])
+        .parse::<usize>()
+        .map_err(|_| String::from("invalid member size"))?;
+    let start = offset + HEADER_LEN;
+    let mut member = start
+        .checked_add(size)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(|| String::from("truncated archive"))?;
+    let mut name = field(&header[..16]).to_owned();
+    // BSD archives store long names at the start of the member data
+    if let Some(len) = name.strip_prefix("#1/") {
+        let len = len
+            .parse::<usize>()
+            .ok()
+            .filter(|l| *l <= member.len())
+            .ok_or_else(|| String::from("invalid member name"))?;
+        name = String::from_utf8_lossy(&member[..len])
+            .trim_end_matches('\0')
+            .to_owned();
+        member = &member[len..];
+    }
+    match name.as_str() {
+        // The GNU symbol table, which MSVC also writes as its first linker
+        // member, followed by another in its own format with the same symbols
+        "/" => gnu_symbols(member, 4),
+        "/SYM64/" => gnu_symbols(member, 8),
+        "__.SYMDEF" | "__.SYMDEF SORTED" => bsd_symbols(member, 4),
+        "__.SYMDEF_64" | "__.SYMDEF_64 SORTED" => bsd_symbols(member, 8),
+        _ => Err(String::from("the archive has no symbol table")),
+    }
+}
+
+/// A space padded field of a member header.
+fn field(bytes: &[u8]) -> &str {
+    std::str::from_utf8(bytes).unwrap_or("").trim_end()
+}
+
+fn read_uint(data: &[u8], offset: usize, width: usize, big_endian: bool) -> Option<usize> {
+    let bytes = data.get(offset..offset + width)?;
+    let value = bytes.iter().enumerate().fold(0u64, |v, (i, b)| {
+        let shift = if big_endian { width - 1 - i } else { i };
+        v | (u64::from(*b) << (8 * shift))
+    });
+    usize::try_from(value).ok()
+}
+
+/// The GNU symbol table is a big endian count of symbols followed by the
+/// offset of the member defining each symbol, then the symbol names as null
+/// terminated strings.
+fn gnu_symbols(member: &[u8], width: usize) -> Result<BTreeSet<String>, String> {
+    let invalid = || String::from("invalid GNU symbol table");
+    let count = read_uint(member, 0, width, true).ok_or_else(invalid)?;
+    let names_start = count
+        .checked_add(1)
+        .and_then(|c| c.checked_mul(width))
+        .filter(|s| *s <= member.len())
+        .ok_or_else(invalid)?;
+    // Each name must be null terminated, and an empty name means the count
+    // doesn't match the table
+    let mut names = BTreeSet::new();
+    let mut rest = &member[names_start..];
+    for _ in 0..count {
+        let len = rest
+            .iter()
+            .position(|b| *b == 0)
+            .filter(|l| *l > 0)
+            .ok_or_else(invalid)?;
+        names.insert(String::from_utf8_lossy(&rest[..len]).into_owned());
+        rest = &rest[len + 1..];
+    }
+    Ok(names)
+}
+
+/// The BSD symbol table is the size in bytes of a list of (name offset,
+/// member offset) pairs, the pairs, then the size of the string table and the
+/// null terminated names, all little endian.
+fn bsd_symbols(member: &[u8], width: usize) -> Result<BTreeSet<String>, String> {
+    let invalid = || String::from("invalid BSD symbol table");
+    let ranlib_size = read_uint(member, 0, width, false).ok_or_else(invalid)?;
+    let strings_offset = width
+        .checked_add(ranlib_size)
+        .and_then(|o| o.checked_add(width))
+        .ok_or_else(invalid)?;
+    let strings_size = read_uint(member, width + ranlib_size, width, false).ok_or_else(invalid)?;
+    let strings = strings_offset
+        .checked_add(strings_size)
+        .and_then(|end| member.get(strings_offset..end))
+        .ok_or_else(invalid)?;
+    let mut names = BTreeSet::new();
+    for i in 0..ranlib_size / (2 * width) {
+        let name = read_uint(member, width + i * 2 * width, width, false).ok_or_else(invalid)?;
+        let name = strings.get(name..).ok_or_else(invalid)?;
+        let len = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+        names.insert(String::from_utf8_lossy(&name[..len]).into_owned());
+    }
+    Ok(names)
+}
+
+/// Find the symbols declared in the `extern "C"` blocks of the Rust bindings,
+/// using the `link_name` if one is given for the declaration.
+pub(crate) fn binding_symbols(bindings: &str) -> BTreeSet<String> {
+    let mut symbols = BTreeSet::new();
+    let mut depth = 0usize;
+    let mut extern_depth = None;
+    let mut link_name = None;
+    for line in bindings.lines().map(str::trim) {
+        let extern_block =
+            line.contains("extern \"C\"") && !line.contains("fn ") && line.ends_with('{');
+        if extern_depth.is_none() && extern_block {
+            extern_depth = Some(depth);
+        } else if extern_depth.is_some() {
+            if let Some(name) = line
+                .strip_prefix("#[link_name = \"")
+                .and_then(|l| l.strip_suffix("\"]"))
+            {
+                // Bindgen marks names which must not be mangled with \u{1}
+                link_name = Some(name.trim_start_matches("\\u{1}").to_owned());
+            } else if let Some(decl) = line
+                .strip_prefix("pub fn ")
+                .or_else(|| line.strip_prefix("pub static mut "))
+                .or_else(|| line.strip_prefix("pub static "))
+            {
+                let name: String = decl
+                    .chars()
+                    .take_while(|c| c.is_alphanumeric() || *c == '_')
+                    .collect();
+                symbols.insert(link_name.take().unwrap_or(name));
+            }
+        }
+        depth += line.matches('{').count();
+        depth = depth.saturating_sub(line.matches('}').count());
+        if extern_depth.is_some_and(|d| depth <= d) {
+            extern_depth = None;
+        }
+    }
+    symbols
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An archive with `member` as its first member.
+    fn archive(name: &str, member: &[u8]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        let header = format!(
+            "{name:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            0,
+            0,
+            0,
+            644,
+            member.len()
+        );
+        assert_eq!(header.len(), HEADER_LEN);
+        data.extend_from_slice(header.as_bytes());
+        data.extend_from_slice(member);
+        data
+    }
+
+    fn gnu_table(names: &[&str], width: usize) -> Vec<u8> {
+        let mut member = names.len().to_be_bytes()[8 - width..].to_vec();
+        for i in 0..names.len() {
+            member.extend_from_slice(&(100 + i).to_be_bytes()[8 - width..]);
+        }
+        for n in names {
+            member.extend_from_slice(n.as_bytes());
+            member.push(0);
+        }
+        member
+    }
+
+    fn bsd_table(names: &[&str], width: usize) -> Vec<u8> {
+        let le = |v: usize| v.to_le_bytes()[..width].to_vec();
+        let mut strings = Vec::new();
+        let mut ranlibs = Vec::new();
+        for n in names {
+            ranlibs.extend(le(strings.len()));
+            ranlibs.extend(le(100));
+            strings.extend_from_slice(n.as_bytes());
+            strings.push(0);
+        }
+        let mut member = le(ranlibs.len());
+        member.extend(ranlibs);
+        member.extend(le(strings.len()));
+        member.extend(strings);
+        member
+    }
+
+    fn set(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_gnu_symbol_tables() {
+        let names = ["add_lists", "sum", "add_lists_avx2"];
+        let data = archive("/", &gnu_table(&names, 4));
+        assert_eq!(symbols(&data).unwrap(), set(&names));
+        let data = archive("/SYM64/", &gnu_table(&names, 8));
+        assert_eq!(symbols(&data).unwrap(), set(&names));
+        assert!(symbols(&archive("/", &gnu_table(&[], 4)))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn reads_bsd_symbol_tables() {
+        let names = ["_add_lists", "_sum"];
+        let data = archive("__.SYMDEF SORTED", &bsd_table(&names, 4));
+        assert_eq!(symbols(&data).unwrap(), set(&names));
+        let data = archive("__.SYMDEF_64", &bsd_table(&names, 8));
+        assert_eq!(symbols(&data).unwrap(), set(&names));
+
+        // Long member names are stored before the member data, null padded
+        let mut member = b"__.SYMDEF SORTED\0\0\0\0".to_vec();
+        member.extend(bsd_table(&names, 4));
+        let data = archive("#1/20", &member);
+        assert_eq!(symbols(&data).unwrap(), set(&names));
+    }
+
+    #[test]
+    fn rejects_invalid_archives() {
+        let error = |data: &[u8]| symbols(data).unwrap_err();
+        assert_eq!(error(b"\x7fELF"), "not an archive file");
+        assert_eq!(error(b"!<arch>\n/   "), "invalid member header");
+
+        let table = gnu_table(&["add_lists", "sum"], 4);
+        let data = archive("/", &table);
+        assert_eq!(error(&data[..data.len() - 4]), "truncated archive");
+
+        let mut bad_size = data.clone();
+        bad_size[MAGIC.len() + 48..MAGIC.len() + 58].copy_from_slice(b"12x       ");
+        assert_eq!(error(&bad_size), "invalid member size");
+
+        assert_eq!(
+            error(&archive("foo.o/", &table)),
+            "the archive has no symbol table"
+        );
+        assert_eq!(error(&archive("#1/99", b"short")), "invalid member name");
+    }
+
+    #[test]
+    fn rejects_corrupt_symbol_tables() {
+        // More symbols than there are offsets or names
+        let mut table = gnu_table(&["add_lists", "sum"], 4);
+        table[3] = 3;
+        assert_eq!(
+            symbols(&archive("/", &table)).unwrap_err(),
+            "invalid GNU symbol table"
+        );
+        table[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(symbols(&archive("/", &table)).is_err());
+        assert!(symbols(&archive("/SYM64/", &[0, 0, 0])).is_err());
+
+        // Name offsets past the string table, or a string table past the end
+        let mut table = bsd_table(&["_add_lists", "_sum"], 4);
+        table[4] = 200;
+        assert_eq!(
+            symbols(&archive("__.SYMDEF", &table)).unwrap_err(),
+            "invalid BSD symbol table"
+        );
+        let mut table = bsd_table(&["_add_lists", "_sum"], 4);
+        table[0] = 200;
+        assert!(symbols(&archive("__.SYMDEF", &table)).is_err());
+        let table = bsd_table(&["_add_lists", "_sum"], 8);
+        assert!(symbols(&archive("__.SYMDEF_64", &table[..table.len() - 2])).is_err());
+    }
+
+    #[test]
+    fn finds_binding_symbols() {
+        let bindings = r#"
+pub mod simple {
+    #[repr(C)]
+    pub struct Params {
+        pub n: i32,
+    }
+    unsafe extern "C" {
+        pub fn add_lists(a: *mut f32, count: i32);
+    }
+    extern "C" {
+        #[link_name = "\u{1}sum_v2"]
+        pub fn sum(a: *const f32, count: i32) -> f32;
+        pub static mut counter: i32;
+        pub static table: [f32; 4];
+    }
+    pub fn not_extern() {}
+    unsafe extern "C" {
+        pub fn ispc_rs_abi_hash_simple() -> u64;
+    }
+}
+pub fn after() {}
+"#;
+        assert_eq!(
+            binding_symbols(bindings),
+            set(&[
+                "add_lists",
+                "sum_v2",
+                "counter",
+                "table",
+                "ispc_rs_abi_hash_simple"
+            ])
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! A build script helper which links the packaged ISPC library when it can be
+//! used, and otherwise compiles the ISPC code from source when the `compile`
+//! feature is enabled. This replaces the separate build script functions for
+//! linking and compiling described in the crate docs.
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     ispc_rt::ModuleBuilder::new("simple")
+//!         .lib_path("src/")
+//!         .file("src/simple.ispc")
+//!         .target_isas(&["sse2-i32x4", "avx2-i32x8"])
+//!         // Rebuild the packaged library when building with the crate's ispc feature
+//!         .update_package(cfg!(feature = "ispc"))
+//!         .build();
+//! }
+//! ```
+
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::package::PackageError;
+use crate::PackagedModule;
+
+#[cfg(feature = "compile")]
+type ConfigureFn = dyn Fn(&mut ispc_compile::Config);
+
+/// Links a packaged ISPC library, falling back to compiling it from source
+/// if it wasn't packaged for the target or doesn't match its manifest.
+pub struct ModuleBuilder {
+    package: PackagedModule,
+    files: Vec<PathBuf>,
+    target_isas: Vec<String>,
+    update_package: bool,
+    #[cfg(feature = "compile")]
+    configure: Vec<Box<ConfigureFn>>,
+}
+
+/// How the library was provided by `ModuleBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleSource {
+    /// The packaged library was linked.
+    Packaged,
+    /// The library was compiled from source.
+    Compiled,
+}
+
+/// Errors from providing the library with `ModuleBuilder`. The `reason` is
+/// why the packaged library couldn't be used, or `None` if the package was
+/// being updated.
+#[derive(Debug)]
+pub enum BuildError {
+    /// The library had to be compiled from source, but the `compile` feature
+    /// of `ispc_rt` isn't enabled.
+    CompileDisabled {
+        lib: String,
+        reason: Option<Box<PackageError>>,
+    },
+    /// Compiling the library from source failed.
+    Compile {
+        lib: String,
+        reason: Option<Box<PackageError>>,
+        error: Box<dyn Error + Send + Sync>,
+    },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::CompileDisabled {
+                lib,
+                reason: Some(reason),
+            } => write!(
+                f,
+                "The packaged ISPC library {lib} can't be used: {reason}\n\
+                 Enable the compile feature of ispc_rt to compile it from source instead"
+            ),
+            BuildError::CompileDisabled { lib, reason: None } => write!(
+                f,
+                "Updating the package of the ISPC library {lib} requires the \
+                 compile feature of ispc_rt"
+            ),
+            BuildError::Compile { lib, reason, error } => {
+                write!(f, "Failed to compile the ISPC library {lib}: {error}")?;
+                if let Some(reason) = reason {
+                    write!(
+                        f,
+                        "\nIt was compiled from source as the packaged library can't be used: {reason}"
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::CompileDisabled { reason, .. } => {
+                reason.as_deref().map(|e| e as &(dyn Error + 'static))
+            }
+            BuildError::Compile { error, .. } => Some(&**error),
+        }
+    }
+}
+
+impl ModuleBuilder {
+    /// Create a builder for the library named `lib`, without any prefix or
+    /// suffix, as in `PackagedModule::new`.
+    pub fn new(lib: &str) -> ModuleBuilder {
+        ModuleBuilder {
+            package: PackagedModule::new(lib),
+            files: Vec::new(),
+            target_isas: Vec::new(),
+            update_package: false,
+            #[cfg(feature = "compile")]
+            configure: Vec::new(),
+        }
+    }
+    /// Specify the path to search for the packaged ISPC library and bindings.
+    /// Defaults to `OUT_DIR`, as in `PackagedModule::lib_path`.
+    pub fn lib_path<P: AsRef<Path>>(&mut self, path: P) -> &mut ModuleBuilder {
+        self.package.lib_path(path);
+        self
+    }
+    /// Add an ISPC file to compile if the packaged library can't be used.
+    pub fn file<P: AsRef<Path>>(&mut self, file: P) -> &mut ModuleBuilder {
+        self.files.push(file.as_ref().to_path_buf());
+        self
+    }
+    /// Set the target ISAs to compile for, using the ISPC names such as
+    /// `avx2-i32x8`. Defaults to the ISA `ispc_compile` picks for the target.
+    pub fn target_isas(&mut self, isas: &[&str]) -> &mut ModuleBuilder {
+        self.target_isas = isas.iter().map(|s| s.to_string()).collect();
+        self
+    }
+    /// Always compile from source and write the library, bindings and manifest
+    /// to the `lib_path`, to update the package distributed with the crate.
+    pub fn update_package(&mut self, update: bool) -> &mut ModuleBuilder {
+        self.update_package = update;
+        self
+    }
+    /// Set any other options needed to compile from source on the `Config`
+    /// before it's compiled.
+    #[cfg(feature = "compile")]
+    pub fn configure<F>(&mut self, f: F) -> &mut ModuleBuilder
+    where
+        F: Fn(&mut ispc_compile::Config) + 'static,
+    {
+        self.configure.push(Box::new(f));
+        self
+    }
+    /// Link the packaged library, or compile it from source if it can't be used.
+    ///
+    /// This will exit the process with `EXIT_FAILURE` if neither is possible,
+    /// see `try_build`.
+    pub fn build(&self) -> ModuleSource {
+        match self.try_build() {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(libc::EXIT_FAILURE);
+            }
+        }
+    }
+    /// Link the packaged library, or compile it from source if it can't be
+    /// used, returning an error instead of exiting the process if neither is
+    /// possible.
+    pub fn try_build(&self) -> Result<ModuleSource, BuildError> {
+        let lib = &self.package.lib;
+        let reason = if self.update_package {
+            println!(
+                "ispc-rs: compiling {lib} from source to update the package in {}",
+                self.package.get_lib_path().display()
+            );
+            None
+        } else {
+            match self.package.find_library(&env::var("TARGET").unwrap()) {
+                Ok(library) => {
+                    println!(
+                        "ispc-rs: linking the packaged {lib} library from {}",
+                        self.package.get_lib_path().display()
+                    );
+                    self.package.print_link_metadata(&library);
+                    return Ok(ModuleSource::Packaged);
+                }
+                Err(e) => {
+                    warn(&format!("the packaged {lib} library can't be used: {e}"));
+                    Some(Box::new(e))
+                }
+            }
+        };
+        self.compile(reason)
+    }
+
+    #[cfg(feature = "compile")]
+    fn compile(&self, reason: Option<Box<PackageError>>) -> Result<ModuleSource, BuildError> {
+        use ispc_compile::{Config, TargetISA};
+
+        let lib = &self.package.lib;
+        let err = |reason, error: Box<dyn Error + Send + Sync>| BuildError::Compile {
+            lib: lib.clone(),
+            reason,
+            error,
+        };
+        let lib_path = self.package.get_lib_path();
+        if reason.is_some() {
+            warn(&format!("compiling {lib} from source"));
+            // Switch to the packaged library if it's added or fixed
+            println!(
+                "cargo:rerun-if-changed={}",
+                crate::PackageManifest::path(&lib_path, lib).display()
+            );
+        }
+
+        let mut cfg = Config::new();
+        for f in &self.files {
+            cfg.file(f);
+        }
+        if !self.target_isas.is_empty() {
+            let isas = self
+                .target_isas
+                .iter()
+                .map(|s| s.parse::<TargetISA>())
+                .collect::<Result<Vec<_>, _>>();
+            match isas {
+                Ok(isas) => {
+                    cfg.target_isas(isas);
+                }
+                Err(e) => return Err(err(reason, Box::new(e))),
+            }
+        }
+        if self.update_package {
+            cfg.out_dir(&lib_path);
+        }
+        for f in &self.configure {
+            f(&mut cfg);
+        }
+        match cfg.try_compile(lib) {
+            Ok(_) => Ok(ModuleSource::Compiled),
+            Err(e) => Err(err(reason, Box::new(e))),
+        }
+    }
+
+    #[cfg(not(feature = "compile"))]
+    fn compile(&self, reason: Option<Box<PackageError>>) -> Result<ModuleSource, BuildError> {
+        Err(BuildError::CompileDisabled {
+            lib: self.package.lib.clone(),
+            reason,
+        })
+    }
+}
+
+/// Show a message as a Cargo warning, as the build script's output is hidden
+/// unless it fails. Each line is a separate warning since Cargo reads them by line.
+fn warn(message: &str) {
+    for line in message.lines() {
+        println!("cargo:warning=ispc-rs: {line}");
+    }
+}
@@ -0,0 +1,279 @@
+//! Detection of the ISPC target the dispatcher of a library compiled for
+//! multiple targets will select on the machine running it.
+//!
+//! The ISPC dispatcher picks the most capable target compiled into the library
+//! which the CPU supports, using the same checks as here: CPUID on x86 and the
+//! hardware capabilities reported by the OS on ARM. Libraries built with
+//! `ispc_compile` list the targets they were compiled for in the `ISPC_TARGETS`
+//! constant of their bindings, which can be passed to `selected_target` or
+//! `TargetReport::new`.
+//!
+//! ```ignore
+//! ispc_module!(simple);
+//!
+//! fn main() {
+//!     ispc_rt::print_target_report("simple", simple::ISPC_TARGETS);
+//! }
+//! ```
+
+use std::fmt;
+use std::sync::OnceLock;
+
+/// The families of ISPC targets, ordered from least to most capable. The
+/// family is the part of the target name before the vector width, e.g. the
+/// `avx2-i32x8` target is in the `Avx2` family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TargetFamily {
+    Sse2,
+    Sse41,
+    Sse42,
+    Avx1,
+    Avx2,
+    Avx2Vnni,
+    Avx512Knl,
+    Avx512Skx,
+    Avx512Icl,
+    Avx512Spr,
+    Neon,
+}
+
+impl TargetFamily {
+    /// Find the family of an ISPC target name such as `avx2-i32x8`. Returns
+    /// `None` for targets which aren't dispatched to based on the CPU, such as
+    /// GPU targets.
+    pub fn from_target(target: &str) -> Option<TargetFamily> {
+        match target.split('-').next()? {
+            "sse2" => Some(TargetFamily::Sse2),
+            "sse4.1" => Some(TargetFamily::Sse41),
+            // ISPC before 1.22 only had sse4, which required SSE4.2
+            "sse4" | "sse4.2" => Some(TargetFamily::Sse42),
+            "avx" | "avx1" => Some(TargetFamily::Avx1),
+            "avx2" => Some(TargetFamily::Avx2),
+            "avx2vnni" => Some(TargetFamily::Avx2Vnni),
+            "avx512knl" => Some(TargetFamily::Avx512Knl),
+            "avx512skx" => Some(TargetFamily::Avx512Skx),
+            "avx512icl" => Some(TargetFamily::Avx512Icl),
+            "avx512spr" => Some(TargetFamily::Avx512Spr),
+            "neon" => Some(TargetFamily::Neon),
+            _ => None,
+        }
+    }
+    /// Check if the CPU running the program supports targets in this family.
+    pub fn is_supported(&self) -> bool {
+        host_families().contains(self)
+    }
+}
+
+impl fmt::Display for TargetFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match *self {
+            TargetFamily::Sse2 => "sse2",
+            TargetFamily::Sse41 => "sse4.1",
+            TargetFamily::Sse42 => "sse4.2",
+            TargetFamily::Avx1 => "avx1",
+            TargetFamily::Avx2 => "avx2",
+            TargetFamily::Avx2Vnni => "avx2vnni",
+            TargetFamily::Avx512Knl => "avx512knl",
+            TargetFamily::Avx512Skx => "avx512skx",
+            TargetFamily::Avx512Icl => "avx512icl",
+            TargetFamily::Avx512Spr => "avx512spr",
+            TargetFamily::Neon => "neon",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The target families supported by the CPU running the program, from least
+/// to most capable. The CPU is only queried the first time this is called.
+pub fn host_families() -> &'static [TargetFamily] {
+    static FAMILIES: OnceLock<Vec<TargetFamily>> = OnceLock::new();
+    FAMILIES.get_or_init(detect)
+}
+
+/// The most capable target family supported by the CPU running the program.
+pub fn host_family() -> Option<TargetFamily> {
+    host_families().last().copied()
+}
+
+/// Find the target the ISPC dispatcher will select from those compiled into
+/// a library, or `None` if the CPU doesn't support any of them.
+pub fn selected_target<'a>(targets: &[&'a str]) -> Option<&'a str> {
+    targets
+        .iter()
+        .filter_map(|t| TargetFamily::from_target(t).map(|f| (f, *t)))
+        .filter(|(f, _)| f.is_supported())
+        .max_by_key(|(f, _)| *f)
+        .map(|(_, t)| t)
+}
+
+/// A summary of the target selected for a library on the CPU running the
+/// program, which can be logged at startup to diagnose performance issues.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetReport {
+    /// The name of the library.
+    pub lib: String,
+    /// The targets the library was compiled for.
+    pub targets: Vec<String>,
+    /// The target which will be used, if the CPU supports any of them.
+    pub selected: Option<String>,
+    /// The most capable target family the CPU supports.
+    pub host: Option<TargetFamily>,
+}
+
+impl TargetReport {
+    /// Build the report for the library `lib` compiled for `targets`, which
+    /// should be the `ISPC_TARGETS` constant from its bindings.
+    pub fn new(lib: &str, targets: &[&str]) -> TargetReport {
+        TargetReport {
+            lib: lib.to_owned(),
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            selected: selected_target(targets).map(String::from),
+            host: host_family(),
+        }
+    }
+}
+
+impl fmt::Display for TargetReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = self
+            .host
+            .map_or_else(|| String::from("unknown"), |h| h.to_string());
+        match (&self.selected, self.targets.is_empty()) {
+            (_, true) => write!(
+                f,
+                "{}: compiled for ISPC's default target (CPU supports {host})",
+                self.lib
+            ),
+            (Some(t), false) => write!(
+                f,
+                "{}: using target {t} of {} (CPU supports {host})",
+                self.lib,
+                self.targets.join(", ")
+            ),
+            (None, false) => write!(
+                f,
+                "{}: the CPU supports none of the targets {} (CPU supports {host})",
+                self.lib,
+                self.targets.join(", ")
+            ),
+        }
+    }
+}
+
+// The CPUID intrinsics are only unsafe in older versions of Rust
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[allow(unused_unsafe)]
+fn detect() -> Vec<TargetFamily> {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::{__cpuid, __cpuid_count, CpuidResult};
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+
+    let bit = |reg: u32, b: u32| reg & (1 << b) != 0;
+    // CPUID is available on every CPU Rust supports for x86 and x86_64
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    let leaf1 = unsafe { __cpuid(1) };
+    let (leaf7, leaf7_1) = if max_leaf >= 7 {
+        unsafe { (__cpuid_count(7, 0), __cpuid_count(7, 1)) }
+    } else {
+        let none = CpuidResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        };
+        (none, none)
+    };
+
+    // The OS must save the AVX and AVX-512 registers for them to be usable
+    let xcr0 = if bit(leaf1.ecx, 27) { xgetbv() } else { 0 };
+    let os_avx = xcr0 & 0x6 == 0x6;
+    let os_avx512 = xcr0 & 0xe6 == 0xe6;
+
+    let sse2 = bit(leaf1.edx, 26);
+    let sse41 = sse2 && bit(leaf1.ecx, 19);
+    let sse42 = sse41 && bit(leaf1.ecx, 20);
+    let avx = sse42 && os_avx && bit(leaf1.ecx, 28);
+    let avx2 = avx
+        && bit(leaf7.ebx, 5)
+        && bit(leaf1.ecx, 12)
+        && bit(leaf1.ecx, 29)
+        && bit(leaf7.ebx, 3)
+        && bit(leaf7.ebx, 8);
+    let avx2vnni = avx2 && bit(leaf7_1.eax, 4);
+    let avx512f = avx2 && os_avx512 && bit(leaf7.ebx, 16);
+    let knl = avx512f && bit(leaf7.ebx, 26) && bit(leaf7.ebx, 27) && bit(leaf7.ebx, 28);
+    let skx = avx512f
+        && bit(leaf7.ebx, 17)
+        && bit(leaf7.ebx, 28)
+        && bit(leaf7.ebx, 30)
+        && bit(leaf7.ebx, 31);
+    let icl = skx
+        && bit(leaf7.ebx, 21)
+        && [1, 6, 8, 9, 10, 11, 12, 14]
+            .iter()
+            .all(|b| bit(leaf7.ecx, *b));
+    let spr = icl && bit(leaf7_1.eax, 5) && bit(leaf7.edx, 23);
+
+    [
+        (sse2, TargetFamily::Sse2),
+        (sse41, TargetFamily::Sse41),
+        (sse42, TargetFamily::Sse42),
+        (avx, TargetFamily::Avx1),
+        (avx2, TargetFamily::Avx2),
+        (avx2vnni, TargetFamily::Avx2Vnni),
+        (knl, TargetFamily::Avx512Knl),
+        (skx, TargetFamily::Avx512Skx),
+        (icl, TargetFamily::Avx512Icl),
+        (spr, TargetFamily::Avx512Spr),
+    ]
+    .into_iter()
+    .filter(|(supported, _)| *supported)
+    .map(|(_, f)| f)
+    .collect()
+}
+
+/// Read XCR0, which must only be done if the OS has enabled XSAVE.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn xgetbv() -> u64 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::_xgetbv;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::_xgetbv;
+
+    #[target_feature(enable = "xsave")]
+    unsafe fn read_xcr0() -> u64 {
+        _xgetbv(0)
+    }
+    unsafe { read_xcr0() }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn detect() -> Vec<TargetFamily> {
+    // NEON is required by AArch64, the check reads HWCAP on Linux and Android
+    if std::arch::is_aarch64_feature_detected!("neon") {
+        vec![TargetFamily::Neon]
+    } else {
+        Vec::new()
+    }
+}
+
+#[cfg(all(target_arch = "arm", any(target_os = "linux", target_os = "android")))]
+fn detect() -> Vec<TargetFamily> {
+    const HWCAP_NEON: libc::c_ulong = 1 << 12;
+    if unsafe { libc::getauxval(libc::AT_HWCAP) } & HWCAP_NEON != 0 {
+        vec![TargetFamily::Neon]
+    } else {
+        Vec::new()
+    }
+}
+
+#[cfg(not(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    all(target_arch = "arm", any(target_os = "linux", target_os = "android"))
+)))]
+fn detect() -> Vec<TargetFamily> {
+    Vec::new()
+}
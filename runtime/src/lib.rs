@@ -7,24 +7,43 @@
 //! This crate also includes the various runtime components for the ISPC
 //! language, including the parallel task system and performance instrumentation.
 //!
+//! Linking packaged libraries with `PackagedModule` and `ModuleBuilder` requires
+//! the `package` feature, which is enabled by default. Disable it for the
+//! runtime dependency to leave out the dependencies used to check the package.
+//!
 
 #![allow(dead_code)]
 
 extern crate libc;
 extern crate num_cpus;
 
+#[cfg(feature = "package")]
+mod archive;
+#[cfg(feature = "package")]
+pub mod builder;
+pub mod cpu;
 pub mod exec;
 pub mod instrument;
+#[cfg(feature = "package")]
+pub mod package;
 pub mod task;
 
-use std::env;
 use std::ffi::CStr;
 use std::mem;
-use std::path::{Path, PathBuf};
 use std::sync::{Arc, Once};
+#[cfg(feature = "package")]
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
+#[cfg(feature = "package")]
+pub use crate::builder::{BuildError, ModuleBuilder, ModuleSource};
+pub use crate::cpu::{TargetFamily, TargetReport};
 pub use crate::exec::{Parallel, TaskSystem};
 pub use crate::instrument::{Instrument, SimpleInstrument};
+#[cfg(feature = "package")]
+pub use crate::package::{PackageError, PackageManifest};
 pub use crate::task::ISPCTaskFn;
 
 /// Convenience macro for generating the module to hold the raw/unsafe ISPC bindings.
@@ -33,6 +52,12 @@ pub use crate::task::ISPCTaskFn;
 /// a rust module containing bindings to the functions exported from ISPC. These
 /// can be imported by passing the name of your library to the `ispc_module` macro.
 ///
+/// The module also contains `ispc_rs_verify()`, which panics if the library linked
+/// wasn't compiled from the same ISPC code as the bindings, see `verify_abi`.
+/// Call it once before using the raw bindings, the safe wrappers call it themselves.
+/// The bindings find `verify_abi` through a macro defined here, so they must be
+/// imported with this macro instead of by including the file directly.
+///
 /// # Example
 ///
 /// ```ignore
@@ -45,10 +70,31 @@ pub use crate::task::ISPCTaskFn;
 #[macro_export]
 macro_rules! ispc_module {
     ($lib:ident) => {
+        #[allow(unused_macros)]
+        macro_rules! ispc_rs_verify_abi {
+            ($name:expr, $bindings_hash:expr, $library_hash:expr) => {
+                $crate::verify_abi($name, $bindings_hash, $library_hash)
+            };
+        }
         include!(concat!(env!("ISPC_OUT_DIR"), "/", stringify!($lib), ".rs"));
     };
 }
 
+/// Check the ISPC library `lib` linked was compiled from the same ISPC code
+/// as its bindings, panicking if it wasn't. The hashes are those of the ISPC
+/// headers the bindings were generated from and the library was compiled
+/// from. This is called by `ispc_rs_verify()` in the generated bindings.
+pub fn verify_abi(lib: &str, bindings_hash: u64, library_hash: u64) {
+    if library_hash != bindings_hash {
+        panic!(
+            "The ISPC library {lib} doesn't match its Rust bindings (library ABI hash \
+             {library_hash:016x}, bindings ABI hash {bindings_hash:016x}), rebuild the \
+             library and bindings together"
+        );
+    }
+}
+
+#[cfg(feature = "package")]
 /// A `PackagedModule` refers to an ISPC module which was previously
 /// built using `ispc_compile`, and is now distributed with
 /// the crate.
@@ -57,6 +103,7 @@ pub struct PackagedModule {
     lib: String,
 }
 
+#[cfg(feature = "package")]
 impl PackagedModule {
     /// Create a new `PackagedModule` to link against the previously compiled
     /// library named `lib`. As in `ispc_compile`, the library name should not
@@ -73,17 +120,52 @@ impl PackagedModule {
         self.path = Some(path.as_ref().to_path_buf());
         self
     }
-    /// Link with a previously built ISPC library packaged with the crate
+    /// Link with a previously built ISPC library packaged with the crate.
+    ///
+    /// This will exit the process with `EXIT_FAILURE` if the library wasn't
+    /// packaged for the target being built or was modified, see `try_link`.
     pub fn link(&self) {
+        if let Err(e) = self.try_link() {
+            eprintln!("{e}");
+            std::process::exit(libc::EXIT_FAILURE);
+        }
+    }
+    /// Link with a previously built ISPC library packaged with the crate,
+    /// returning an error instead of exiting the process if it can't be used.
+    ///
+    /// If the library was packaged with a manifest (see the `package` module),
+    /// this checks it lists the target being built and that the library and
+    /// bindings match the hashes recorded when they were compiled. The library
+    /// must also export each function declared in the bindings, and if the
+    /// manifest records the bindings weren't filtered, no other functions, see
+    /// `package::check_symbols`. This is only checked for static libraries.
+    pub fn try_link(&self) -> Result<(), PackageError> {
+        let library = self.find_library(&env::var("TARGET").unwrap())?;
+        self.print_link_metadata(&library);
+        Ok(())
+    }
+    /// Print the Cargo metadata to link the library and use its bindings.
+    fn print_link_metadata(&self, library: &PackagedLibrary) {
         let path = self.get_lib_path();
-        let libfile = self.lib.clone() + &env::var("TARGET").unwrap();
+        let target = env::var("TARGET").unwrap();
+        let libfile = self.lib.clone() + &target;
         let bindgen_file = self.lib.clone() + ".rs";
 
-        println!("cargo:rustc-link-lib=static={libfile}");
+        if library.shared {
+            println!("cargo:rustc-link-lib=dylib={libfile}");
+            // As when compiling a shared library, so the crate's binaries can
+            // be run from Cargo
+            if !target.contains("windows") {
+                println!("cargo:rustc-link-arg=-Wl,-rpath,{}", path.display());
+            }
+        } else {
+            println!("cargo:rustc-link-lib=static={libfile}");
+        }
         println!(
             "cargo:rerun-if-changed={}",
-            path.join(get_lib_filename(&libfile)).display()
+            PackageManifest::path(&path, &self.lib).display()
         );
+        println!("cargo:rerun-if-changed={}", library.path.display());
         println!(
             "cargo:rerun-if-changed={}",
             path.join(bindgen_file).display()
@@ -91,6 +173,80 @@ impl PackagedModule {
         println!("cargo:rustc-link-search=native={}", path.display());
         println!("cargo:rustc-env=ISPC_OUT_DIR={}", path.display());
     }
+    /// Check the library was packaged for the target being built, without
+    /// linking it. See `try_link` for the checks made.
+    pub fn verify(&self) -> Result<(), PackageError> {
+        self.verify_target(&env::var("TARGET").unwrap())
+    }
+    /// Check the library was packaged for the target triple `target`, e.g. to
+    /// check a package for several targets before publishing it.
+    pub fn verify_target(&self, target: &str) -> Result<(), PackageError> {
+        self.find_library(target).map(|_| ())
+    }
+    /// Find the library packaged for `target` and check it can be linked, see
+    /// `try_link` for the checks made.
+    fn find_library(&self, target: &str) -> Result<PackagedLibrary, PackageError> {
+        let path = self.get_lib_path();
+        let target = target.to_owned();
+        let libfile = self.lib.clone() + &target;
+        // Extra exports are only checked if the bindings are known to declare
+        // every function, filtered bindings only declare some of them
+        let (library, bindings, check_extra) = match PackageManifest::read(&path, &self.lib)? {
+            Some(manifest) => {
+                let packaged = manifest.verify(&path, &target)?;
+                let library = PackagedLibrary {
+                    path: path.join(&packaged.library),
+                    shared: packaged.shared,
+                };
+                (
+                    library,
+                    path.join(&packaged.bindings),
+                    packaged.bindings_filtered == Some(false),
+                )
+            }
+            None => {
+                let library = path.join(get_lib_filename(&libfile));
+                if !library.is_file() {
+                    return Err(PackageError::UnsupportedTarget {
+                        lib: self.lib.clone(),
+                        target,
+                        available: self.find_packaged_targets(&path),
+                    });
+                }
+                let library = PackagedLibrary {
+                    path: library,
+                    shared: false,
+                };
+                (library, path.join(self.lib.clone() + ".rs"), false)
+            }
+        };
+        // Only the symbol tables of static libraries can be read
+        if !library.shared {
+            package::check_symbols(&library.path, &bindings, &target, check_extra)?;
+        }
+        Ok(library)
+    }
+    /// Find the targets the library was packaged for from the names of the
+    /// library files in `path`, for packages without a manifest.
+    fn find_packaged_targets(&self, path: &Path) -> Vec<String> {
+        let mut targets: Vec<_> = fs::read_dir(path)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                let triple = name
+                    .strip_prefix("lib")
+                    .and_then(|n| n.strip_suffix(".a"))
+                    .or_else(|| name.strip_suffix(".lib"))?
+                    .strip_prefix(&self.lib)?;
+                // Triples have at least an architecture, vendor or OS and system
+                (triple.split('-').count() >= 2).then(|| triple.to_owned())
+            })
+            .collect();
+        targets.sort();
+        targets
+    }
     /// Returns the user-set output directory if they've set one, otherwise
     /// returns env("OUT_DIR")
     fn get_lib_path(&self) -> PathBuf {
@@ -106,8 +262,18 @@ impl PackagedModule {
     }
 }
 
+#[cfg(feature = "package")]
+/// The library file found for the target by `PackagedModule::find_library`.
+struct PackagedLibrary {
+    path: PathBuf,
+    shared: bool,
+}
+
+#[cfg(feature = "package")]
+/// The library file name for `libfile`, which ends with the target triple.
+/// Only the MSVC toolchain uses `.lib` files, MinGW uses `lib*.a` like Unix.
 fn get_lib_filename(libfile: &str) -> String {
-    if libfile.contains("windows") {
+    if libfile.contains("msvc") {
         format!("{libfile}.lib")
     } else {
         format!("lib{libfile}.a")
@@ -174,6 +340,14 @@ pub fn print_instrumenting_summary() {
     get_instrument().print_summary();
 }
 
+/// Print which of the targets compiled into the library `lib` the ISPC
+/// dispatcher will use on this machine, e.g. when the program starts. The
+/// targets should be the `ISPC_TARGETS` constant from the library's bindings.
+/// See `cpu::TargetReport` to log the information some other way.
+pub fn print_target_report(lib: &str, targets: &[&str]) {
+    println!("{}", TargetReport::new(lib, targets));
+}
+
 fn get_instrument() -> &'static dyn Instrument {
     // TODO: This is a bit nasty, like above
     INSTRUMENT_INIT.call_once(|| unsafe {
@@ -232,3 +406,19 @@ pub unsafe extern "C" fn ISPCInstrument(
     let active_count = mask.count_ones();
     get_instrument().instrument(file_name, note, line, mask, active_count);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_abi() {
+        verify_abi("simple", 0x1234, 0x1234);
+    }
+
+    #[test]
+    #[should_panic(expected = "The ISPC library simple doesn't match its Rust bindings")]
+    fn rejects_mismatched_abi() {
+        verify_abi("simple", 0x1234, 0x5678);
+    }
+}
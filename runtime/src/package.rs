@@ -0,0 +1,534 @@
+//! Reading and verifying the package manifest written by `ispc_compile` next
+//! to the compiled library and bindings.
+//!
+//! The manifest `<lib>.manifest.json` records each target triple the library
+//! was compiled for and, for each triple, the library file, its kind and its
+//! SHA-256,
+//! the bindings file and its SHA-256, whether the bindings were filtered, the
+//! target ISAs, the ISPC version and the arguments each source file was
+//! compiled with:
+//!
+//! ```json
+//! {
+//!   "format": 1,
+//!   "lib": "simple",
+//!   "targets": {
+//!     "x86_64-unknown-linux-gnu": {
+//!       "library": "libsimplex86_64-unknown-linux-gnu.a",
+//!       "kind": "static",
+//!       "library_sha256": "...",
+//!       "bindings": "simple.rs",
+//!       "bindings_sha256": "...",
+//!       "bindings_filtered": false,
+//!       "isas": ["sse2-i32x4", "avx2-i32x8"],
+//!       "ispc_version": "1.25.3",
+//!       "files": [{ "source": "src/simple.ispc", "args": ["-O2", "--pic"] }]
+//!     }
+//!   }
+//! }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::archive;
+
+/// The newest version of the manifest format which can be read.
+const FORMAT_VERSION: u64 = 1;
+
+/// Errors from checking a packaged library before linking it.
+#[derive(Debug)]
+pub enum PackageError {
+    /// The library wasn't packaged for the target being built.
+    UnsupportedTarget {
+        lib: String,
+        target: String,
+        available: Vec<String>,
+    },
+    /// A file listed in the manifest doesn't match the hash recorded when it
+    /// was compiled, so was modified or replaced since.
+    Modified {
+        path: PathBuf,
+        expected: String,
+        found: String,
+    },
+    /// The packaged library doesn't export the functions declared in the
+    /// packaged bindings, or exports functions missing from them.
+    SymbolMismatch {
+        library: PathBuf,
+        bindings: PathBuf,
+        missing: Vec<String>,
+        extra: Vec<String>,
+    },
+    /// The symbol table of the packaged library couldn't be read.
+    InvalidLibrary { path: PathBuf, message: String },
+    /// The manifest couldn't be parsed.
+    InvalidManifest { path: PathBuf, message: String },
+    /// A packaged file couldn't be read.
+    Io { path: PathBuf, source: io::Error },
+}
+
+impl fmt::Display for PackageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackageError::UnsupportedTarget {
+                lib,
+                target,
+                available,
+            } => {
+                write!(f, "The ISPC library {lib} was not packaged for {target}")?;
+                if available.is_empty() {
+                    write!(f, ", no packaged targets were found")
+                } else {
+                    write!(f, ", it was packaged for: {}", available.join(", "))
+                }
+            }
+            PackageError::Modified {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "The packaged file {} was modified after it was compiled \
+                 (expected SHA-256 {expected}, found {found})",
+                path.display()
+            ),
+            PackageError::SymbolMismatch {
+                library,
+                bindings,
+                missing,
+                extra,
+            } => {
+                write!(
+                    f,
+                    "The packaged library {} does not match the bindings {}",
+                    library.display(),
+                    bindings.display()
+                )?;
+                if !missing.is_empty() {
+                    write!(f, "\n  missing from the library: {}", missing.join(", "))?;
+                }
+                if !extra.is_empty() {
+                    write!(f, "\n  missing from the bindings: {}", extra.join(", "))?;
+                }
+                Ok(())
+            }
+            PackageError::InvalidLibrary { path, message } => write!(
+                f,
+                "Failed to read the symbols of {}: {message}",
+                path.display()
+            ),
+            PackageError::InvalidManifest { path, message } => write!(
+                f,
+                "Failed to read the package manifest {}: {message}",
+                path.display()
+            ),
+            PackageError::Io { path, source } => {
+                write!(f, "Failed to read {}: {source}", path.display())
+            }
+        }
+    }
+}
+
+impl Error for PackageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PackageError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// The manifest of a packaged library, listing what was compiled for each
+/// target triple.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageManifest {
+    /// The name of the library.
+    pub lib: String,
+    /// What was compiled for each target triple.
+    pub targets: BTreeMap<String, PackagedTarget>,
+}
+
+/// The library and bindings compiled for one target triple.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackagedTarget {
+    /// The file name of the library.
+    pub library: String,
+    /// Whether the library is a shared library, which is linked dynamically,
+    /// instead of a static library.
+    pub shared: bool,
+    pub library_sha256: String,
+    /// The file name of the bindings.
+    pub bindings: String,
+    pub bindings_sha256: String,
+    /// Whether the bindings were generated with an allowlist or blocklist,
+    /// leaving out some of the functions the library exports. `None` for
+    /// manifests written before this was recorded.
+    pub bindings_filtered: Option<bool>,
+    /// The target ISAs compiled for, empty if ISPC's default was used.
+    pub isas: Vec<String>,
+    pub ispc_version: String,
+    /// The source files compiled and the ISPC arguments used for each.
+    pub files: Vec<(String, Vec<String>)>,
+}
+
+impl PackageManifest {
+    /// The path of the manifest for `lib` in the directory `dir`.
+    pub fn path(dir: &Path, lib: &str) -> PathBuf {
+        dir.join(format!("{lib}.manifest.json"))
+    }
+    /// Read the manifest for `lib` from the directory `dir`. Returns `None` if
+    /// there is no manifest, e.g. for libraries packaged by older versions of
+    /// `ispc_compile`.
+    pub fn read(dir: &Path, lib: &str) -> Result<Option<PackageManifest>, PackageError> {
+        let path = PackageManifest::path(dir, lib);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(PackageError::Io { path, source: e }),
+        };
+        let invalid = |message: String| PackageError::InvalidManifest {
+            path: path.clone(),
+            message,
+        };
+        let json: Value = serde_json::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+        match json["format"].as_u64() {
+            Some(v) if v <= FORMAT_VERSION => {}
+            Some(v) => {
+                return Err(invalid(format!(
+                    "format version {v} is newer than this version of ispc_rt supports"
+                )))
+            }
+            None => return Err(invalid(String::from("missing the format version"))),
+        }
+        let targets = json["targets"]
+            .as_object()
+            .ok_or_else(|| invalid(String::from("missing the list of targets")))?;
+        let mut manifest = PackageManifest {
+            lib: string(&json, "lib").map_err(invalid)?,
+            targets: BTreeMap::new(),
+        };
+        for (triple, t) in targets {
+            let target = parse_target(t).map_err(|e| invalid(format!("{triple}: {e}")))?;
+            manifest.targets.insert(triple.clone(), target);
+        }
+        Ok(Some(manifest))
+    }
+    /// Check that the library was packaged for `target` and that its library
+    /// and bindings in `dir` haven't been modified since they were compiled.
+    pub fn verify(&self, dir: &Path, target: &str) -> Result<&PackagedTarget, PackageError> {
+        let packaged = self
+            .targets
+            .get(target)
+            .ok_or_else(|| PackageError::UnsupportedTarget {
+                lib: self.lib.clone(),
+                target: target.to_owned(),
+                available: self.targets.keys().cloned().collect(),
+            })?;
+        check_hash(&dir.join(&packaged.library), &packaged.library_sha256)?;
+        check_hash(&dir.join(&packaged.bindings), &packaged.bindings_sha256)?;
+        Ok(packaged)
+    }
+}
+
+/// Check the static library exports the functions declared in the `extern`
+/// blocks of the bindings for the target triple `target`. With `check_extra`
+/// the library also mustn't export other functions which are missing from
+/// them, which can only be checked when the bindings weren't filtered.
+pub fn check_symbols(
+    library: &Path,
+    bindings: &Path,
+    target: &str,
+    check_extra: bool,
+) -> Result<(), PackageError> {
+    let io_err = |path: &Path| {
+        let path = path.to_path_buf();
+        move |e| PackageError::Io { path, source: e }
+    };
+    let data = fs::read(library).map_err(io_err(library))?;
+    let text = fs::read_to_string(bindings).map_err(io_err(bindings))?;
+    let exported = archive::symbols(&data).map_err(|message| PackageError::InvalidLibrary {
+        path: library.to_path_buf(),
+        message,
+    })?;
+    // C symbols have a leading underscore on Apple platforms and 32-bit Windows
+    let x86 = target
+        .split('-')
+        .next()
+        .is_some_and(|a| a.starts_with('i') && a.ends_with("86"));
+    let underscore = target.contains("apple") || (x86 && target.contains("windows"));
+    let exported: BTreeSet<_> = exported
+        .iter()
+        .filter_map(|s| {
+            if underscore {
+                s.strip_prefix('_')
+            } else {
+                Some(s.as_str())
+            }
+        })
+        .collect();
+    let declared = archive::binding_symbols(&text);
+
+    let missing: Vec<_> = declared
+        .iter()
+        .filter(|s| !exported.contains(s.as_str()))
+        .cloned()
+        .collect();
+    // ISPC also exports a variant of each function for each target ISA, e.g.
+    // foo_avx2, and functions which aren't exported with mangled names
+    let is_isa_variant = |s: &str| {
+        s.rsplit_once('_').is_some_and(|(f, isa)| {
+            exported.contains(f) && isa.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+    };
+    let extra: Vec<_> = exported
+        .iter()
+        .filter(|s| {
+            check_extra
+                && !declared.contains(**s)
+                && !s.starts_with("__")
+                && !s.contains("___")
+                && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                && !is_isa_variant(s)
+        })
+        .map(|s| s.to_string())
+        .collect();
+    if !missing.is_empty() || !extra.is_empty() {
+        return Err(PackageError::SymbolMismatch {
+            library: library.to_path_buf(),
+            bindings: bindings.to_path_buf(),
+            missing,
+            extra,
+        });
+    }
+    Ok(())
+}
+
+/// Check the SHA-256 of the file at `path` matches the `expected` hex string.
+fn check_hash(path: &Path, expected: &str) -> Result<(), PackageError> {
+    let data = fs::read(path).map_err(|e| PackageError::Io {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    let found: String = Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    if found != expected {
+        return Err(PackageError::Modified {
+            path: path.to_path_buf(),
+            expected: expected.to_owned(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+fn parse_target(t: &Value) -> Result<PackagedTarget, String> {
+    let files = match t["files"].as_array() {
+        Some(files) => files
+            .iter()
+            .map(|f| Ok((string(f, "source")?, strings(f, "args")?)))
+            .collect::<Result<_, String>>()?,
+        None => Vec::new(),
+    };
+    Ok(PackagedTarget {
+        library: string(t, "library")?,
+        shared: t["kind"] == "shared",
+        library_sha256: string(t, "library_sha256")?,
+        bindings: string(t, "bindings")?,
+        bindings_sha256: string(t, "bindings_sha256")?,
+        bindings_filtered: t["bindings_filtered"].as_bool(),
+        isas: strings(t, "isas")?,
+        ispc_version: string(t, "ispc_version")?,
+        files,
+    })
+}
+
+fn string(json: &Value, key: &str) -> Result<String, String> {
+    json[key]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| format!("missing {key}"))
+}
+
+fn strings(json: &Value, key: &str) -> Result<Vec<String>, String> {
+    json[key]
+        .as_array()
+        .and_then(|a| a.iter().map(|s| s.as_str().map(String::from)).collect())
+        .ok_or_else(|| format!("missing {key}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ispc-rt-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        Sha256::digest(data)
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// Write a library, bindings and manifest for `simple` packaged for Linux
+    /// and macOS, returning the manifest text.
+    fn write_package(dir: &Path) -> String {
+        fs::write(dir.join("libsimplex86_64-unknown-linux-gnu.a"), "linux").unwrap();
+        fs::write(dir.join("libsimpleaarch64-apple-darwin.dylib"), "macos").unwrap();
+        fs::write(dir.join("simple.rs"), "bindings").unwrap();
+        let manifest = format!(
+            r#"{{
+  "format": 1,
+  "lib": "simple",
+  "targets": {{
+    "x86_64-unknown-linux-gnu": {{
+      "library": "libsimplex86_64-unknown-linux-gnu.a",
+      "kind": "static",
+      "library_sha256": "{linux}",
+      "bindings": "simple.rs",
+      "bindings_sha256": "{bindings}",
+      "bindings_filtered": false,
+      "isas": ["sse2-i32x4", "avx2-i32x8"],
+      "ispc_version": "1.25.3",
+      "files": [{{ "source": "src/simple.ispc", "args": ["-O2", "--pic"] }}]
+    }},
+    "aarch64-apple-darwin": {{
+      "library": "libsimpleaarch64-apple-darwin.dylib",
+      "kind": "shared",
+      "library_sha256": "{macos}",
+      "bindings": "simple.rs",
+      "bindings_sha256": "{bindings}",
+      "isas": [],
+      "ispc_version": "1.25.3"
+    }}
+  }}
+}}"#,
+            linux = sha256(b"linux"),
+            macos = sha256(b"macos"),
+            bindings = sha256(b"bindings"),
+        );
+        fs::write(PackageManifest::path(dir, "simple"), &manifest).unwrap();
+        manifest
+    }
+
+    #[test]
+    fn reads_manifest() {
+        let dir = test_dir("read-manifest");
+        assert!(PackageManifest::read(&dir, "simple").unwrap().is_none());
+
+        write_package(&dir);
+        let manifest = PackageManifest::read(&dir, "simple").unwrap().unwrap();
+        assert_eq!(manifest.lib, "simple");
+        let linux = &manifest.targets["x86_64-unknown-linux-gnu"];
+        assert!(!linux.shared);
+        assert_eq!(linux.bindings_filtered, Some(false));
+        assert_eq!(linux.isas, ["sse2-i32x4", "avx2-i32x8"]);
+        assert_eq!(
+            linux.files,
+            [(
+                String::from("src/simple.ispc"),
+                vec![String::from("-O2"), String::from("--pic")]
+            )]
+        );
+        let macos = &manifest.targets["aarch64-apple-darwin"];
+        assert!(macos.shared);
+        assert_eq!(macos.bindings_filtered, None);
+        assert!(macos.isas.is_empty() && macos.files.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_manifests() {
+        let dir = test_dir("invalid-manifest");
+        let manifest = write_package(&dir);
+        let path = PackageManifest::path(&dir, "simple");
+        for (text, message) in [
+            (
+                manifest.replace("\"format\": 1", "\"format\": 2"),
+                "format version 2 is newer",
+            ),
+            (
+                manifest.replace("\"format\": 1,", ""),
+                "missing the format version",
+            ),
+            (
+                manifest.replace("\"ispc_version\": \"1.25.3\",", ""),
+                "x86_64-unknown-linux-gnu: missing ispc_version",
+            ),
+            (String::from("{"), "EOF"),
+        ] {
+            fs::write(&path, text).unwrap();
+            match PackageManifest::read(&dir, "simple") {
+                Err(PackageError::InvalidManifest { message: m, .. }) => {
+                    assert!(m.contains(message), "{m}")
+                }
+                r => panic!("expected an invalid manifest error, got {r:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn verifies_hashes() {
+        let dir = test_dir("verify-package");
+        write_package(&dir);
+        let manifest = PackageManifest::read(&dir, "simple").unwrap().unwrap();
+        let packaged = manifest.verify(&dir, "x86_64-unknown-linux-gnu").unwrap();
+        assert_eq!(packaged.library, "libsimplex86_64-unknown-linux-gnu.a");
+
+        match manifest.verify(&dir, "x86_64-pc-windows-msvc") {
+            Err(PackageError::UnsupportedTarget { available, .. }) => assert_eq!(
+                available,
+                ["aarch64-apple-darwin", "x86_64-unknown-linux-gnu"]
+            ),
+            r => panic!("expected an unsupported target error, got {r:?}"),
+        }
+
+        fs::write(dir.join("simple.rs"), "modified").unwrap();
+        match manifest.verify(&dir, "aarch64-apple-darwin") {
+            Err(PackageError::Modified {
+                path,
+                expected,
+                found,
+            }) => {
+                assert_eq!(path, dir.join("simple.rs"));
+                assert_eq!(expected, sha256(b"bindings"));
+                assert_eq!(found, sha256(b"modified"));
+            }
+            r => panic!("expected a modified file error, got {r:?}"),
+        }
+    }
+
+    #[test]
+    fn checks_hash() {
+        let dir = test_dir("check-hash");
+        let path = dir.join("file");
+        fs::write(&path, "abc").unwrap();
+        // The SHA-256 test vector for "abc"
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        check_hash(&path, abc).unwrap();
+        assert!(matches!(
+            check_hash(&path, &sha256(b"abd")),
+            Err(PackageError::Modified { .. })
+        ));
+        assert!(matches!(
+            check_hash(&dir.join("missing"), abc),
+            Err(PackageError::Io { .. })
+        ));
+    }
+}
@@ -167,6 +167,37 @@
 //! of the crate. When building with `cargo build`, the previously compiled library
 //! for the host system will be linked against.
 //!
+//! ## Linking or Compiling in a Single Build Script
+//!
+//! Instead of writing both versions of `link_ispc`, the `ModuleBuilder` in `ispc_rt`
+//! links the packaged library when it was packaged for the target being built and
+//! matches its manifest, and otherwise compiles the ISPC code from source when the
+//! `compile` feature of `ispc_rt` is enabled. It warns when it falls back to compiling and why,
+//! and with `update_package` it always compiles, writing the outputs to the package.
+//!
+//! ```toml
+//! # Cargo.toml
+//! [build-dependencies]
+//! ispc_rt = "2.0"
+//!
+//! [features]
+//! ispc = ["ispc_rt/compile"]
+//! ```
+//!
+//! ```no_run
+//! extern crate ispc_rt;
+//!
+//! fn main() {
+//!     ispc_rt::ModuleBuilder::new("simple")
+//!         .lib_path("src/")
+//!         .file("src/simple.ispc")
+//!         .target_isas(&["sse2-i32x4", "avx2-i32x8", "avx512skx-x16"])
+//!         // Update the packaged library when building with the ispc feature
+//!         .update_package(cfg!(feature = "ispc"))
+//!         .build();
+//! }
+//! ```
+//!
 //! Whether building with or without the ispc feature, you can import the generated
 //! bindings into your rust code with the `ispc_module!` macro as before:
 //!
@@ -185,9 +216,11 @@
 #![allow(dead_code)]
 
 extern crate ispc_compile;
+extern crate ispc_macros;
 extern crate ispc_rt;
 
 pub use ispc_compile::*;
+pub use ispc_macros::ispc;
 pub use ispc_rt::*;
 
 /// Convenience macro for generating the module to hold the raw/unsafe ISPC bindings.
@@ -208,6 +241,12 @@ pub use ispc_rt::*;
 #[macro_export]
 macro_rules! ispc_module {
     ($lib:ident) => {
+        #[allow(unused_macros)]
+        macro_rules! ispc_rs_verify_abi {
+            ($name:expr, $bindings_hash:expr, $library_hash:expr) => {
+                $crate::verify_abi($name, $bindings_hash, $library_hash)
+            };
+        }
         include!(concat!(env!("ISPC_OUT_DIR"), "/", stringify!($lib), ".rs"));
     };
 }